@@ -0,0 +1,80 @@
+//! 测量音频线程中 Audio Capture 滤镜 dispatch 的最坏耗时
+//!
+//! 同时渲染线程以 60fps 的节奏输出画面，UI 线程不停地修改另一个 Audio Renderer 的声音源，在声音源通道列表中插入和移除通道。
+//!
+//! `cargo bench --bench dispatch`
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bindings::{OBS_SOURCE_AUDIO, OBS_SOURCE_VIDEO};
+use obs_audio_renderer::obs_module_load;
+use obs_shim::{create_source, Data, Instance, module_session, source_info};
+
+const DURATION: Duration = Duration::from_secs(3);
+/// 和 src/audio_renderer.rs 中的 MAX_AUDIO_SOURCE_COUNT 相同
+const SOURCE_COUNT: usize = 4;
+
+/// 所有声音源槽位都选择 `uuid` 的 Audio Renderer 设置
+fn renderer_settings(uuid: &str) -> Box<Data> {
+    let settings = Data::new();
+    unsafe { source_info("audio_renderer").get_defaults.unwrap()(settings.as_ptr()) };
+    for i in 0..SOURCE_COUNT {
+        settings.set_string(&format!("source{}", i), uuid);
+    }
+    settings
+}
+
+fn main() {
+    let _session = module_session(obs_module_load);
+    let filter_source = create_source("audio_capture", "Audio Capture", "filter-uuid", OBS_SOURCE_AUDIO);
+    let filter = unsafe { Instance::create("audio_capture", &Data::new(), filter_source) };
+    let renderer_source = create_source("audio_renderer", "Audio Renderer", "renderer-uuid", OBS_SOURCE_VIDEO);
+    let renderer = unsafe { Instance::create("audio_renderer", &renderer_settings("filter-uuid"), renderer_source) };
+    let churn_source = create_source("audio_renderer", "Churn", "churn-uuid", OBS_SOURCE_VIDEO);
+    let churn = unsafe { Instance::create("audio_renderer", &renderer_settings(""), churn_source) };
+
+    let running = AtomicBool::new(true);
+    let mut latencies = Vec::new();
+    let mut churn_count = 0;
+    thread::scope(|scope| {
+        // 渲染线程：每帧读出数据并编码
+        scope.spawn(|| {
+            while running.load(Ordering::Relaxed) {
+                renderer.render();
+                thread::sleep(Duration::from_millis(16));
+            }
+        });
+        // UI 线程：反复选择和取消选择滤镜，每次都会插入或移除声音源通道
+        let churn_count = &mut churn_count;
+        scope.spawn(|| {
+            let selected = renderer_settings("filter-uuid");
+            let cleared = renderer_settings("");
+            while running.load(Ordering::Relaxed) {
+                churn.update(&selected);
+                churn.update(&cleared);
+                *churn_count += 1;
+            }
+        });
+        // 音频线程：OBS 大约每 21ms 提交 1024 个采样，这里提交得更频繁以便多采样几次
+        let left = vec![0.5f32; 1024];
+        let right = vec![-0.5f32; 1024];
+        let start = Instant::now();
+        while start.elapsed() < DURATION {
+            let t = Instant::now();
+            filter.filter_audio(&[&left, &right]);
+            latencies.push(t.elapsed());
+            thread::sleep(Duration::from_millis(1));
+        }
+        running.store(false, Ordering::Relaxed);
+    });
+
+    latencies.sort();
+    println!("dispatch count: {}, churn count: {}, p50: {:?}, p99: {:?}, max: {:?}",
+        latencies.len(),
+        churn_count,
+        latencies[latencies.len() / 2],
+        latencies[latencies.len() * 99 / 100],
+        latencies[latencies.len() - 1]);
+}
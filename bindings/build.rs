@@ -0,0 +1,130 @@
+/// 插件用到的 libobs 函数，启用 `bindgen` feature 时只为这些函数以及它们用到的类型生成绑定
+#[cfg(feature = "bindgen")]
+const ALLOWLIST_FUNCTIONS: &[&str] = &[
+    "audio_output_get_channels",
+    "audio_output_get_sample_rate",
+    "bfree",
+    "blog",
+    "calldata_get_data",
+    "calldata_get_string",
+    "gs_draw_sprite",
+    "gs_effect_create",
+    "gs_effect_destroy",
+    "gs_effect_get_param_by_name",
+    "gs_effect_loop",
+    "gs_effect_set_float",
+    "gs_effect_set_texture",
+    "gs_matrix_pop",
+    "gs_matrix_push",
+    "gs_matrix_translate3f",
+    "gs_texture_create",
+    "gs_texture_destroy",
+    "gs_texture_set_image",
+    "obs_add_raw_audio_callback",
+    "obs_data_get_bool",
+    "obs_data_get_double",
+    "obs_data_get_int",
+    "obs_data_get_string",
+    "obs_data_set_default_bool",
+    "obs_data_set_default_double",
+    "obs_data_set_default_int",
+    "obs_data_set_int",
+    "obs_enter_graphics",
+    "obs_enum_sources",
+    "obs_filter_get_target",
+    "obs_get_audio",
+    "obs_get_base_effect",
+    "obs_get_source_by_uuid",
+    "obs_get_video_frame_time",
+    "obs_get_video_info",
+    "obs_leave_graphics",
+    "obs_properties_add_bool",
+    "obs_properties_add_button",
+    "obs_properties_add_float_slider",
+    "obs_properties_add_int",
+    "obs_properties_add_list",
+    "obs_properties_add_text",
+    "obs_properties_create",
+    "obs_properties_get",
+    "obs_property_int_set_limits",
+    "obs_property_list_add_int",
+    "obs_property_list_add_string",
+    "obs_property_name",
+    "obs_property_set_description",
+    "obs_property_set_modified_callback",
+    "obs_property_set_visible",
+    "obs_property_text_set_info_type",
+    "obs_register_source_s",
+    "obs_remove_raw_audio_callback",
+    "obs_source_add_audio_capture_callback",
+    "obs_source_get_base_height",
+    "obs_source_get_base_width",
+    "obs_source_get_id",
+    "obs_source_get_name",
+    "obs_source_get_output_flags",
+    "obs_source_get_proc_handler",
+    "obs_source_get_uuid",
+    "obs_source_process_filter_begin",
+    "obs_source_process_filter_tech_end",
+    "obs_source_update_properties",
+    "obs_source_release",
+    "obs_source_remove_audio_capture_callback",
+    "proc_handler_add",
+];
+
+/// 插件用到的宏定义，支持正则表达式
+#[cfg(feature = "bindgen")]
+const ALLOWLIST_VARS: &[&str] = &[
+    "LIBOBS_API_.*",
+    "LOG_.*",
+    "OBS_SOURCE_.*",
+    "GS_DYNAMIC",
+    "MAX_AUDIO_MIXES",
+    "MAX_AV_PLANES",
+];
+
+fn main() {
+    #[cfg(feature = "bindgen")]
+    generate();
+}
+
+/// 从 OBS 的头文件生成 `$OUT_DIR/bindings.rs`
+///
+/// 头文件目录（包含 obs-module.h 的 libobs 目录）按以下顺序查找：
+/// 1. 环境变量 `OBS_INCLUDE_DIR`
+/// 2. `pkg-config --cflags-only-I libobs`
+/// 3. 和本仓库同级的 obs-studio 源码 `../../obs-studio/libobs`
+#[cfg(feature = "bindgen")]
+fn generate() {
+    use std::env;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    println!("cargo:rerun-if-changed=src/wrapper.h");
+    println!("cargo:rerun-if-env-changed=OBS_INCLUDE_DIR");
+    let include_dirs = if let Ok(obs_include_dir) = env::var("OBS_INCLUDE_DIR") {
+        vec![obs_include_dir]
+    } else if let Some(output) = Command::new("pkg-config").arg("--cflags-only-I").arg("libobs").output().ok().filter(|v| v.status.success()) {
+        String::from_utf8_lossy(&output.stdout)
+            .split_ascii_whitespace()
+            .filter_map(|s| s.strip_prefix("-I"))
+            .map(String::from)
+            .collect()
+    } else {
+        vec![String::from("../../obs-studio/libobs")]
+    };
+
+    let mut builder = bindgen::Builder::default()
+        .header("src/wrapper.h")
+        .derive_default(true)
+        .clang_args(include_dirs.iter().map(|v| format!("-I{}", v)))
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()));
+    for function in ALLOWLIST_FUNCTIONS {
+        builder = builder.allowlist_function(function);
+    }
+    for var in ALLOWLIST_VARS {
+        builder = builder.allowlist_var(var);
+    }
+    let bindings = builder.generate().expect("failed to generate libobs bindings, set OBS_INCLUDE_DIR to the libobs header directory");
+    bindings.write_to_file(PathBuf::from(env::var("OUT_DIR").unwrap()).join("bindings.rs")).unwrap();
+}
@@ -1,5 +1,22 @@
 #![allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
 
+#[cfg(not(feature = "bindgen"))]
 pub use bindings::*;
 
+#[cfg(not(feature = "bindgen"))]
 mod bindings;
+
+#[cfg(feature = "bindgen")]
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+/// 插件支持的 libobs 主版本号范围
+///
+/// 29.1 开始才有 obs_source_get_uuid、obs_get_source_by_uuid。
+/// OBS 会拒绝加载 obs_module_ver 比自己新的插件，而更新的主版本可能改动插件用到的结构体，需要确认之后再放宽上限。
+pub const MIN_LIBOBS_API_MAJOR_VER: u32 = 29;
+pub const MAX_LIBOBS_API_MAJOR_VER: u32 = 31;
+
+const _: () = assert!(
+    LIBOBS_API_MAJOR_VER >= MIN_LIBOBS_API_MAJOR_VER && LIBOBS_API_MAJOR_VER <= MAX_LIBOBS_API_MAJOR_VER,
+    "unsupported libobs version, check LIBOBS_API_MAJOR_VER of the OBS headers",
+);
@@ -1,2 +1,2 @@
-#include "../../obs-studio/libobs/obs-module.h"
-#include "../../obs-studio/libobs/util/platform.h"
+#include <obs-module.h>
+#include <util/platform.h>
@@ -1,15 +1,30 @@
 use std::io::Write;
+use std::collections::BTreeSet;
 use std::env;
-use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=OBS_LIB_DIR");
+    match env::var("CARGO_CFG_TARGET_OS").unwrap().as_str() {
+        "windows" => {
+            link_windows();
+            println!("cargo:rustc-flags=-l dylib=obs");
+        }
+        "macos" => link_macos(),
+        _ => link_linux(),
+    }
+}
+
+/// Windows：从注册表找到 OBS 安装路径，用 obs.dll 的导出表生成 obs.lib
+fn link_windows() {
     // 获取 OBS 安装路径
     let mut obs_studio_path = None;
     for x in String::from_utf8_lossy(&Command::new("reg.exe").arg("query").arg("HKEY_LOCAL_MACHINE\\SOFTWARE\\OBS Studio").output().unwrap().stdout).split("\r\n") {
         let mut iter = x.split("REG_SZ");
-        if let Some(_) = iter.next() {
+        if iter.next().is_some() {
             if let Some(path) = iter.next() {
                 obs_studio_path = Some(String::from(path.trim()));
                 break;
@@ -31,19 +46,19 @@ fn main() {
             let dumpbin_result = dumpbin_exe.arg("/EXPORTS").arg(obs_dll_path).output().unwrap().stdout;
             let mut exports = Vec::new();
             for s in String::from_utf8_lossy(&dumpbin_result).split("\r\n").skip(19) { // 跳过前 19 行
-                if let Some(name) = s.trim().split_ascii_whitespace().skip(3).next() {
+                if let Some(name) = s.trim().split_ascii_whitespace().nth(3) {
                     exports.push(String::from(name));
                 } else {
                     break;
                 }
             }
-            if exports.len() > 0 {
+            if !exports.is_empty() {
                 let def = format!("LIBRARY\nEXPORTS\n{}", exports.join("\n"));
                 let out_dir = env::var("OUT_DIR").unwrap();
                 let def_path = PathBuf::from(&out_dir).join("obs.def");
                 {
                     let mut f2 = OpenOptions::new().write(true).truncate(true).create(true).open(def_path.clone()).unwrap();
-                    f2.write(def.as_bytes()).unwrap();
+                    f2.write_all(def.as_bytes()).unwrap();
                 }
                 let arch = match target.as_str() {
                     "i686-pc-windows-msvc" => Some("X86"),
@@ -78,6 +93,102 @@ fn main() {
     } else {
         println!("cargo:warning=OBS Studio not found on this computer");
     }
+}
+
+/// Linux：依次尝试 `OBS_LIB_DIR`、pkg-config，都找不到时链接一个桩 libobs.so
+///
+/// 桩库只用于通过链接，soname 和真正的 libobs 相同，插件在 OBS 中加载时使用的是 OBS 自带的 libobs。
+fn link_linux() {
+    if let Ok(obs_lib_dir) = env::var("OBS_LIB_DIR") {
+        println!("cargo:rustc-link-search=native={}", obs_lib_dir);
+    } else if let Some(link_dirs) = pkg_config_link_dirs() {
+        for link_dir in link_dirs {
+            println!("cargo:rustc-link-search=native={}", link_dir);
+        }
+    } else {
+        println!("cargo:warning=libobs not found, linking against a stub library (set OBS_LIB_DIR or install libobs-dev)");
+        let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+        build_stub_library(&out_dir);
+        println!("cargo:rustc-link-search=native={}", out_dir.display());
+    }
+    println!("cargo:rustc-link-lib=dylib=obs");
+}
+
+/// macOS：libobs 是 framework，`OBS_LIB_DIR` 指向包含 libobs.framework 的目录，
+/// 没有设置时不链接 libobs，所有符号在 OBS 加载插件时再查找
+fn link_macos() {
+    if let Ok(obs_lib_dir) = env::var("OBS_LIB_DIR") {
+        println!("cargo:rustc-link-search=framework={}", obs_lib_dir);
+        println!("cargo:rustc-link-lib=framework=libobs");
+    } else {
+        println!("cargo:warning=OBS_LIB_DIR not set, libobs symbols will be resolved when OBS loads the plugin");
+        println!("cargo:rustc-cdylib-link-arg=-undefined");
+        println!("cargo:rustc-cdylib-link-arg=dynamic_lookup");
+    }
+}
+
+/// 返回 `pkg-config --libs-only-L libobs` 中的目录，没有安装 pkg-config 或 libobs 时返回 None
+fn pkg_config_link_dirs() -> Option<Vec<String>> {
+    let output = Command::new("pkg-config").arg("--libs-only-L").arg("libobs").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout)
+        .split_ascii_whitespace()
+        .filter_map(|s| s.strip_prefix("-L"))
+        .map(String::from)
+        .collect())
+}
 
-    println!("cargo:rustc-flags=-l dylib=obs");
+/// libobs 导出函数的前缀，bindings 里还有 libc 的函数，不能放进桩库，否则会覆盖掉 libc 的实现
+const LIBOBS_PREFIXES: [&str; 9] = ["obs_", "gs_", "audio_", "video_", "os_", "calldata_", "proc_handler_", "blog", "bfree"];
+
+/// 生成只包含空函数的 libobs.so，只导出 src 中用到的 libobs 函数
+fn build_stub_library(out_dir: &Path) {
+    println!("cargo:rerun-if-changed=src");
+    let bindings = fs::read_to_string("bindings/src/bindings.rs").unwrap();
+    let functions: BTreeSet<&str> = bindings.lines()
+        .filter_map(|line| line.strip_prefix("    pub fn "))
+        .filter_map(|line| line.split('(').next())
+        .filter(|name| LIBOBS_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+        .collect();
+    let mut used = BTreeSet::new();
+    let mut defined = BTreeSet::new();
+    for entry in fs::read_dir("src").unwrap() {
+        let source = fs::read_to_string(entry.unwrap().path()).unwrap();
+        // 插件自己导出的 obs_module_* 等函数
+        for line in source.lines() {
+            if let Some(name) = line.split("extern \"C\" fn ").nth(1).and_then(|s| s.split('(').next()) {
+                defined.insert(name.to_string());
+            }
+        }
+        for word in source.split(|c: char| !c.is_ascii_alphanumeric() && c != '_') {
+            if let Some(name) = functions.get(word) {
+                used.insert(*name);
+            }
+        }
+    }
+    let stub_path = out_dir.join("obs_stub.c");
+    {
+        let mut f = OpenOptions::new().write(true).truncate(true).create(true).open(&stub_path).unwrap();
+        for name in used.iter().filter(|name| !defined.contains(**name)) {
+            writeln!(f, "void {}(void) {{}}", name).unwrap();
+        }
+    }
+    let lib_path = out_dir.join("libobs.so");
+    let success = cc::Build::new().get_compiler().to_command()
+        .arg("-shared")
+        .arg("-fPIC")
+        .arg("-w")
+        .arg("-Wl,-soname,libobs.so.0")
+        .arg("-o").arg(&lib_path)
+        .arg(&stub_path)
+        .status()
+        .unwrap()
+        .success();
+    if !success {
+        panic!("failed to build stub libobs.so");
+    }
+    // 运行测试时按照 soname 查找
+    fs::copy(&lib_path, out_dir.join("libobs.so.0")).unwrap();
 }
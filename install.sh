@@ -0,0 +1,18 @@
+#!/bin/sh
+# 在 Linux 上编译插件并安装到 OBS 的用户插件目录
+#
+# 安装后的目录结构：
+#   ~/.config/obs-studio/plugins/obs-audio-renderer/bin/64bit/obs-audio-renderer.so
+#   ~/.config/obs-studio/plugins/obs-audio-renderer/data/audio_encode.effect
+#
+# 可以用 OBS_PLUGIN_DIR 指定其他的插件目录，用 OBS_LIB_DIR 指定 libobs.so 所在目录
+set -e
+
+cd "$(dirname "$0")"
+cargo build --release
+
+PLUGIN_DIR="${OBS_PLUGIN_DIR:-$HOME/.config/obs-studio/plugins}/obs-audio-renderer"
+mkdir -p "$PLUGIN_DIR/bin/64bit" "$PLUGIN_DIR/data"
+cp target/release/libobs_audio_renderer.so "$PLUGIN_DIR/bin/64bit/obs-audio-renderer.so"
+cp data/* "$PLUGIN_DIR/data/"
+echo "installed to $PLUGIN_DIR"
@@ -0,0 +1,996 @@
+//! 仅用于测试的 libobs 替身
+//!
+//! 导出和 libobs 同名的 `extern "C"` 函数，链接测试程序时优先于桩 libobs.so 使用。
+//! 所有状态都是全局的，测试开始时先调用 [`session`]，保证同一时刻只有一个测试在使用。
+
+// 导出的函数和 libobs 的签名一致，指针参数的约定同 libobs
+#![allow(clippy::missing_safety_doc, clippy::manual_c_str_literals)]
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_longlong, c_void};
+use std::ptr::null_mut;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use bindings::{audio_convert_info, audio_data, audio_output_callback_t, audio_t, calldata_t, gs_color_format, gs_eparam_t, gs_effect_t, gs_texture_t, LOG_ERROR, MAX_AV_PLANES, obs_allow_direct_render, obs_audio_data, obs_base_effect, obs_combo_format, obs_combo_type, obs_data_t, obs_properties_t, obs_property_clicked_t, obs_property_modified_t, obs_property_t, obs_source_audio_capture_t, obs_source_info, obs_source_t, obs_text_info_type, obs_text_type, obs_video_info, proc_handler_proc_t, proc_handler_t};
+
+/// [`audio_output_get_sample_rate`] 返回的采样率
+pub const SAMPLE_RATE: u32 = 48000;
+/// [`audio_output_get_channels`] 返回的声道数
+pub const CHANNELS: usize = 2;
+/// [`obs_get_video_info`] 返回的画布大小
+pub const CANVAS_WIDTH: u32 = 1920;
+pub const CANVAS_HEIGHT: u32 = 1080;
+
+#[derive(Default)]
+struct State {
+    source_infos: Vec<obs_source_info>,
+    sources: Vec<&'static ShimSource>,
+    raw_audio_callbacks: Vec<(usize, audio_output_callback_t, usize)>,
+    texture_uploads: Vec<TextureUpload>,
+    effect_params: Vec<(String, EffectParam)>,
+    sprite_draws: Vec<SpriteDraw>,
+    filter_draws: Vec<FilterDraw>,
+    logs: Vec<(i32, String)>,
+    video_frame_time: u64,
+}
+
+// obs_source_info 中的字符串指针都指向插件中的静态数据
+unsafe impl Send for State {}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+static SESSION: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    /// 当前线程 obs_enter_graphics 的嵌套层数
+    static GRAPHICS_DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// 当前线程的变换矩阵，只支持平移，最后一个是当前使用的
+    static MATRIX_STACK: RefCell<Vec<(f32, f32)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    f(state.get_or_insert_with(State::default))
+}
+
+/// 独占 libobs 的全局状态并清空之前测试留下的数据，返回的 guard 释放之前其他测试会等待
+pub fn session() -> MutexGuard<'static, ()> {
+    let guard = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+    with_state(|state| *state = State::default());
+    guard
+}
+
+/// 和 [`session`] 相同，之后再调用插件的 `obs_module_load` 注册源
+pub fn module_session(obs_module_load: unsafe extern "C" fn() -> bool) -> MutexGuard<'static, ()> {
+    let guard = session();
+    assert!(unsafe { obs_module_load() });
+    guard
+}
+
+/// 插件通过 obs_register_source_s 注册的类型
+pub fn source_info(id: &str) -> obs_source_info {
+    with_state(|state| state.source_infos.iter().find(|info| unsafe { CStr::from_ptr(info.id) }.to_bytes() == id.as_bytes()).copied())
+        .unwrap_or_else(|| panic!("source type {} is not registered", id))
+}
+
+/// 一个 OBS 中的实例，通过 [`source_info`] 取得的回调创建，drop 时调用 destroy
+pub struct Instance {
+    pub info: obs_source_info,
+    pub data: *mut c_void,
+    /// 实例所属的源
+    pub source: *mut obs_source_t,
+}
+
+// 和 OBS 一样，同一个实例的回调会在 UI 线程、渲染线程和音频线程中同时调用
+unsafe impl Send for Instance {}
+unsafe impl Sync for Instance {}
+
+impl Instance {
+    /// 用 `id` 类型的 create 回调创建实例，`source` 必须是 [`create_source`] 返回的指针
+    pub unsafe fn create(id: &str, settings: &Data, source: *mut obs_source_t) -> Self {
+        let info = source_info(id);
+        let data = info.create.unwrap()(settings.as_ptr(), source);
+        assert!(!data.is_null());
+        Self { info, data, source }
+    }
+
+    pub fn update(&self, settings: &Data) {
+        unsafe { self.info.update.unwrap()(self.data, settings.as_ptr()) };
+    }
+
+    /// 把一批 float planar 格式的数据交给滤镜的 filter_audio
+    pub fn filter_audio(&self, planes: &[&[f32]]) {
+        let mut audio = obs_audio_data {
+            data: [null_mut(); MAX_AV_PLANES as usize],
+            frames: planes[0].len() as u32,
+            timestamp: 0,
+        };
+        for (plane, samples) in audio.data.iter_mut().zip(planes) {
+            *plane = samples.as_ptr() as *mut u8;
+        }
+        unsafe { self.info.filter_audio.unwrap()(self.data, &mut audio) };
+    }
+
+    /// 输出一帧画面，和 OBS 一样先 video_tick 再 video_render
+    pub fn render(&self) {
+        unsafe { self.info.video_tick.unwrap()(self.data, 1.0 / 60.0) };
+        self.render_again();
+    }
+
+    /// 同一帧画面被预览、投影等再渲染一次
+    pub fn render_again(&self) {
+        unsafe { self.info.video_render.unwrap()(self.data, null_mut()) };
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        unsafe { self.info.destroy.unwrap()(self.data) };
+    }
+}
+
+/// 插件通过 blog 输出的日志
+pub fn logs() -> Vec<(i32, String)> {
+    with_state(|state| state.logs.clone())
+}
+
+/// 上传到 texture 的数据，按上传顺序排列
+pub fn texture_uploads() -> Vec<TextureUpload> {
+    with_state(|state| state.texture_uploads.clone())
+}
+
+#[derive(Clone)]
+pub struct TextureUpload {
+    pub width: u32,
+    pub height: u32,
+    pub linesize: u32,
+    pub data: Vec<u8>,
+}
+
+fn record_log(level: i32, message: String) {
+    with_state(|state| state.logs.push((level, message)));
+}
+
+/// texture 相关的函数必须在 obs_enter_graphics 和 obs_leave_graphics 之间调用，否则记录一条错误日志
+fn check_graphics(name: &str) {
+    if GRAPHICS_DEPTH.with(|depth| depth.get()) == 0 {
+        record_log(LOG_ERROR, format!("{} called outside graphics context", name));
+    }
+}
+
+// region util
+
+/// libobs 中是 `blog(level, format, ...)`，插件只会以 `blog(level, "%s", message)` 的形式调用
+#[no_mangle]
+pub unsafe extern "C" fn blog(log_level: c_int, format: *const c_char, message: *const c_char) {
+    let format = CStr::from_ptr(format).to_string_lossy().into_owned();
+    let message = if format == "%s" {
+        CStr::from_ptr(message).to_string_lossy().into_owned()
+    } else {
+        format
+    };
+    record_log(log_level, message);
+}
+
+/// 插件只会释放 gs_effect_create 的错误信息，而这里从来不会生成错误信息
+#[no_mangle]
+pub unsafe extern "C" fn bfree(_ptr: *mut c_void) {}
+
+// endregion
+
+// region obs_data
+
+/// 设置项，相当于 obs_data_t
+#[derive(Default)]
+pub struct Data {
+    values: Mutex<HashMap<String, Value>>,
+    defaults: Mutex<HashMap<String, Value>>,
+}
+
+#[derive(Clone)]
+enum Value {
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+    String(CString),
+}
+
+impl Data {
+    pub fn new() -> Box<Self> {
+        Box::default()
+    }
+
+    pub fn as_ptr(&self) -> *mut obs_data_t {
+        self as *const Data as *mut obs_data_t
+    }
+
+    pub fn set_int(&self, name: &str, value: i64) {
+        self.values.lock().unwrap().insert(name.to_string(), Value::Int(value));
+    }
+
+    pub fn set_double(&self, name: &str, value: f64) {
+        self.values.lock().unwrap().insert(name.to_string(), Value::Double(value));
+    }
+
+    pub fn set_bool(&self, name: &str, value: bool) {
+        self.values.lock().unwrap().insert(name.to_string(), Value::Bool(value));
+    }
+
+    pub fn set_string(&self, name: &str, value: &str) {
+        self.values.lock().unwrap().insert(name.to_string(), Value::String(CString::new(value).unwrap()));
+    }
+
+    /// 插件在 modified 回调中调整之后的值
+    pub fn get_int(&self, name: &str) -> i64 {
+        match self.get(name) {
+            Some(Value::Int(v)) => v,
+            _ => 0,
+        }
+    }
+
+    /// 用户设置的值优先，其次是默认值
+    fn get(&self, name: &str) -> Option<Value> {
+        self.values.lock().unwrap().get(name).or(self.defaults.lock().unwrap().get(name)).cloned()
+    }
+}
+
+unsafe fn data<'a>(data: *mut obs_data_t) -> &'a Data {
+    &*(data as *const Data)
+}
+
+unsafe fn name(name: *const c_char) -> String {
+    CStr::from_ptr(name).to_string_lossy().into_owned()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_data_get_int(d: *mut obs_data_t, n: *const c_char) -> c_longlong {
+    match data(d).get(&name(n)) {
+        Some(Value::Int(v)) => v,
+        Some(Value::Double(v)) => v as _,
+        _ => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_data_get_double(d: *mut obs_data_t, n: *const c_char) -> f64 {
+    match data(d).get(&name(n)) {
+        Some(Value::Double(v)) => v,
+        Some(Value::Int(v)) => v as _,
+        _ => 0.0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_data_get_bool(d: *mut obs_data_t, n: *const c_char) -> bool {
+    matches!(data(d).get(&name(n)), Some(Value::Bool(true)))
+}
+
+/// 返回的指针在这一项被重新设置之前有效
+#[no_mangle]
+pub unsafe extern "C" fn obs_data_get_string(d: *mut obs_data_t, n: *const c_char) -> *const c_char {
+    let d = data(d);
+    let n = name(n);
+    for map in [&d.values, &d.defaults] {
+        if let Some(Value::String(v)) = map.lock().unwrap().get(&n) {
+            return v.as_ptr();
+        }
+    }
+    "\0".as_ptr().cast()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_data_set_int(d: *mut obs_data_t, n: *const c_char, val: c_longlong) {
+    data(d).values.lock().unwrap().insert(name(n), Value::Int(val));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_data_set_default_int(d: *mut obs_data_t, n: *const c_char, val: c_longlong) {
+    data(d).defaults.lock().unwrap().insert(name(n), Value::Int(val));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_data_set_default_double(d: *mut obs_data_t, n: *const c_char, val: f64) {
+    data(d).defaults.lock().unwrap().insert(name(n), Value::Double(val));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_data_set_default_bool(d: *mut obs_data_t, n: *const c_char, val: bool) {
+    data(d).defaults.lock().unwrap().insert(name(n), Value::Bool(val));
+}
+
+// endregion
+
+// region obs_source
+
+/// 场景中的一个源，相当于 obs_source_t，创建之后一直存在直到测试进程结束
+pub struct ShimSource {
+    id: CString,
+    name: CString,
+    uuid: CString,
+    output_flags: u32,
+    audio_capture_callbacks: Mutex<Vec<(obs_source_audio_capture_t, usize)>>,
+    base_size: Mutex<(u32, u32)>,
+    filter_target: Mutex<usize>,
+    /// proc handler 中的函数名、函数和 data
+    procs: Mutex<Vec<(String, proc_handler_proc_t, usize)>>,
+    /// obs_source_update_properties 被调用的次数
+    properties_updates: Mutex<usize>,
+}
+
+/// 创建一个可以被 obs_get_source_by_uuid、obs_enum_sources 找到的源
+pub fn create_source(id: &str, name: &str, uuid: &str, output_flags: u32) -> *mut obs_source_t {
+    let source: &'static ShimSource = Box::leak(Box::new(ShimSource {
+        id: CString::new(id).unwrap(),
+        name: CString::new(name).unwrap(),
+        uuid: CString::new(uuid).unwrap(),
+        output_flags,
+        audio_capture_callbacks: Mutex::new(Vec::new()),
+        base_size: Mutex::new((0, 0)),
+        filter_target: Mutex::new(0),
+        procs: Mutex::new(Vec::new()),
+        properties_updates: Mutex::new(0),
+    }));
+    with_state(|state| state.sources.push(source));
+    source as *const ShimSource as *mut obs_source_t
+}
+
+unsafe fn source<'a>(source: *const obs_source_t) -> &'a ShimSource {
+    &*(source as *const ShimSource)
+}
+
+/// 设置 obs_source_get_base_width、obs_source_get_base_height 返回的画面大小，`s` 必须是 [`create_source`] 返回的指针
+pub unsafe fn set_source_size(s: *mut obs_source_t, width: u32, height: u32) {
+    *source(s).base_size.lock().unwrap() = (width, height);
+}
+
+/// 插件调用 obs_source_update_properties 要求刷新 `s` 的属性的次数，`s` 必须是 [`create_source`] 返回的指针
+pub unsafe fn properties_updates(s: *mut obs_source_t) -> usize {
+    *source(s).properties_updates.lock().unwrap()
+}
+
+/// 把滤镜 `filter` 添加到源 `target` 上，两者都必须是 [`create_source`] 返回的指针
+pub unsafe fn set_filter_target(filter: *mut obs_source_t, target: *mut obs_source_t) {
+    *source(filter).filter_target.lock().unwrap() = target as usize;
+}
+
+/// 把一批 float planar 格式的数据交给注册在 `s` 上的 audio capture 回调，`s` 必须是 [`create_source`] 返回的指针
+pub unsafe fn push_source_audio(s: *mut obs_source_t, planes: &[&[f32]]) {
+    push_source_audio_at(s, planes, 0);
+}
+
+/// 和 [`push_source_audio`] 相同，第一个采样的时间戳是 `timestamp`（单位：纳秒）
+pub unsafe fn push_source_audio_at(s: *mut obs_source_t, planes: &[&[f32]], timestamp: u64) {
+    // 和 libobs 一样在回调执行期间持有锁，注销回调时会等待正在执行的回调结束
+    let callbacks = source(s).audio_capture_callbacks.lock().unwrap();
+    let audio = make_audio_data(planes, timestamp);
+    for (callback, param) in callbacks.iter() {
+        callback.unwrap()(*param as _, s, &audio, false);
+    }
+}
+
+/// 把一批 float planar 格式的数据交给注册在输出音轨 `mix_idx` 上的 raw audio 回调
+pub fn push_mix_audio(mix_idx: usize, planes: &[&[f32]]) {
+    // 和 libobs 一样在回调执行期间持有锁，回调中不能再调用 libobs 的函数
+    let mut audio = make_audio_data(planes, 0);
+    with_state(|state| {
+        for (idx, callback, param) in &state.raw_audio_callbacks {
+            if *idx == mix_idx {
+                unsafe { callback.unwrap()(*param as _, mix_idx, &mut audio) };
+            }
+        }
+    });
+}
+
+/// proc handler 的参数，相当于 calldata_t
+#[derive(Default)]
+pub struct CallData {
+    ints: HashMap<String, c_longlong>,
+    strings: HashMap<String, CString>,
+}
+
+impl CallData {
+    pub fn set_int(&mut self, name: &str, value: i64) {
+        self.ints.insert(name.to_string(), value);
+    }
+
+    pub fn set_string(&mut self, name: &str, value: &str) {
+        self.strings.insert(name.to_string(), CString::new(value).unwrap());
+    }
+}
+
+/// 调用源 `s` 的 proc handler 中名为 `name` 的函数，找不到时返回 false，`s` 必须是 [`create_source`] 返回的指针
+pub unsafe fn call_proc(s: *mut obs_source_t, name: &str, calldata: &mut CallData) -> bool {
+    let proc = source(s).procs.lock().unwrap().iter().find(|v| v.0 == name).map(|v| (v.1, v.2));
+    match proc {
+        Some((proc, data)) => {
+            proc.unwrap()(data as _, calldata as *mut CallData as *mut calldata_t);
+            true
+        }
+        None => false,
+    }
+}
+
+fn make_audio_data(planes: &[&[f32]], timestamp: u64) -> audio_data {
+    let mut data = [null_mut(); MAX_AV_PLANES as usize];
+    for (plane, samples) in data.iter_mut().zip(planes) {
+        *plane = samples.as_ptr() as *mut u8;
+    }
+    audio_data {
+        data,
+        frames: planes.first().map(|v| v.len()).unwrap_or(0) as u32,
+        timestamp,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_register_source_s(info: *const obs_source_info, _size: usize) {
+    let info = *info;
+    with_state(|state| {
+        state.source_infos.retain(|v| CStr::from_ptr(v.id) != CStr::from_ptr(info.id));
+        state.source_infos.push(info);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_get_id(s: *const obs_source_t) -> *const c_char {
+    source(s).id.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_get_name(s: *const obs_source_t) -> *const c_char {
+    source(s).name.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_get_uuid(s: *const obs_source_t) -> *const c_char {
+    source(s).uuid.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_get_output_flags(s: *const obs_source_t) -> u32 {
+    source(s).output_flags
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_get_base_width(s: *mut obs_source_t) -> u32 {
+    source(s).base_size.lock().unwrap().0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_get_base_height(s: *mut obs_source_t) -> u32 {
+    source(s).base_size.lock().unwrap().1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_filter_get_target(filter: *const obs_source_t) -> *mut obs_source_t {
+    *source(filter).filter_target.lock().unwrap() as *mut obs_source_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_get_source_by_uuid(uuid: *const c_char) -> *mut obs_source_t {
+    let uuid = CStr::from_ptr(uuid);
+    with_state(|state| state.sources.iter().find(|v| v.uuid.as_c_str() == uuid).copied())
+        .map(|v| v as *const ShimSource as *mut obs_source_t)
+        .unwrap_or(null_mut())
+}
+
+/// 源一直存在，不需要引用计数
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_release(_source: *mut obs_source_t) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_enum_sources(enum_proc: Option<unsafe extern "C" fn(*mut c_void, *mut obs_source_t) -> bool>, param: *mut c_void) {
+    // 回调中还会调用 obs_source_get_*，不能持有 STATE 的锁
+    let sources = with_state(|state| state.sources.clone());
+    for s in sources {
+        if !enum_proc.unwrap()(param, s as *const ShimSource as *mut obs_source_t) {
+            break;
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_add_audio_capture_callback(s: *mut obs_source_t, callback: obs_source_audio_capture_t, param: *mut c_void) {
+    source(s).audio_capture_callbacks.lock().unwrap().push((callback, param as usize));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_remove_audio_capture_callback(s: *mut obs_source_t, callback: obs_source_audio_capture_t, param: *mut c_void) {
+    source(s).audio_capture_callbacks.lock().unwrap().retain(|v| *v != (callback, param as usize));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_update_properties(s: *mut obs_source_t) {
+    *source(s).properties_updates.lock().unwrap() += 1;
+}
+
+/// proc handler 属于源，直接用源的指针表示
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_get_proc_handler(s: *const obs_source_t) -> *mut proc_handler_t {
+    s as *mut proc_handler_t
+}
+
+/// 只记录声明中的函数名，例如 `void send_data(in int type, in string data)` 中的 `send_data`
+#[no_mangle]
+pub unsafe extern "C" fn proc_handler_add(handler: *mut proc_handler_t, decl_string: *const c_char, proc_: proc_handler_proc_t, data: *mut c_void) {
+    let decl = name(decl_string);
+    let proc_name = decl.split('(').next().unwrap().split_whitespace().last().unwrap().to_string();
+    source(handler as *const obs_source_t).procs.lock().unwrap().push((proc_name, proc_, data as usize));
+}
+
+/// 只支持 `in int` 参数，`size` 必须是 8
+#[no_mangle]
+pub unsafe extern "C" fn calldata_get_data(data: *const calldata_t, n: *const c_char, out: *mut c_void, size: usize) -> bool {
+    assert_eq!(size, std::mem::size_of::<c_longlong>());
+    match (*(data as *const CallData)).ints.get(&name(n)) {
+        Some(value) => {
+            *(out as *mut c_longlong) = *value;
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calldata_get_string(data: *const calldata_t, n: *const c_char, str_: *mut *const c_char) -> bool {
+    match (*(data as *const CallData)).strings.get(&name(n)) {
+        Some(value) => {
+            *str_ = value.as_ptr();
+            true
+        }
+        None => false,
+    }
+}
+
+// endregion
+
+// region video
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_get_video_info(ovi: *mut obs_video_info) -> bool {
+    let ovi = &mut *ovi;
+    ovi.fps_num = 60;
+    ovi.fps_den = 1;
+    ovi.base_width = CANVAS_WIDTH;
+    ovi.base_height = CANVAS_HEIGHT;
+    ovi.output_width = CANVAS_WIDTH;
+    ovi.output_height = CANVAS_HEIGHT;
+    true
+}
+
+/// 设置 [`obs_get_video_frame_time`] 返回的时间戳（单位：纳秒）
+pub fn set_video_frame_time(timestamp: u64) {
+    with_state(|state| state.video_frame_time = timestamp);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_get_video_frame_time() -> u64 {
+    with_state(|state| state.video_frame_time)
+}
+
+// endregion
+
+// region audio
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_get_audio() -> *mut audio_t {
+    // 只作为 audio_output_get_* 的参数，不会被解引用
+    std::ptr::NonNull::dangling().as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn audio_output_get_channels(_audio: *const audio_t) -> usize {
+    CHANNELS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn audio_output_get_sample_rate(_audio: *const audio_t) -> u32 {
+    SAMPLE_RATE
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_add_raw_audio_callback(mix_idx: usize, _conversion: *const audio_convert_info, callback: audio_output_callback_t, param: *mut c_void) {
+    with_state(|state| state.raw_audio_callbacks.push((mix_idx, callback, param as usize)));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_remove_raw_audio_callback(mix_idx: usize, callback: audio_output_callback_t, param: *mut c_void) {
+    with_state(|state| state.raw_audio_callbacks.retain(|v| *v != (mix_idx, callback, param as usize)));
+}
+
+// endregion
+
+// region graphics
+
+struct ShimTexture {
+    width: u32,
+    height: u32,
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_enter_graphics() {
+    GRAPHICS_DEPTH.with(|depth| depth.set(depth.get() + 1));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_leave_graphics() {
+    GRAPHICS_DEPTH.with(|depth| depth.set(depth.get() - 1));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_texture_create(width: u32, height: u32, _color_format: gs_color_format, _levels: u32, _data: *mut *const u8, _flags: u32) -> *mut gs_texture_t {
+    check_graphics("gs_texture_create");
+    Box::into_raw(Box::new(ShimTexture { width, height })) as _
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_texture_destroy(tex: *mut gs_texture_t) {
+    check_graphics("gs_texture_destroy");
+    drop(Box::from_raw(tex as *mut ShimTexture));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_texture_set_image(tex: *mut gs_texture_t, data: *const u8, linesize: u32, _invert: bool) {
+    check_graphics("gs_texture_set_image");
+    let texture = &*(tex as *const ShimTexture);
+    let data = std::slice::from_raw_parts(data, (linesize * texture.height) as usize).to_vec();
+    with_state(|state| state.texture_uploads.push(TextureUpload {
+        width: texture.width,
+        height: texture.height,
+        linesize,
+        data,
+    }));
+}
+
+/// effect 不会真正编译，只记录插件设置的参数
+#[derive(Default)]
+struct ShimEffect {
+    /// 插件拿到的是参数的地址，增加参数时已有参数的地址不能变
+    #[allow(clippy::vec_box)]
+    params: Mutex<Vec<Box<ShimParam>>>,
+    /// gs_effect_loop 正在执行唯一的一个 pass
+    looping: Mutex<bool>,
+}
+
+struct ShimParam {
+    name: String,
+}
+
+/// 插件通过 gs_effect_set_* 设置的 effect 参数值
+#[derive(Clone, Debug, PartialEq)]
+pub enum EffectParam {
+    Float(f32),
+    /// texture 的指针
+    Texture(usize),
+}
+
+/// 插件调用 gs_draw_sprite 绘制的一个矩形
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpriteDraw {
+    /// texture 的指针，为 0 表示由 shader 决定颜色
+    pub texture: usize,
+    /// 绘制时变换矩阵的平移量
+    pub x: f32,
+    pub y: f32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// 滤镜调用 obs_source_process_filter_tech_end 绘制的所在源的画面
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterDraw {
+    /// 所在源的指针
+    pub target: usize,
+    pub technique: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// 插件设置的 effect 参数，按设置顺序排列
+pub fn effect_params() -> Vec<(String, EffectParam)> {
+    with_state(|state| state.effect_params.clone())
+}
+
+/// 最后一次设置的名为 `name` 的 effect 参数
+pub fn last_effect_param(name: &str) -> Option<EffectParam> {
+    effect_params().into_iter().rev().find(|(n, _)| n == name).map(|(_, v)| v)
+}
+
+/// 插件调用 gs_draw_sprite 的记录
+pub fn filter_draws() -> Vec<FilterDraw> {
+    with_state(|state| state.filter_draws.clone())
+}
+
+pub fn sprite_draws() -> Vec<SpriteDraw> {
+    with_state(|state| state.sprite_draws.clone())
+}
+
+fn record_effect_param(param: *mut gs_eparam_t, value: EffectParam) {
+    if !param.is_null() {
+        let name = unsafe { &*(param as *const ShimParam) }.name.clone();
+        with_state(|state| state.effect_params.push((name, value)));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_effect_create(effect_string: *const c_char, _filename: *const c_char, _error_string: *mut *mut c_char) -> *mut gs_effect_t {
+    check_graphics("gs_effect_create");
+    if CStr::from_ptr(effect_string).to_bytes().is_empty() {
+        return null_mut();
+    }
+    Box::into_raw(Box::<ShimEffect>::default()) as _
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_effect_destroy(effect: *mut gs_effect_t) {
+    check_graphics("gs_effect_destroy");
+    drop(Box::from_raw(effect as *mut ShimEffect));
+}
+
+/// libobs 内置的 effect，整个进程只有一个
+#[no_mangle]
+pub unsafe extern "C" fn obs_get_base_effect(_effect: obs_base_effect) -> *mut gs_effect_t {
+    static BASE_EFFECT: OnceLock<usize> = OnceLock::new();
+    *BASE_EFFECT.get_or_init(|| Box::into_raw(Box::<ShimEffect>::default()) as usize) as _
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_effect_loop(effect: *mut gs_effect_t, _name: *const c_char) -> bool {
+    check_graphics("gs_effect_loop");
+    let mut looping = (*(effect as *const ShimEffect)).looping.lock().unwrap();
+    *looping = !*looping;
+    *looping
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_effect_get_param_by_name(effect: *const gs_effect_t, n: *const c_char) -> *mut gs_eparam_t {
+    if effect.is_null() {
+        return null_mut();
+    }
+    let n = name(n);
+    let mut params = (*(effect as *const ShimEffect)).params.lock().unwrap();
+    let param = match params.iter().position(|v| v.name == n) {
+        Some(i) => &params[i],
+        None => {
+            params.push(Box::new(ShimParam { name: n }));
+            params.last().unwrap()
+        }
+    };
+    &**param as *const ShimParam as *mut gs_eparam_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_effect_set_texture(param: *mut gs_eparam_t, val: *mut gs_texture_t) {
+    record_effect_param(param, EffectParam::Texture(val as usize));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_effect_set_float(param: *mut gs_eparam_t, val: f32) {
+    record_effect_param(param, EffectParam::Float(val));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_draw_sprite(tex: *mut gs_texture_t, _flip: u32, width: u32, height: u32) {
+    check_graphics("gs_draw_sprite");
+    let (x, y) = MATRIX_STACK.with(|stack| stack.borrow().last().copied().unwrap_or_default());
+    with_state(|state| state.sprite_draws.push(SpriteDraw {
+        texture: tex as usize,
+        x,
+        y,
+        width,
+        height,
+    }));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_matrix_push() {
+    check_graphics("gs_matrix_push");
+    MATRIX_STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let current = stack.last().copied().unwrap_or_default();
+        stack.push(current);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gs_matrix_pop() {
+    check_graphics("gs_matrix_pop");
+    if MATRIX_STACK.with(|stack| stack.borrow_mut().pop()).is_none() {
+        record_log(LOG_ERROR, "gs_matrix_pop called without gs_matrix_push".to_string());
+    }
+}
+
+/// 只记录 x、y 方向的平移
+#[no_mangle]
+pub unsafe extern "C" fn gs_matrix_translate3f(x: f32, y: f32, _z: f32) {
+    check_graphics("gs_matrix_translate3f");
+    MATRIX_STACK.with(|stack| {
+        if let Some(current) = stack.borrow_mut().last_mut() {
+            current.0 += x;
+            current.1 += y;
+        } else {
+            record_log(LOG_ERROR, "gs_matrix_translate3f called without gs_matrix_push".to_string());
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_process_filter_begin(filter: *mut obs_source_t, _format: gs_color_format, _allow_direct: obs_allow_direct_render) -> bool {
+    check_graphics("obs_source_process_filter_begin");
+    !obs_filter_get_target(filter).is_null()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_source_process_filter_tech_end(filter: *mut obs_source_t, _effect: *mut gs_effect_t, width: u32, height: u32, tech_name: *const c_char) {
+    check_graphics("obs_source_process_filter_tech_end");
+    let target = obs_filter_get_target(filter) as usize;
+    let technique = name(tech_name);
+    with_state(|state| state.filter_draws.push(FilterDraw { target, technique, width, height }));
+}
+
+// endregion
+
+// region obs_properties
+
+/// 属性列表，相当于 obs_properties_t
+#[derive(Default)]
+pub struct Properties {
+    pub properties: Vec<Box<Property>>,
+}
+
+impl Properties {
+    pub fn get(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|p| p.name == name).map(|p| &**p)
+    }
+
+    /// 模拟在属性界面中修改了 `name`，`settings` 中已经是修改之后的值，返回值为 true 表示需要刷新属性
+    pub unsafe fn modify(&mut self, name: &str, settings: &Data) -> bool {
+        let property = self.get(name).unwrap_or_else(|| panic!("no property {}", name));
+        let callback = property.modified.unwrap_or_else(|| panic!("{} has no modified callback", name));
+        let property = property as *const Property as *mut obs_property_t;
+        callback(self as *mut Properties as *mut obs_properties_t, property, settings.as_ptr())
+    }
+}
+
+/// 属性列表中的一项，相当于 obs_property_t
+#[derive(Default)]
+pub struct Property {
+    pub name: String,
+    pub description: String,
+    /// 下拉列表中每一项的显示名称
+    pub list_items: Vec<String>,
+    /// 按钮的回调
+    pub clicked: obs_property_clicked_t,
+    /// obs_property_set_modified_callback 设置的回调
+    pub modified: obs_property_modified_t,
+    /// obs_property_set_visible 设置为隐藏
+    pub hidden: bool,
+    /// 说明文字的类型，obs_property_text_set_info_type 设置
+    pub info_type: obs_text_info_type,
+    /// 整数的最小值、最大值和步长
+    pub int_limits: Option<(c_int, c_int, c_int)>,
+    /// obs_property_name 返回的字符串
+    c_name: CString,
+}
+
+impl Property {
+    /// 模拟点击按钮，`data` 是获取属性列表的实例，返回值为 true 表示需要刷新属性
+    pub unsafe fn click(&self, data: *mut c_void) -> bool {
+        let callback = self.clicked.unwrap_or_else(|| panic!("{} is not a button", self.name));
+        callback(null_mut(), self as *const Property as *mut obs_property_t, data)
+    }
+}
+
+/// 取回插件 get_properties 返回的属性列表
+pub unsafe fn take_properties(props: *mut obs_properties_t) -> Box<Properties> {
+    Box::from_raw(props as *mut Properties)
+}
+
+unsafe fn add_property(props: *mut obs_properties_t, n: *const c_char, description: *const c_char) -> *mut obs_property_t {
+    let props = &mut *(props as *mut Properties);
+    let mut property = Box::new(Property {
+        name: name(n),
+        c_name: CStr::from_ptr(n).to_owned(),
+        description: name(description),
+        ..Default::default()
+    });
+    let ptr = &mut *property as *mut Property as *mut obs_property_t;
+    props.properties.push(property);
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_properties_create() -> *mut obs_properties_t {
+    Box::into_raw(Box::<Properties>::default()) as _
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_properties_add_bool(props: *mut obs_properties_t, n: *const c_char, description: *const c_char) -> *mut obs_property_t {
+    add_property(props, n, description)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_properties_add_int(props: *mut obs_properties_t, n: *const c_char, description: *const c_char, min: c_int, max: c_int, step: c_int) -> *mut obs_property_t {
+    let p = add_property(props, n, description);
+    (*(p as *mut Property)).int_limits = Some((min, max, step));
+    p
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_properties_add_float_slider(props: *mut obs_properties_t, n: *const c_char, description: *const c_char, _min: f64, _max: f64, _step: f64) -> *mut obs_property_t {
+    add_property(props, n, description)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_properties_add_text(props: *mut obs_properties_t, n: *const c_char, description: *const c_char, _type: obs_text_type) -> *mut obs_property_t {
+    add_property(props, n, description)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_properties_add_list(props: *mut obs_properties_t, n: *const c_char, description: *const c_char, _type: obs_combo_type, _format: obs_combo_format) -> *mut obs_property_t {
+    add_property(props, n, description)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_property_list_add_int(p: *mut obs_property_t, n: *const c_char, _val: c_longlong) -> usize {
+    let property = &mut *(p as *mut Property);
+    property.list_items.push(name(n));
+    property.list_items.len() - 1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_properties_add_button(props: *mut obs_properties_t, n: *const c_char, text: *const c_char, callback: obs_property_clicked_t) -> *mut obs_property_t {
+    let p = add_property(props, n, text);
+    (*(p as *mut Property)).clicked = callback;
+    p
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_property_name(p: *mut obs_property_t) -> *const c_char {
+    (*(p as *const Property)).c_name.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_properties_get(props: *mut obs_properties_t, n: *const c_char) -> *mut obs_property_t {
+    let props = &mut *(props as *mut Properties);
+    let n = name(n);
+    match props.properties.iter_mut().find(|p| p.name == n) {
+        Some(property) => &mut **property as *mut Property as *mut obs_property_t,
+        None => null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_property_set_modified_callback(p: *mut obs_property_t, modified: obs_property_modified_t) {
+    (*(p as *mut Property)).modified = modified;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_property_set_description(p: *mut obs_property_t, description: *const c_char) {
+    (*(p as *mut Property)).description = name(description);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_property_set_visible(p: *mut obs_property_t, visible: bool) {
+    (*(p as *mut Property)).hidden = !visible;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_property_text_set_info_type(p: *mut obs_property_t, type_: obs_text_info_type) {
+    (*(p as *mut Property)).info_type = type_;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_property_int_set_limits(p: *mut obs_property_t, min: c_int, max: c_int, step: c_int) {
+    (*(p as *mut Property)).int_limits = Some((min, max, step));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn obs_property_list_add_string(p: *mut obs_property_t, n: *const c_char, _val: *const c_char) -> usize {
+    let property = &mut *(p as *mut Property);
+    property.list_items.push(name(n));
+    property.list_items.len() - 1
+}
+
+// endregion
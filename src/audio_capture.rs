@@ -1,50 +1,42 @@
-use std::mem::size_of;
+use std::sync::Arc;
 
-use bindings::{audio_output_get_channels, obs_audio_data, obs_data_t, obs_get_audio, obs_register_source_s, OBS_SOURCE_AUDIO, obs_source_info, obs_source_t, obs_source_type_OBS_SOURCE_TYPE_FILTER};
+use bindings::{audio_output_get_channels, obs_audio_data, obs_get_audio, OBS_SOURCE_AUDIO, obs_source_type, obs_source_type_OBS_SOURCE_TYPE_FILTER};
 
-use crate::audio_renderer::dispatch;
+use crate::audio_renderer::dispatch_audio;
+use crate::obs::{register_source, ObsData, Source, SourceRef};
+use crate::registry::Registry;
 
-pub static mut AUDIO_CAPTURE_LIST: Vec<*mut AudioCapture> = Vec::new();
+pub static AUDIO_CAPTURE_LIST: Registry<AudioCapture> = Registry::new();
 
 pub struct AudioCapture {
-    pub source: *mut obs_source_t,
+    pub source: SourceRef,
     pub channels: usize,
 }
 
 pub unsafe fn register() {
-    obs_register_source_s(&obs_source_info {
-        id: "audio_capture\0".as_ptr().cast(),
-        type_: obs_source_type_OBS_SOURCE_TYPE_FILTER,
-        output_flags: OBS_SOURCE_AUDIO,
-        get_name: Some(filter_getname),
-        create: Some(filter_create),
-        destroy: Some(filter_destroy),
-        filter_audio: Some(filter_audio),
-        ..Default::default()
-    }, size_of::<obs_source_info>());
+    register_source::<AudioCapture>();
 }
 
-unsafe extern "C" fn filter_getname(_type_data: *mut ::std::os::raw::c_void) -> *const ::std::os::raw::c_char {
-    "Audio Capture\0".as_ptr().cast()
-}
-
-unsafe extern "C" fn filter_create(_settings: *mut obs_data_t, source: *mut obs_source_t) -> *mut ::std::os::raw::c_void {
-    let p = Box::into_raw(Box::new(AudioCapture {
-        source,
-        channels: audio_output_get_channels(obs_get_audio()),
-    }));
-    AUDIO_CAPTURE_LIST.push(p);
-    p as _
-}
-
-unsafe extern "C" fn filter_destroy(data: *mut ::std::os::raw::c_void) {
-    let p = data as *mut AudioCapture;
-    AUDIO_CAPTURE_LIST.retain(|v| *v != p);
-    let _ = Box::from_raw(p);
-}
-
-unsafe extern "C" fn filter_audio(data: *mut ::std::os::raw::c_void, audio: *mut obs_audio_data) -> *mut obs_audio_data {
-    let audio_capture = &mut *(data as *mut AudioCapture);
-    dispatch(audio_capture.source, audio, audio_capture.channels);
-    audio
+impl Source for AudioCapture {
+    const ID: &'static str = "audio_capture\0";
+    const NAME: &'static str = "Audio Capture\0";
+    const TYPE: obs_source_type = obs_source_type_OBS_SOURCE_TYPE_FILTER;
+    const OUTPUT_FLAGS: u32 = OBS_SOURCE_AUDIO;
+
+    fn create(_settings: &ObsData, source: SourceRef) -> Arc<Self> {
+        let audio_capture = Arc::new(AudioCapture {
+            source,
+            channels: unsafe { audio_output_get_channels(obs_get_audio()) },
+        });
+        AUDIO_CAPTURE_LIST.insert(audio_capture.clone());
+        audio_capture
+    }
+
+    fn destroy(&self) {
+        AUDIO_CAPTURE_LIST.remove(self);
+    }
+
+    fn filter_audio(&self, audio: &mut obs_audio_data) {
+        unsafe { dispatch_audio(self.source.uuid(), &audio.data, audio.frames as usize, self.channels, audio.timestamp) };
+    }
 }
@@ -1,25 +1,399 @@
 use std::collections::VecDeque;
 use std::ffi::{CStr, CString};
-use std::mem::size_of;
-use std::ptr::{null_mut, slice_from_raw_parts};
-use std::sync::Mutex;
+use std::ptr::slice_from_raw_parts;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use bindings::{blog, gs_color_format_GS_BGRA, gs_draw_sprite, GS_DYNAMIC, gs_effect_get_param_by_name, gs_effect_set_texture, gs_effect_t, gs_texture_create, gs_texture_destroy, gs_texture_set_image, gs_texture_t, LOG_ERROR, obs_audio_data, obs_combo_format_OBS_COMBO_FORMAT_STRING, obs_combo_type_OBS_COMBO_TYPE_LIST, obs_data_get_double, obs_data_get_int, obs_data_get_string, obs_data_set_default_double, obs_data_set_default_int, obs_data_t, obs_enter_graphics, obs_leave_graphics, obs_properties_add_float_slider, obs_properties_add_int, obs_properties_add_list, obs_properties_add_text, obs_properties_create, obs_properties_t, obs_property_list_add_string, obs_register_source_s, obs_source_get_name, obs_source_get_uuid, obs_source_info, obs_source_t, obs_source_type_OBS_SOURCE_TYPE_INPUT, OBS_SOURCE_VIDEO, obs_text_type_OBS_TEXT_INFO};
+use bindings::{audio_output_get_sample_rate, gs_effect_t, LOG_ERROR, LOG_WARNING, MAX_AUDIO_MIXES, obs_enum_sources, obs_get_audio, obs_property_list_add_string, obs_property_t, obs_source_t, obs_source_type, obs_source_type_OBS_SOURCE_TYPE_INPUT, OBS_SOURCE_AUDIO, OBS_SOURCE_CUSTOM_DRAW, OBS_SOURCE_VIDEO};
 
 use crate::audio_capture::AUDIO_CAPTURE_LIST;
+use crate::auth::{self, PayloadAuthenticator};
+use crate::cipher::PayloadCipher;
+use crate::data_channel::{DATA_TYPE_JSON, DATA_TYPE_MARKER, DATA_TYPE_SUBTITLE, DataQueue, frame_bytes};
+use crate::direct_capture::{attach, attach_mix, AttachResult, DirectCapture, FINAL_MIX};
+use crate::limiter::Limiter;
+use crate::logger::Logger;
+use crate::clipboard;
+use crate::obs::{CallData, canvas_size, Effect, GraphicsGuard, ObsData, Proc, Properties, register_source, Source, SourceRef, Texture, video_fps, video_frame_time};
+use crate::registry::Registry;
+use crate::ring_buffer::SpscRing;
+use crate::stats::Stats;
 
 const MAX_AUDIO_SOURCE_COUNT: usize = 4;
+/// 声音来源：混合选择的若干个声音源
+const CAPTURE_MODE_SOURCES: i64 = 0;
+/// 声音来源：直接使用 OBS 的某个输出音轨
+const CAPTURE_MODE_MIX_TRACK: i64 = 1;
+/// 追赶延迟时，每次最多多消耗 1/50 的采样，即播放速度最多加快 2%，人耳基本听不出音调变化
+const DRIFT_CORRECTION_MAX_RATIO: usize = 50;
+/// 缓冲长度超过 flush_len 的这个倍数时，直接清空 buffer，仅作为最后的保护手段
+const HARD_RESET_FLUSH_LEN_MULTIPLIER: usize = 10;
 
-pub static mut AUDIO_RENDERER_LIST: Mutex<Vec<*mut AudioRenderer>> = Mutex::new(Vec::new());
+/// 编码方式：在 CPU 上逐像素编码，作为参考实现
+const ENCODER_CPU: i64 = 0;
+/// 编码方式：只上传原始采样，由 shader 编码
+const ENCODER_GPU: i64 = 1;
+/// 编码方式：和 GPU 编码相同，但是由 Audio Renderer Overlay 滤镜以低对比度叠加在画面上，不出现在属性中
+pub const ENCODER_LOW_CONTRAST: i64 = 2;
+/// GPU 编码器的 shader
+const ENCODE_EFFECT: &str = include_str!("../data/audio_encode.effect");
+/// 每个声道的编码区域开头用于包序号、放大倍数和声道布局的格子数
+const HEADER_CELL_COUNT: usize = 10;
+/// 低对比度模式没有黑色的结束标记，头部另外用 16 个格子记录采样数
+const LOW_CONTRAST_HEADER_CELL_COUNT: usize = HEADER_CELL_COUNT + 16;
 
-/// 将 `data` f32 数组使用 [`encode_audio_sample`] 编码为 BGRA 格式并填充到 `buf` u8 数组中
-fn fill_texture_buffer(texture_buffer: &mut [u8], mut audio_buffer: impl Iterator<Item=f32>, width: usize, cell_width: usize, cell_height: usize, packet_index: u32, amplifier: f32) {
-    let amplifier = amplifier.clamp(1.0, 16.9).floor();
+/// 声道布局：上半部分是左声道，下半部分是右声道
+const LAYOUT_VERTICAL: i64 = 0;
+/// 声道布局：左半部分是左声道，右半部分是右声道，适合很矮的横条
+const LAYOUT_HORIZONTAL: i64 = 1;
+/// 声道布局：格子按行交错，偶数行是左声道，奇数行是右声道
+///
+/// 按行而不是按列交错，这样三种布局中左声道的头部都在第一行的开头，解码时可以先读出布局
+const LAYOUT_INTERLEAVED: i64 = 2;
+
+/// 数据通道的 proc handler 函数，`type` 是数据类型（1 ~ 255），`data` 是要发送的字符串，见 src/data_channel.rs
+const SEND_DATA_PROC: &str = "void send_data(in int type, in string data)";
+
+/// 编码区域位置：使用下面的自定义位置和大小
+pub const PLACEMENT_CUSTOM: i64 = 0;
+/// 编码区域位置：画面左侧的竖条
+const PLACEMENT_LEFT_STRIP: i64 = 1;
+/// 编码区域位置：画面顶部的横条
+const PLACEMENT_TOP_STRIP: i64 = 2;
+/// 编码区域位置：画面底部的横条
+const PLACEMENT_BOTTOM_STRIP: i64 = 3;
+/// 横条的高度和竖条的宽度分别是 16、32 像素，另一边按视频编码的 16 像素宏块向下对齐
+const STRIP_ALIGN: u32 = 16;
+
+/// 每个声音源通道的环形缓冲区容量（单位：采样），48000Hz 下为 1s
+const SOURCE_CHANNEL_CAPACITY: usize = 48000;
+/// 每个声音源通道最多记录的批次时间戳，OBS 每批通常是 1024 个采样，足够覆盖上面的 1s
+const SOURCE_CHANNEL_BATCH_CAPACITY: usize = 256;
+
+/// 已经添加了 proc handler 的 Audio Renderer，proc handler 按所属的源在这里查找实例，见 [`SourceRef::add_proc`]
+pub static AUDIO_RENDERER_LIST: Registry<AudioRenderer> = Registry::new();
+
+/// 选择了 Audio Capture 滤镜的声音源通道，滤镜在音频线程中按 uuid 查找并写入数据
+///
+/// 音频线程遍历这个列表时不加锁，所以不会等待正在修改设置的 UI 线程或者正在编码的渲染线程
+pub static SOURCE_CHANNEL_LIST: Registry<SourceChannel> = Registry::new();
+
+/// 一个声音源槽位的数据通道，音频线程写入，渲染线程在 video_render 中读出并混合
+pub struct SourceChannel {
+    /// 声音源的 uuid，可以是 Audio Capture 滤镜、任意带声音的源，或者 [`FINAL_MIX`]
+    pub uuid: CString,
+    /// 左右声道的采样
+    pub ring: SpscRing<[f32; 2]>,
+    /// 每批写入 ring 的采样数和第一个采样的时间戳（单位：纳秒）
+    pub batches: SpscRing<(usize, u64)>,
+}
+
+impl SourceChannel {
+    pub fn new(uuid: CString) -> Self {
+        Self {
+            uuid,
+            ring: SpscRing::new(SOURCE_CHANNEL_CAPACITY),
+            batches: SpscRing::new(SOURCE_CHANNEL_BATCH_CAPACITY),
+        }
+    }
+}
+
+/// 编码区域在画面中的位置和大小，和 userscript 的 `x,y,width,height,cell_width,cell_height` 一一对应
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub cell_width: u32,
+    pub cell_height: u32,
+    /// 是否以低对比度叠加在画面上
+    pub low_contrast: bool,
+    /// 是否用观看端密码加密
+    pub encrypted: bool,
+    /// 是否在头部写入验证标签
+    pub authenticated: bool,
+    /// 是否在头部写入视频帧和第一个采样的时间戳
+    pub timestamped: bool,
+    /// 每个包是否再带上上一个包的采样
+    pub redundant: bool,
+}
+
+impl Region {
+    /// 观看端解码脚本需要输入的配置字符串
+    ///
+    /// 低对比度、加密、验证、时间戳和冗余在可选的第 7 项中分别用第 0、1、2、3、4 位表示，密码和密钥本身不出现在配置字符串中
+    pub fn viewer_config(&self) -> String {
+        let config = format!("{},{},{},{},{},{}", self.x, self.y, self.width, self.height, self.cell_width, self.cell_height);
+        let flags = self.low_contrast as u32 | (self.encrypted as u32) << 1 | (self.authenticated as u32) << 2 | (self.timestamped as u32) << 3 | (self.redundant as u32) << 4;
+        if flags != 0 {
+            format!("{},{}", config, flags)
+        } else {
+            config
+        }
+    }
+}
+
+/// 预设位置对应的编码区域，1920x1080 的画面下左侧竖条、顶部横条和 README 中的 `0,0,32,1072,2,2`、`0,0,1920,16,2,2` 相同
+///
+/// 自定义位置返回 None
+fn preset_region(placement: i64, canvas_width: u32, canvas_height: u32) -> Option<Region> {
+    let strip_width = canvas_width / STRIP_ALIGN * STRIP_ALIGN;
+    let strip_height = canvas_height / STRIP_ALIGN * STRIP_ALIGN;
+    let (x, y, width, height) = match placement {
+        PLACEMENT_LEFT_STRIP => (0, 0, 32, strip_height),
+        PLACEMENT_TOP_STRIP => (0, 0, strip_width, 16),
+        PLACEMENT_BOTTOM_STRIP => (0, canvas_height.saturating_sub(16), strip_width, 16),
+        _ => return None,
+    };
+    Some(Region { x, y, width, height, cell_width: 2, cell_height: 2, low_contrast: false, encrypted: false, authenticated: false, timestamped: false, redundant: false })
+}
+
+/// `channel` 声道的第 `index` 个格子在编码区域中的列和行，`columns`、`rows` 是整个编码区域的格子列数和行数
+///
+/// data/audio_encode.effect 和 userscript 中有相同的计算
+fn cell_position(layout: i64, channel: usize, index: usize, columns: usize, rows: usize) -> (usize, usize) {
+    match layout {
+        LAYOUT_HORIZONTAL => {
+            let half = columns / 2;
+            (index % half + channel * half, index / half)
+        }
+        LAYOUT_INTERLEAVED => (index % columns, index / columns * 2 + channel),
+        _ => (index % columns, index / columns + channel * rows / 2),
+    }
+}
+
+/// 检查编码区域能否按 `layout` 划分，返回错误信息
+fn check_layout(layout: i64, width: usize, height: usize, cell_width: usize, cell_height: usize) -> Result<(), &'static str> {
+    if !width.is_multiple_of(cell_width) { // 必须整除
+        return Err("编码区域宽度必须整除格子宽度");
+    }
+    if !height.is_multiple_of(cell_height) { // 必须整除
+        return Err("编码区域高度必须整除格子高度");
+    }
+    let columns = width / cell_width;
+    let rows = height / cell_height;
+    match layout {
+        LAYOUT_HORIZONTAL => {
+            if !columns.is_multiple_of(2) {
+                return Err("左右分布时编码区域宽度的一半必须整除格子宽度");
+            }
+            // 解码时按第一行读取头部
+            if columns / 2 < HEADER_CELL_COUNT {
+                return Err("左右分布时每个声道每行至少要有 10 个格子");
+            }
+        }
+        LAYOUT_INTERLEAVED => {
+            if !rows.is_multiple_of(2) {
+                return Err("逐行交错时编码区域的格子行数必须是偶数");
+            }
+            if columns < HEADER_CELL_COUNT {
+                return Err("逐行交错时每行至少要有 10 个格子");
+            }
+        }
+        _ => {
+            if !rows.is_multiple_of(2) {
+                return Err("编码区域高度的一半必须整除格子高度");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 编码区域宽度、高度的上限，和属性中的范围相同
+const MAX_WIDTH: usize = 7680;
+const MAX_HEIGHT: usize = 4320;
+/// 最少缓冲长度的范围，和属性中的范围相同
+const MIN_FLUSH_LEN: usize = 480;
+const MAX_FLUSH_LEN: usize = 9600;
+
+/// 编码区域的划分方式和每个包至少要放下的内容，update 和属性的 modified 回调用 [`Geometry::check`] 做相同的检查
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Geometry {
+    width: usize,
+    height: usize,
+    cell_width: usize,
+    cell_height: usize,
+    layout: i64,
+    /// 低对比度模式的格子宽度和高度必须是偶数
+    low_contrast: bool,
+    /// 头部和附加头部的格子数
+    header_cell_count: usize,
+    /// 每个包中采样的份数，冗余时是 2
+    block_count: usize,
+    /// 验证时采样之后还要放下结束标记和辅助位
+    authenticated: bool,
+    /// 每个包至少要放下的采样数，设置了目标延迟时是 1 帧的采样数
+    flush_len: usize,
+}
+
+impl Geometry {
+    /// 每个声道的格子数
+    fn channel_cell_count(&self) -> usize {
+        (self.width / self.cell_width.max(1)) * (self.height / self.cell_height.max(1)) / 2
+    }
+
+    /// 编码区域最多能放下的缓冲长度
+    fn max_flush_len(&self) -> usize {
+        let cells = self.channel_cell_count().saturating_sub(self.header_cell_count);
+        if self.authenticated { auth::sample_capacity(cells) / self.block_count } else { cells / self.block_count }
+    }
+
+    /// 每个包至少要放下的格子数
+    fn required_cell_count(&self) -> usize {
+        let samples = self.flush_len * self.block_count;
+        let helpers = if self.authenticated { 1 + auth::helper_cell_count(samples) } else { 0 };
+        self.header_cell_count + samples + helpers
+    }
+
+    /// 有效的宽度和高度分别是这两个值的整数倍：左右分布时列数是偶数，其他布局行数是偶数
+    fn size_steps(&self) -> (usize, usize) {
+        let (column_step, row_step) = if self.layout == LAYOUT_HORIZONTAL { (2, 1) } else { (1, 2) };
+        (self.cell_width.max(1) * column_step, self.cell_height.max(1) * row_step)
+    }
+
+    /// 检查设置是否有效，返回错误信息
+    fn check(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 || self.cell_width == 0 || self.cell_height == 0 {
+            return Err("编码区域和格子的宽度、高度不能是 0".to_string());
+        }
+        check_layout(self.layout, self.width, self.height, self.cell_width, self.cell_height)?;
+        if self.low_contrast && (!self.cell_width.is_multiple_of(2) || !self.cell_height.is_multiple_of(2)) {
+            // 每个格子分成 4 个象限，解码端用对角象限之差抵消底下的画面
+            return Err("低对比度模式的格子宽度和高度必须是偶数".to_string());
+        }
+        if self.channel_cell_count() < self.required_cell_count() { // 编码区域不够大
+            let error = if self.block_count > 1 {
+                "开启冗余时编码区域大小必须大于两倍的缓冲长度加上头部"
+            } else {
+                "编码区域大小必须大于缓冲长度加上头部"
+            };
+            return Err(format!("{}（需要 {} 个采样，最多只能放下 {} 个）", error, self.flush_len, self.max_flush_len()));
+        }
+        Ok(())
+    }
+
+    /// 保持格子大小和布局，宽度和高度与当前相差最少的有效设置，低对比度模式下格子宽度和高度先向上取偶数
+    ///
+    /// 宽度和高度先取最接近的 [`Geometry::size_steps`] 的整数倍，再按缓冲长度加上头部需要的格子数增加高度
+    fn nearest_valid(&self) -> Option<Geometry> {
+        let (mut cell_width, mut cell_height) = (self.cell_width.max(1), self.cell_height.max(1));
+        if self.low_contrast {
+            cell_width = cell_width.next_multiple_of(2);
+            cell_height = cell_height.next_multiple_of(2);
+        }
+        let (width_step, height_step) = Geometry { cell_width, cell_height, ..*self }.size_steps();
+        let (column_step, row_step) = (width_step / cell_width, height_step / cell_height);
+        let required = self.required_cell_count();
+        let nearest_rows = ((self.height + height_step / 2) / height_step).max(1) * row_step;
+        (1..=MAX_WIDTH / width_step)
+            .filter_map(|n| {
+                let columns = n * column_step;
+                let rows = nearest_rows.max((2 * required).div_ceil(columns).next_multiple_of(row_step));
+                let geometry = Geometry { width: columns * cell_width, height: rows * cell_height, cell_width, cell_height, ..*self };
+                (geometry.height <= MAX_HEIGHT && geometry.check().is_ok()).then_some(geometry)
+            })
+            .min_by_key(|geometry| (geometry.width.abs_diff(self.width) + geometry.height.abs_diff(self.height), geometry.width))
+    }
+
+    /// 设置无效时显示在属性中的错误信息和建议，`preset` 为 true 时编码区域的大小由预设决定，只建议调整缓冲长度
+    fn describe_error(&self, error: &str, preset: bool) -> String {
+        let mut text = error.to_string();
+        if !preset {
+            if let Some(nearest) = self.nearest_valid() {
+                text += &format!("。最接近的有效设置：编码区域 {}x{}，格子 {}x{}", nearest.width, nearest.height, nearest.cell_width, nearest.cell_height);
+            }
+        } else if self.max_flush_len() >= MIN_FLUSH_LEN {
+            text += &format!("。可以把缓冲长度降到 {} 以内，或者改用自定义的位置和大小", self.max_flush_len());
+        }
+        text
+    }
+}
+
+/// 按设置计算编码区域，update 和属性的 modified 回调共用，`encoder` 同 [`AudioRenderer::update_with_encoder`]
+///
+/// 返回值的第 2 项表示编码区域的位置和大小来自预设
+fn settings_geometry(settings: &ObsData, encoder: i64) -> (Region, bool, Geometry) {
+    // 预设位置按画布大小计算，画布还没有初始化时使用自定义的位置和大小
+    let preset = canvas_size().and_then(|(canvas_width, canvas_height)| preset_region(settings.get_int("placement"), canvas_width, canvas_height));
+    let region = preset.unwrap_or_else(|| Region {
+        x: settings.get_int("position_x").max(0) as _,
+        y: settings.get_int("position_y").max(0) as _,
+        width: settings.get_int("width").max(0) as _,
+        height: settings.get_int("height").max(0) as _,
+        cell_width: settings.get_int("cell_width").max(0) as _,
+        cell_height: settings.get_int("cell_height").max(0) as _,
+        low_contrast: false,
+        encrypted: false,
+        authenticated: false,
+        timestamped: false,
+        redundant: false,
+    });
+    let encrypted = !settings.get_string("passphrase").is_empty();
+    let authenticated = !settings.get_string("auth_secret").is_empty();
+    let redundant = settings.get_bool("redundancy");
+    let header_cell_count = if encoder == ENCODER_LOW_CONTRAST { LOW_CONTRAST_HEADER_CELL_COUNT } else { HEADER_CELL_COUNT };
+    // 设置了目标延迟时放不下会退回到更少的帧数，至少要放下 1 帧
+    let target_latency = settings.get_int("target_latency");
+    let flush_len = match video_fps().filter(|_| target_latency > 0) {
+        Some((fps_num, fps_den)) => frames_sample_count(1, unsafe { audio_output_get_sample_rate(obs_get_audio()) }, fps_num, fps_den),
+        None => settings.get_int("flush_len").max(0) as usize,
+    };
+    let geometry = Geometry {
+        width: region.width as usize,
+        height: region.height as usize,
+        cell_width: region.cell_width as usize,
+        cell_height: region.cell_height as usize,
+        layout: settings.get_int("layout"),
+        low_contrast: encoder == ENCODER_LOW_CONTRAST,
+        header_cell_count: header_cell_count + extra_header_cell_count(encrypted, authenticated, settings.get_bool("embed_timestamps"), redundant),
+        // 冗余时每个包要放下两份采样
+        block_count: 1 + redundant as usize,
+        authenticated,
+        flush_len,
+    };
+    (region, preset.is_some(), geometry)
+}
+
+/// 写入头部的放大倍数，取值范围是 1 ~ 16 的整数
+fn header_amplifier(amplifier: f32) -> f32 {
+    amplifier.clamp(1.0, 16.9).floor()
+}
+
+/// 按帧率计算 `frames` 帧画面的时间内有多少个采样，帧率不能整除采样率时向下取整，多出来的采样由追赶延迟的逻辑消耗
+fn frames_sample_count(frames: usize, sample_rate: u32, fps_num: u32, fps_den: u32) -> usize {
+    (frames as u64 * sample_rate as u64 * fps_den as u64 / fps_num as u64) as usize
+}
+
+/// 最接近目标延迟 `target_latency` 毫秒的整数帧数，至少 1 帧
+fn target_latency_frames(target_latency: u32, fps_num: u32, fps_den: u32) -> usize {
+    ((target_latency as u64 * fps_num as u64 + 500 * fps_den as u64) / (1000 * fps_den as u64)).max(1) as usize
+}
+
+/// 附加头部的格子数，每个格子 1 位：加密时是 32 位的盐，加密或者验证时是 32 位的完整包序号，验证时是 32 位的标签，
+/// 之后是 64 位的视频帧时间戳和 64 位的第一个采样的时间戳，冗余时最后是 32 位的上一个包的采样数
+fn extra_header_cell_count(encrypted: bool, authenticated: bool, timestamped: bool, redundant: bool) -> usize {
+    32 * (encrypted as usize + (encrypted || authenticated) as usize + authenticated as usize + redundant as usize) + 128 * timestamped as usize
+}
+
+/// 把 `value` 的 32 位追加到附加头部中，低位在前
+fn push_header_bits(bits: &mut Vec<bool>, value: u32) {
+    bits.extend((0..32).map(|i| value & (1 << i) != 0));
+}
+
+/// 将一个声道的 f32 采样编码为 BGRA 格式，按 `layout` 填充到整个编码区域的 `texture_buffer` 中属于 `channel` 的格子
+///
+/// `extra_header` 是紧跟在固定的头部之后的附加头部，见 [`extra_header_cell_count`]，`data` 是采样和黑色的结束标记之后数据通道的各位
+// 只在 render 中调用，参数都直接来自 VideoState
+#[allow(clippy::too_many_arguments)]
+fn fill_texture_buffer(texture_buffer: &mut [u8], mut audio_buffer: impl Iterator<Item=f32>, layout: i64, channel: usize, width: usize, cell_width: usize, cell_height: usize, packet_index: u32, amplifier: f32, extra_header: &[bool], data: &[bool]) {
+    let amplifier = header_amplifier(amplifier);
     let amplifier_u32 = amplifier as u32 - 1;
     // buffer 第 1~4 个数据点是包序号，用于同步
     // buffer 第 5~8 个数据点是音量缩放系数，取值范围是 0 ~ 15
     // 0 表示不缩放（通常用于 0 ~ -3dB 左右的声音）
     // 15 表示振幅放大到原来的 16 倍（通常用于 -24dB 的声音）
+    // buffer 第 9~10 个数据点是声道布局
     let prefix = [
         if packet_index & 0x1 != 0 { 255u8 } else { 0u8 },
         if packet_index & 0x2 != 0 { 255u8 } else { 0u8 },
@@ -29,57 +403,64 @@ fn fill_texture_buffer(texture_buffer: &mut [u8], mut audio_buffer: impl Iterato
         if amplifier_u32 & 0x2 != 0 { 255u8 } else { 0u8 },
         if amplifier_u32 & 0x4 != 0 { 255u8 } else { 0u8 },
         if amplifier_u32 & 0x8 != 0 { 255u8 } else { 0u8 },
+        if layout & 0x1 != 0 { 255u8 } else { 0u8 },
+        if layout & 0x2 != 0 { 255u8 } else { 0u8 },
     ];
-    let mut prefix_iter = prefix.into_iter();
+    let mut prefix_iter = prefix.into_iter().chain(extra_header.iter().map(|bit| if *bit { 255u8 } else { 0u8 }));
+    let mut suffix_iter = std::iter::once(0u8).chain(data.iter().map(|bit| if *bit { 255u8 } else { 0u8 }));
     let height = texture_buffer.len() / 4 / width;
+    let columns = width / cell_width;
+    let rows = height / cell_height;
     let cell_pixel_count = cell_width * cell_height;
-    for y in (0..height).step_by(cell_height) {
-        for x in (0..width).step_by(cell_width) {
-            if let Some(gray) = prefix_iter.next() {
-                for j in 0..cell_height {
-                    for i in 0..cell_width {
-                        let texture_buffer_index = 4 * ((y + j) * width + (x + i)); // 因为 buf 中的存储格式是 BGRX，所以需要乘以 4
-                        texture_buffer[texture_buffer_index + 0] = gray; // B
-                        texture_buffer[texture_buffer_index + 1] = gray; // G
-                        texture_buffer[texture_buffer_index + 2] = gray; // R
-                        texture_buffer[texture_buffer_index + 3] = 255; // A
-                    }
+    for index in 0..columns * rows / 2 {
+        let (column, row) = cell_position(layout, channel, index, columns, rows);
+        let x = column * cell_width;
+        let y = row * cell_height;
+        if let Some(gray) = prefix_iter.next() {
+            for j in 0..cell_height {
+                for i in 0..cell_width {
+                    let texture_buffer_index = 4 * ((y + j) * width + (x + i)); // 因为 buf 中的存储格式是 BGRX，所以需要乘以 4
+                    texture_buffer[texture_buffer_index + 0] = gray; // B
+                    texture_buffer[texture_buffer_index + 1] = gray; // G
+                    texture_buffer[texture_buffer_index + 2] = gray; // R
+                    texture_buffer[texture_buffer_index + 3] = 255; // A
                 }
-            } else if let Some(v) = audio_buffer.next() {
-                // 音频部分
-                // 音频数据编码到 16.0 ~ 256.0 范围
-                let v1 = 16.0 + 120.0 * (v * amplifier + 1.0);
-                let mut n = (cell_pixel_count * 2) as f32;
-                let mut v2 = v1 * n;
-                for j in 0..cell_height {
-                    for i in 0..cell_width {
-                        // 在一个 cell 中，要进行 dithering，如果 cell_width cell_height 都是 2 的话，相当于 4 个像素编码 1 个采样，可以多 2bit 信息。
-                        // RGB 按 1:2:1 分配
-                        // R 和 B 取相同数值，G 取另一个数值，这样 1 个像素可以编码 2 个 8bit 信息，相当于 1 个像素编码了 9bit 的信息，如果一个 cell 是 4 个像素相当于 1 个音频采样编码成了 11bit 的深度。
-                        let r3 = (if v1 * n >= v2 { v1 as i32 } else { v1 as i32 + 1 }).clamp(16, 255);
-                        v2 -= r3 as f32;
-                        n -= 1.0;
-                        let g3 = (if v1 * n >= v2 { v1 as i32 } else { v1 as i32 + 1 }).clamp(16, 255);
-                        v2 -= g3 as f32;
-                        n -= 1.0;
-                        let texture_buffer_index = 4 * ((y + j) * width + (x + i)); // 因为 buf 中的存储格式是 BGRX，所以需要乘以 4
-                        texture_buffer[texture_buffer_index + 0] = r3 as u8; // B
-                        texture_buffer[texture_buffer_index + 1] = g3 as u8; // G
-                        texture_buffer[texture_buffer_index + 2] = r3 as u8; // R
-                        texture_buffer[texture_buffer_index + 3] = 255; // A
-                    }
+            }
+        } else if let Some(v) = audio_buffer.next() {
+            // 音频部分
+            // 音频数据编码到 16.0 ~ 256.0 范围
+            let v1 = 16.0 + 120.0 * (v * amplifier + 1.0);
+            let mut n = (cell_pixel_count * 2) as f32;
+            let mut v2 = v1 * n;
+            for j in 0..cell_height {
+                for i in 0..cell_width {
+                    // 在一个 cell 中，要进行 dithering，如果 cell_width cell_height 都是 2 的话，相当于 4 个像素编码 1 个采样，可以多 2bit 信息。
+                    // RGB 按 1:2:1 分配
+                    // R 和 B 取相同数值，G 取另一个数值，这样 1 个像素可以编码 2 个 8bit 信息，相当于 1 个像素编码了 9bit 的信息，如果一个 cell 是 4 个像素相当于 1 个音频采样编码成了 11bit 的深度。
+                    let r3 = (if v1 * n >= v2 { v1 as i32 } else { v1 as i32 + 1 }).clamp(16, 255);
+                    v2 -= r3 as f32;
+                    n -= 1.0;
+                    let g3 = (if v1 * n >= v2 { v1 as i32 } else { v1 as i32 + 1 }).clamp(16, 255);
+                    v2 -= g3 as f32;
+                    n -= 1.0;
+                    let texture_buffer_index = 4 * ((y + j) * width + (x + i)); // 因为 buf 中的存储格式是 BGRX，所以需要乘以 4
+                    texture_buffer[texture_buffer_index + 0] = r3 as u8; // B
+                    texture_buffer[texture_buffer_index + 1] = g3 as u8; // G
+                    texture_buffer[texture_buffer_index + 2] = r3 as u8; // R
+                    texture_buffer[texture_buffer_index + 3] = 255; // A
                 }
-            } else {
-                // 其余部分静音
-                // 静音状态的颜色是纯黑色
-                for j in 0..cell_height {
-                    for i in 0..cell_width {
-                        let texture_buffer_index = ((y + j) * width + (x + i)) * 4;
-                        texture_buffer[texture_buffer_index + 0] = 0; // B
-                        texture_buffer[texture_buffer_index + 1] = 0; // G
-                        texture_buffer[texture_buffer_index + 2] = 0; // R
-                        texture_buffer[texture_buffer_index + 3] = 255; // A
-                    }
+            }
+        } else {
+            // 采样之后是结束标记和数据通道，其余部分静音
+            // 静音状态的颜色是纯黑色
+            let gray = suffix_iter.next().unwrap_or(0);
+            for j in 0..cell_height {
+                for i in 0..cell_width {
+                    let texture_buffer_index = ((y + j) * width + (x + i)) * 4;
+                    texture_buffer[texture_buffer_index + 0] = gray; // B
+                    texture_buffer[texture_buffer_index + 1] = gray; // G
+                    texture_buffer[texture_buffer_index + 2] = gray; // R
+                    texture_buffer[texture_buffer_index + 3] = 255; // A
                 }
             }
         }
@@ -92,6 +473,24 @@ fn truncate_front<T>(q: &mut VecDeque<T>, n: usize) {
     }
 }
 
+/// 将 `audio_buffer` 的前 `input_len` 个采样通过线性插值伸缩为 `output_len` 个采样
+///
+/// 用于缓冲过长时平滑地追赶延迟，`input_len` 略大于 `output_len` 时相当于轻微加快播放速度，不会产生断点
+fn stretch_audio_buffer(audio_buffer: &VecDeque<f32>, input_len: usize, output_len: usize) -> Vec<f32> {
+    if input_len == output_len || output_len <= 1 {
+        return audio_buffer.iter().take(output_len).copied().collect();
+    }
+    let step = (input_len - 1) as f64 / (output_len - 1) as f64;
+    (0..output_len).map(|i| {
+        let position = i as f64 * step;
+        let index = position as usize;
+        let fraction = (position - index as f64) as f32;
+        let v0 = audio_buffer.get(index).copied().unwrap_or(0.0);
+        let v1 = audio_buffer.get(index + 1).copied().unwrap_or(v0);
+        v0 + (v1 - v0) * fraction
+    }).collect()
+}
+
 fn mix_audio_buffer(audio_buffer: &mut VecDeque<f32>, audio_data: &[f32], amplifier: f32, mut source_sample_number: usize, base_sample_number: usize) -> usize {
     if source_sample_number < base_sample_number {
         source_sample_number = base_sample_number;
@@ -110,46 +509,119 @@ fn mix_audio_buffer(audio_buffer: &mut VecDeque<f32>, audio_data: &[f32], amplif
     source_sample_number
 }
 
-pub unsafe fn dispatch(audio_capture_source: *mut obs_source_t, audio: *mut obs_audio_data, channels: usize) {
-    let audio_capture_uuid = CStr::from_ptr(obs_source_get_uuid(audio_capture_source));
-    for audio_renderer in &*AUDIO_RENDERER_LIST.lock().unwrap() {
-        let audio_renderer = &mut **audio_renderer;
-        for (i, source_uuid) in audio_renderer.source_uuids.iter().enumerate() {
-            if audio_capture_uuid == source_uuid.as_c_str() {
-                let mut audio_buffer = audio_renderer.audio_buffer.lock().unwrap();
-                // 仅支持双声道
-                if channels >= 1 {
-                    let base_sample_number = audio_renderer.base_sample_number;
-                    let source_sample_number = audio_renderer.source_sample_number[i];
-                    let source_amplifier = audio_renderer.source_amplifier[i];
-                    let new_source_sample_number = if channels == 1 {
-                        mix_audio_buffer(&mut audio_buffer[0], &*slice_from_raw_parts((*audio).data[0] as *mut f32, (*audio).frames as usize), source_amplifier, source_sample_number, base_sample_number);
-                        mix_audio_buffer(&mut audio_buffer[1], &*slice_from_raw_parts((*audio).data[0] as *mut f32, (*audio).frames as usize), source_amplifier, source_sample_number, base_sample_number)
-                    } else if channels >= 2 {
-                        mix_audio_buffer(&mut audio_buffer[0], &*slice_from_raw_parts((*audio).data[0] as *mut f32, (*audio).frames as usize), source_amplifier, source_sample_number, base_sample_number);
-                        mix_audio_buffer(&mut audio_buffer[1], &*slice_from_raw_parts((*audio).data[1] as *mut f32, (*audio).frames as usize), source_amplifier, source_sample_number, base_sample_number)
-                    } else {
-                        unreachable!();
-                    };
-                    audio_renderer.source_sample_number[i] = new_source_sample_number;
+/// 把 Audio Capture 滤镜的数据写入所有选择了这个滤镜的声音源通道，只在音频线程调用，不会阻塞
+pub unsafe fn dispatch_audio(audio_capture_uuid: &CStr, data: &[*mut u8], frames: usize, channels: usize, timestamp: u64) {
+    SOURCE_CHANNEL_LIST.for_each(|source_channel| {
+        if audio_capture_uuid == source_channel.uuid.as_c_str() {
+            push_audio(source_channel, data, frames, channels, false, timestamp);
+        }
+    });
+}
+
+/// 将一批 float planar 格式的音频数据写入声音源通道，只在音频线程调用，不会阻塞
+///
+/// `muted` 为 true 时写入静音，这样声音源静音时不会因为缺少数据而被认为已停用，`timestamp` 是第一个采样的时间戳
+pub unsafe fn push_audio(source_channel: &SourceChannel, data: &[*mut u8], frames: usize, channels: usize, muted: bool, timestamp: u64) {
+    // 仅支持双声道
+    if channels >= 1 {
+        let left = &*slice_from_raw_parts(data[0] as *const f32, frames);
+        let right = &*slice_from_raw_parts(data[if channels >= 2 { 1 } else { 0 }] as *const f32, frames);
+        let written = source_channel.ring.push_with(frames, |i| if muted { [0.0, 0.0] } else { [left[i], right[i]] });
+        if written > 0 {
+            source_channel.batches.push_with(1, |_| (written, timestamp));
+        }
+    }
+}
+
+/// 把声音源通道中新写入的数据混合到 audio_buffer 中，在渲染线程调用
+///
+/// 第一个已填充数据的声音源的时间戳记录在 `timestamps` 中，多个声音源的时钟可能不完全一致，只以这一个为准
+///
+/// 声音源开始提供数据时输出一次日志，停止提供数据见 [`AudioState::source_active`]
+fn drain_source_channels(audio_state: &mut AudioState, logger: &Logger) {
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+    let mut batches = Vec::new();
+    let base_sample_number = audio_state.base_sample_number;
+    let mut reference = (0..MAX_AUDIO_SOURCE_COUNT).find(|i| audio_state.source_channels[*i].is_some() && audio_state.source_sample_number[*i] >= base_sample_number);
+    for i in 0..MAX_AUDIO_SOURCE_COUNT {
+        if let Some(source_channel) = &audio_state.source_channels[i] {
+            left.clear();
+            right.clear();
+            batches.clear();
+            source_channel.batches.pop_all(|batch| batches.push(batch));
+            source_channel.ring.pop_all(|[l, r]| {
+                left.push(l);
+                right.push(r);
+            });
+            let overrun = source_channel.ring.overrun();
+            if overrun != audio_state.source_overrun[i] {
+                // 渲染线程长时间没有读取数据，音频线程丢弃了写不下的数据
+                logger.log_limited(LOG_WARNING, "source_overrun", &format!("声音源{} 的数据来不及读取，已丢弃 {} 个采样", i + 1, overrun - audio_state.source_overrun[i]));
+                audio_state.source_overrun[i] = overrun;
+            }
+            if left.is_empty() {
+                continue;
+            }
+            if !audio_state.source_active[i] {
+                audio_state.source_active[i] = true;
+                logger.info(&format!("声音源{} 开始提供数据", i + 1));
+            }
+            let base_sample_number = audio_state.base_sample_number;
+            let source_sample_number = audio_state.source_sample_number[i];
+            let source_amplifier = audio_state.source_amplifier[i];
+            mix_audio_buffer(&mut audio_state.audio_buffer[0], &left, source_amplifier, source_sample_number, base_sample_number);
+            audio_state.source_sample_number[i] = mix_audio_buffer(&mut audio_state.audio_buffer[1], &right, source_amplifier, source_sample_number, base_sample_number);
+            // 还没有参考的声音源时，以这一次最先填充的声音源为准
+            if reference.is_none() {
+                reference = Some(i);
+            }
+            if reference == Some(i) {
+                let mut sample_number = audio_state.source_sample_number[i] - left.len();
+                for (frames, timestamp) in &batches {
+                    audio_state.timestamps.push_back((sample_number, *timestamp));
+                    sample_number += frames;
                 }
             }
         }
     }
 }
 
+/// 第 `sample_number` 个采样的时间戳，由之前最近一批的时间戳按采样率推算，没有记录时返回 0
+fn sample_timestamp(timestamps: &VecDeque<(usize, u64)>, sample_number: usize, sample_rate: u32) -> u64 {
+    match timestamps.iter().rev().find(|(n, _)| *n <= sample_number).or(timestamps.front()) {
+        Some((n, timestamp)) if sample_rate > 0 => {
+            let offset = (sample_number as i64 - *n as i64) * 1_000_000_000 / sample_rate as i64;
+            timestamp.saturating_add_signed(offset)
+        }
+        _ => 0,
+    }
+}
+
+/// Audio Renderer 的实例，OBS 的 `data` 指针指向 `Arc<AudioRenderer>`
+///
+/// 状态按访问的线程拆成三部分，各自加锁。加锁顺序固定为：
+/// graphics（obs_enter_graphics）→ captures → video → audio → data → [`SOURCE_CHANNEL_LIST`] 的 writer，
+/// 渲染线程调用 video_render 时已经持有 graphics。音频线程只通过 [`SourceChannel`] 写入数据，不会锁这里的任何一个。
 pub struct AudioRenderer {
-    pub source_uuids: [CString; MAX_AUDIO_SOURCE_COUNT],
+    /// 混合后的声音数据，只有 UI 线程和渲染线程访问
+    pub audio: Mutex<AudioState>,
+    /// 编码区域的设置和 texture，只有 UI 线程和渲染线程访问
+    pub video: Mutex<VideoState>,
+    /// 直接捕获注册的回调
+    pub captures: Mutex<CaptureState>,
+    /// 数据通道，proc handler 可能在任意线程调用
+    pub data: Mutex<DataState>,
+    /// 日志中带有视频源自己或者持有这个实例的滤镜，显示实时统计时也用这个源刷新属性
+    pub logger: Logger,
+}
+
+pub struct AudioState {
+    /// 每个声音源槽位的数据通道，None 表示没有选择声音源
+    pub source_channels: [Option<Arc<SourceChannel>>; MAX_AUDIO_SOURCE_COUNT],
     pub source_amplifier: [f32; MAX_AUDIO_SOURCE_COUNT],
-    pub width: usize,
-    pub height: usize,
-    pub cell_width: usize,
-    pub cell_height: usize,
-    pub flush_len: usize,
-    pub texture_buffer: Vec<u8>,
-    pub texture: *mut gs_texture_t,
     /// audio_buffer 仅支持双声，支持最多 4 个源
-    pub audio_buffer: Mutex<[VecDeque<f32>; 2]>,
+    pub audio_buffer: [VecDeque<f32>; 2],
     /// 音频源当前已写入 audio_buffer 的数据的下一个采样的序号
     ///
     /// 1. 如果 source_sample_number < base_sample_number 则表示当前源已停用，通常此时 source_sample_number == 0
@@ -165,228 +637,1090 @@ pub struct AudioRenderer {
     pub source_sample_number: [usize; MAX_AUDIO_SOURCE_COUNT],
     /// audio_buffer 第一个采样对应的采样序号
     pub base_sample_number: usize,
+    /// 每个声音源通道上一次检查时累计丢弃的采样数
+    pub source_overrun: [usize; MAX_AUDIO_SOURCE_COUNT],
+    /// 每个声音源槽位是否正在提供数据，只在变化时输出日志，清空缓冲不会改变
+    pub source_active: [bool; MAX_AUDIO_SOURCE_COUNT],
+    /// 参考的声音源每批数据的第一个采样的序号和时间戳（单位：纳秒），见 [`drain_source_channels`]
+    pub timestamps: VecDeque<(usize, u64)>,
+}
+
+impl AudioState {
+    /// 清空缓冲的数据，所有声音源重新开始填充
+    fn reset(&mut self) {
+        self.audio_buffer[0].clear();
+        self.audio_buffer[1].clear();
+        self.source_sample_number.fill(0);
+        self.base_sample_number = 1; // 0 用于默认值
+        self.timestamps.clear();
+    }
+}
+
+pub struct VideoState {
+    /// 编码区域在画面中的位置，只用于生成观看端配置字符串
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub cell_width: usize,
+    pub cell_height: usize,
+    pub layout: i64,
+    pub flush_len: usize,
+    /// 为 None 表示设置无效或者 texture 创建失败，此时不渲染
+    pub encoder: Option<Encoder>,
     /// 视频帧变化的次数，用作时钟
     pub packet_index: usize,
+    /// 是否在编码前对混合后的声音限幅
+    pub limiter_enabled: bool,
+    pub limiter: Limiter,
+    /// 设置了观看端密码时加密采样
+    pub cipher: Option<PayloadCipher>,
+    /// tick 中编码了新的包，下一次渲染时上传到 texture
+    pub pending_upload: bool,
+    /// 设置了目标延迟时为 true，每个包正好是 flush_len 个采样，剩下的留给下一个包
+    pub exact_flush: bool,
+    /// 设置了验证密钥时在头部写入标签
+    pub authenticator: Option<PayloadAuthenticator>,
+    /// 是否在头部写入时间戳
+    pub timestamped: bool,
+    /// 用于由批次的时间戳推算第一个采样的时间戳
+    pub sample_rate: u32,
+    /// 是否在每个包的采样之前再放一份上一个包的采样
+    pub redundant: bool,
+    /// 上一个包的左右声道采样，还没有加密和对齐档位
+    pub previous_block: [Vec<f32>; 2],
+    /// 是否在属性中显示实时统计
+    pub show_stats: bool,
+    pub stats: Stats,
 }
 
-pub unsafe fn register() {
-    obs_register_source_s(&obs_source_info {
-        id: "audio_renderer\0".as_ptr().cast(),
-        type_: obs_source_type_OBS_SOURCE_TYPE_INPUT,
-        output_flags: OBS_SOURCE_VIDEO,
-        get_name: Some(filter_getname),
-        create: Some(filter_create),
-        destroy: Some(filter_destroy),
-        get_width: Some(get_width),
-        get_height: Some(get_height),
-        get_defaults: Some(get_defaults),
-        get_properties: Some(get_properties),
-        update: Some(filter_update),
-        video_render: Some(video_render),
-        ..Default::default()
-    }, size_of::<obs_source_info>());
-}
-
-unsafe extern "C" fn filter_getname(_type_data: *mut ::std::os::raw::c_void) -> *const ::std::os::raw::c_char {
-    "Audio Renderer\0".as_ptr().cast()
-}
-
-unsafe extern "C" fn filter_create(settings: *mut obs_data_t, _source: *mut obs_source_t) -> *mut ::std::os::raw::c_void {
-    let audio_renderer = Box::new(AudioRenderer {
-        source_uuids: Default::default(),
-        source_amplifier: Default::default(),
-        width: 0,
-        height: 0,
-        cell_width: 0,
-        cell_height: 0,
-        flush_len: 0,
-        texture_buffer: Vec::new(),
-        texture: null_mut(),
-        audio_buffer: Default::default(),
-        source_sample_number: Default::default(),
-        base_sample_number: 1, // 0 用于默认值
-        packet_index: 0,
-    });
-    let p = Box::into_raw(audio_renderer);
-    filter_update(p as _, settings);
-    AUDIO_RENDERER_LIST.lock().unwrap().push(p);
-    p as _
+pub enum Encoder {
+    /// 用 [`fill_texture_buffer`] 在 CPU 上编码整个画面，然后上传
+    Cpu {
+        texture_buffer: Vec<u8>,
+        texture: Texture,
+    },
+    /// 把采样原样上传到 R32F 的 texture，由 data/audio_encode.effect 编码
+    Gpu {
+        effect: Effect,
+        /// 第 0 行是左声道，第 1 行是右声道，每行的长度是每个声道的格子数
+        sample_buffer: Vec<f32>,
+        sample_texture: Texture,
+        /// 最近一次输出的采样数
+        sample_count: usize,
+        /// 最近一次输出的附加头部格子数，每行先是附加头部的各位，之后才是采样
+        extra_header_count: usize,
+        /// 最近一次输出的数据通道格子数，每行在采样之后空一个格子，之后是数据通道的各位，取左右声道中较多的一个
+        data_cell_count: usize,
+        /// 最近一次输出时左右声道的放大倍数
+        amplifier: [f32; 2],
+        /// 最近一次输出时的包序号，头部只记录低 4 位，加密时另外记录完整的 32 位
+        packet_index: u32,
+        /// 由滤镜以低对比度叠加在画面上，见 [`AudioRenderer::render_low_contrast`]
+        low_contrast: bool,
+    },
+}
+
+impl Encoder {
+    fn new(graphics: &GraphicsGuard, encoder: i64, width: usize, height: usize, cell_width: usize, cell_height: usize, logger: &Logger) -> Option<Self> {
+        if encoder == ENCODER_GPU || encoder == ENCODER_LOW_CONTRAST {
+            match Effect::new(graphics, ENCODE_EFFECT, "audio_encode.effect") {
+                Ok(effect) => {
+                    let cell_count = (width / cell_width) * (height / cell_height) / 2;
+                    return Texture::new_r32f(graphics, cell_count as _, 2).map(|sample_texture| Encoder::Gpu {
+                        effect,
+                        sample_buffer: vec![0.0; cell_count * 2],
+                        sample_texture,
+                        sample_count: 0,
+                        extra_header_count: 0,
+                        data_cell_count: 0,
+                        amplifier: [1.0; 2],
+                        packet_index: 0,
+                        low_contrast: encoder == ENCODER_LOW_CONTRAST,
+                    });
+                }
+                Err(error) => {
+                    // 退回到 CPU 编码
+                    logger.error(&format!("GPU 编码器 shader 编译失败：{}", error));
+                }
+            }
+        }
+        Texture::new(graphics, width as _, height as _).map(|texture| Encoder::Cpu {
+            texture_buffer: vec![0u8; width * height * 4],
+            texture,
+        })
+    }
 }
 
-unsafe extern "C" fn filter_destroy(data: *mut ::std::os::raw::c_void) {
-    let p = data as *mut AudioRenderer;
-    obs_enter_graphics();
-    gs_texture_destroy((*p).texture);
-    obs_leave_graphics();
-    AUDIO_RENDERER_LIST.lock().unwrap().retain(|v| *v != p);
-    let _ = Box::from_raw(p);
+/// GPU 编码器有新数据时上传采样，并设置 data/audio_encode.effect 的公共参数，不是 GPU 编码器时返回 None
+fn prepare_gpu_encoder<'a>(graphics: &GraphicsGuard, video_state: &'a mut VideoState, modified: bool) -> Option<&'a Effect> {
+    match &mut video_state.encoder {
+        Some(Encoder::Gpu { effect, sample_buffer, sample_texture, sample_count, extra_header_count, data_cell_count, amplifier, packet_index, .. }) => {
+            if modified {
+                let bytes: Vec<u8> = sample_buffer.iter().flat_map(|v| v.to_ne_bytes()).collect();
+                sample_texture.set_image(graphics, &bytes);
+            }
+            effect.set_texture("samples", sample_texture);
+            effect.set_float("width", video_state.width as f32);
+            effect.set_float("height", video_state.height as f32);
+            effect.set_float("cell_width", video_state.cell_width as f32);
+            effect.set_float("cell_height", video_state.cell_height as f32);
+            effect.set_float("channel_layout", video_state.layout as f32);
+            effect.set_float("sample_count", *sample_count as f32);
+            effect.set_float("extra_header_count", *extra_header_count as f32);
+            effect.set_float("data_cell_count", *data_cell_count as f32);
+            effect.set_float("packet_index", (*packet_index & 0xf) as f32);
+            effect.set_float("amplifier_0", amplifier[0]);
+            effect.set_float("amplifier_1", amplifier[1]);
+            Some(effect)
+        }
+        _ => None,
+    }
 }
 
-unsafe extern "C" fn get_width(data: *mut ::std::os::raw::c_void) -> u32 {
-    let audio_renderer = &mut *(data as *mut AudioRenderer);
-    audio_renderer.width as _
+impl Encoder {
+    /// 每个声道固定头部的格子数
+    fn header_cell_count(&self) -> usize {
+        match self {
+            Encoder::Gpu { low_contrast: true, .. } => LOW_CONTRAST_HEADER_CELL_COUNT,
+            _ => HEADER_CELL_COUNT,
+        }
+    }
 }
 
-unsafe extern "C" fn get_height(data: *mut ::std::os::raw::c_void) -> u32 {
-    let audio_renderer = &mut *(data as *mut AudioRenderer);
-    audio_renderer.height as _
+pub struct DataState {
+    /// 等待发送的数据帧
+    pub queue: DataQueue,
+    /// 属性中输入的数据类型和文本，点击“发送数据”按钮时加入队列
+    pub draft_type: i64,
+    pub draft_text: String,
 }
 
-unsafe extern "C" fn get_defaults(settings: *mut obs_data_t) {
-    for i in 0..MAX_AUDIO_SOURCE_COUNT {
-        let _ = obs_data_set_default_double(settings, format!("source{}_amplifier\0", i).as_ptr().cast(), 1.0);
+pub struct CaptureState {
+    /// 直接捕获的声音源注册的回调，Audio Capture 滤镜对应的槽位为 None
+    pub direct_captures: [Option<Box<DirectCapture>>; MAX_AUDIO_SOURCE_COUNT],
+    /// 声音源暂时还不存在，需要在渲染时重试注册回调
+    pub direct_capture_pending: [bool; MAX_AUDIO_SOURCE_COUNT],
+    /// 输出音轨模式下捕获的音轨序号（0 ~ 5），为 None 表示使用上面的声音源混合
+    ///
+    /// 输出音轨模式只使用第 0 个槽位，不再混合其他声音源，也不放大
+    pub mix_track: Option<usize>,
+}
+
+pub unsafe fn register() {
+    register_source::<AudioRenderer>();
+}
+
+impl AudioRenderer {
+    /// 还没有应用任何设置的实例，设置无效时不渲染
+    pub fn new() -> Arc<Self> {
+        Arc::new(AudioRenderer {
+            audio: Mutex::new(AudioState {
+                source_channels: Default::default(),
+                source_amplifier: Default::default(),
+                audio_buffer: Default::default(),
+                source_sample_number: Default::default(),
+                base_sample_number: 1, // 0 用于默认值
+                source_overrun: Default::default(),
+                source_active: Default::default(),
+                timestamps: VecDeque::new(),
+            }),
+            video: Mutex::new(VideoState {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+                cell_width: 0,
+                cell_height: 0,
+                layout: LAYOUT_VERTICAL,
+                flush_len: 0,
+                pending_upload: false,
+                exact_flush: false,
+                encoder: None,
+                packet_index: 0,
+                limiter_enabled: false,
+                limiter: Limiter::new(),
+                cipher: None,
+                authenticator: None,
+                timestamped: false,
+                sample_rate: 0,
+                redundant: false,
+                previous_block: Default::default(),
+                show_stats: false,
+                stats: Stats::default(),
+            }),
+            captures: Mutex::new(CaptureState {
+                direct_captures: Default::default(),
+                direct_capture_pending: Default::default(),
+                mix_track: None,
+            }),
+            data: Mutex::new(DataState {
+                queue: DataQueue::default(),
+                draft_type: DATA_TYPE_SUBTITLE as i64,
+                draft_text: String::new(),
+            }),
+            logger: Logger::new("audio_renderer"),
+        })
+    }
+
+    /// 在 `source` 的 proc handler 中添加数据通道的 send_data，显示实时统计时刷新 `source` 的属性
+    ///
+    /// `source` 是视频源自己或者持有这个实例的滤镜
+    pub fn attach_source(self: &Arc<Self>, source: SourceRef) {
+        self.logger.set_source(source);
+        AUDIO_RENDERER_LIST.insert(self.clone());
+        unsafe { source.add_proc::<AudioRenderer>(SEND_DATA_PROC) };
+    }
+
+    /// 把一段数据加入数据通道的队列，在之后的包中发送
+    pub fn send_data(&self, data_type: i64, payload: &[u8]) {
+        let result = match u8::try_from(data_type) {
+            Ok(data_type) => self.data.lock().unwrap().queue.push(data_type, payload),
+            Err(_) => Err("数据类型必须是 1 ~ 255"),
+        };
+        if let Err(error) = result {
+            self.logger.warn(&format!("发送数据失败：{}", error));
+        }
+    }
+
+    /// 发送属性中输入的数据
+    pub fn send_draft(&self) {
+        let (draft_type, draft_text) = {
+            let data_state = self.data.lock().unwrap();
+            (data_state.draft_type, data_state.draft_text.clone())
+        };
+        if !draft_text.is_empty() {
+            self.send_data(draft_type, draft_text.as_bytes());
+        }
+    }
+
+    /// 应用设置，`encoder` 取代设置中的编码方式，Audio Renderer Overlay 滤镜的低对比度模式使用 [`ENCODER_LOW_CONTRAST`]
+    pub fn update_with_encoder(&self, settings: &ObsData, encoder: i64) {
+        let (region, preset, geometry) = settings_geometry(settings, encoder);
+        {
+            let mut data_state = self.data.lock().unwrap();
+            data_state.draft_type = settings.get_int("data_type");
+            data_state.draft_text = settings.get_string("data_text").to_string_lossy().into_owned();
+        }
+        if let Err(error) = geometry.check() {
+            // 属性中由 encoding_property_modified 显示同样的错误信息
+            self.logger.error(&format!("{}，继续使用之前的设置", geometry.describe_error(&error, preset)));
+            return;
+        }
+        let Geometry { width, height, cell_width, cell_height, layout, mut flush_len, .. } = geometry;
+        let target_latency = settings.get_int("target_latency").max(0) as u32;
+        let passphrase = settings.get_string("passphrase").to_string_lossy().into_owned();
+        let auth_secret = settings.get_string("auth_secret").to_string_lossy().into_owned();
+        let timestamped = settings.get_bool("embed_timestamps");
+        let redundant = settings.get_bool("redundancy");
+        let sample_rate = unsafe { audio_output_get_sample_rate(obs_get_audio()) };
+        // 按目标延迟计算缓冲长度，取整数帧，这样每隔相同的帧数输出相同数量的采样
+        let fps = video_fps().filter(|_| target_latency > 0);
+        if let Some((fps_num, fps_den)) = fps {
+            let capacity = geometry.max_flush_len();
+            let mut frames = target_latency_frames(target_latency, fps_num, fps_den);
+            flush_len = frames_sample_count(frames, sample_rate, fps_num, fps_den);
+            if flush_len > capacity {
+                // 编码区域放不下时退回到放得下的最多帧数，上面已经检查过至少放得下 1 帧
+                let max_frames = (1..frames).rev().find(|n| frames_sample_count(*n, sample_rate, fps_num, fps_den) <= capacity).unwrap_or(1);
+                self.logger.warn(&format!("目标延迟 {} 毫秒需要 {} 个采样，编码区域最多只能放下 {} 个采样", target_latency, flush_len, capacity));
+                frames = max_frames;
+                flush_len = frames_sample_count(frames, sample_rate, fps_num, fps_den);
+            }
+            self.logger.info(&format!("每 {} 帧输出 {} 个采样，延迟约 {} 毫秒", frames, flush_len, flush_len as u64 * 1000 / sample_rate.max(1) as u64));
+        }
+        let mix_track = if settings.get_int("capture_mode") == CAPTURE_MODE_MIX_TRACK {
+            Some((settings.get_int("mix_track") as usize).clamp(1, MAX_AUDIO_MIXES as usize) - 1)
+        } else {
+            None
+        };
+        {
+            let mut captures = self.captures.lock().unwrap();
+            if mix_track != captures.mix_track {
+                // 切换声音来源时，之前所有的回调和缓冲的数据都作废
+                for i in 0..MAX_AUDIO_SOURCE_COUNT {
+                    captures.direct_captures[i] = None;
+                    captures.direct_capture_pending[i] = false;
+                }
+                let mix_channel = mix_track.map(|_| Arc::new(SourceChannel::new(CString::default())));
+                {
+                    let mut audio_state = self.audio.lock().unwrap();
+                    for source_channel in audio_state.source_channels.iter().flatten() {
+                        SOURCE_CHANNEL_LIST.remove(Arc::as_ptr(source_channel));
+                    }
+                    audio_state.source_channels = Default::default();
+                    audio_state.source_overrun = Default::default();
+                    audio_state.source_active = Default::default();
+                    audio_state.source_channels[0] = mix_channel.clone();
+                    audio_state.reset();
+                    if mix_track.is_some() {
+                        audio_state.source_amplifier[0] = 1.0;
+                    }
+                }
+                captures.mix_track = mix_track;
+                if let (Some(mix_idx), Some(mix_channel)) = (mix_track, mix_channel) {
+                    captures.direct_captures[0] = Some(unsafe { attach_mix(mix_channel, mix_idx) });
+                }
+            }
+            if mix_track.is_none() {
+                for i in 0..MAX_AUDIO_SOURCE_COUNT {
+                    let source_uuid = settings.get_string(&format!("source{}", i));
+                    let source_amplifier = settings.get_double(&format!("source{}_amplifier", i)) as f32;
+                    let mut audio_state = self.audio.lock().unwrap();
+                    audio_state.source_amplifier[i] = source_amplifier;
+                    let old_uuid = audio_state.source_channels[i].as_ref().map(|v| v.uuid.clone()).unwrap_or_default();
+                    if source_uuid != old_uuid {
+                        if let Some(source_channel) = &audio_state.source_channels[i] {
+                            SOURCE_CHANNEL_LIST.remove(Arc::as_ptr(source_channel));
+                        }
+                        captures.direct_captures[i] = None;
+                        captures.direct_capture_pending[i] = !source_uuid.is_empty();
+                        audio_state.source_overrun[i] = 0;
+                        audio_state.source_active[i] = false;
+                        audio_state.source_channels[i] = if source_uuid.is_empty() {
+                            None
+                        } else {
+                            Some(Arc::new(SourceChannel::new(source_uuid)))
+                        };
+                    }
+                }
+            }
+        }
+        self.attach_direct_captures();
+        let limiter_enabled = settings.get_bool("limiter_enabled");
+        let limiter_ceiling = settings.get_double("limiter_ceiling") as f32;
+        let limiter_release = settings.get_int("limiter_release") as f32;
+        // 派生密钥比较慢，在持有锁之前完成；密码没有变化时继续使用原来的盐
+        let reuse_cipher = self.video.lock().unwrap().cipher.as_ref().is_some_and(|cipher| cipher.passphrase_matches(&passphrase));
+        let cipher = if passphrase.is_empty() || reuse_cipher {
+            None
+        } else {
+            match PayloadCipher::new(&passphrase) {
+                Ok(cipher) => Some(cipher),
+                Err(error) => {
+                    // 不能退回到不加密的输出
+                    self.logger.error(&format!("生成观看端密码的盐失败：{}", error));
+                    let _graphics = GraphicsGuard::enter();
+                    self.video.lock().unwrap().encoder = None;
+                    return;
+                }
+            }
+        };
+        // 渲染线程是先持有 graphics 再锁 video 的，这里也必须按照相同的顺序
+        let graphics = GraphicsGuard::enter();
+        let mut video_state = self.video.lock().unwrap();
+        video_state.x = region.x as _;
+        video_state.y = region.y as _;
+        video_state.width = width;
+        video_state.height = height;
+        video_state.cell_width = cell_width;
+        video_state.cell_height = cell_height;
+        video_state.layout = layout;
+        video_state.flush_len = flush_len;
+        video_state.exact_flush = fps.is_some();
+        video_state.limiter_enabled = limiter_enabled;
+        video_state.limiter.configure(limiter_ceiling, limiter_release, sample_rate);
+        if !reuse_cipher {
+            video_state.cipher = cipher;
+        }
+        video_state.authenticator = (!auth_secret.is_empty()).then(|| PayloadAuthenticator::new(&auth_secret));
+        video_state.timestamped = timestamped;
+        video_state.redundant = redundant;
+        video_state.previous_block = Default::default();
+        video_state.show_stats = settings.get_bool("show_stats");
+        video_state.sample_rate = sample_rate;
+        // 先释放旧的 texture 再创建新的
+        video_state.pending_upload = false;
+        video_state.encoder = None;
+        video_state.encoder = Encoder::new(&graphics, encoder, width, height, cell_width, cell_height, &self.logger);
+    }
+
+    /// 取出缓冲的声音，攒够 flush_len 时编码到 encoder 中，返回是否有新的数据需要上传
+    fn encode(&self, video_state: &mut VideoState) -> bool {
+        let encoder = match &mut video_state.encoder {
+            Some(encoder) => encoder,
+            None => return false,
+        };
+
+        let mut modified = false;
+        {
+            let mut audio_state = self.audio.lock().unwrap();
+            let audio_state = &mut *audio_state;
+            drain_source_channels(audio_state, &self.logger);
+            let base_sample_number = audio_state.base_sample_number;
+            // 忽略落后 3 * flush_len 的源
+            let max_source_sample_number = audio_state.source_sample_number.iter().copied().max().unwrap_or(0);
+            if max_source_sample_number.saturating_sub(base_sample_number) >= video_state.flush_len * 3 {
+                for i in 0..MAX_AUDIO_SOURCE_COUNT {
+                    if audio_state.source_sample_number[i] == base_sample_number {
+                        audio_state.source_sample_number[i] = 0;
+                        if audio_state.source_active[i] {
+                            audio_state.source_active[i] = false;
+                            self.logger.warn(&format!("声音源{} 停止提供数据，暂时忽略这个声音源", i + 1));
+                        }
+                    }
+                }
+            }
+            // 输出数据
+            let min_source_sample_number = audio_state.source_sample_number.iter().filter_map(|v| {
+                if *v >= base_sample_number {
+                    Some(*v)
+                } else {
+                    None
+                }
+            }).min().unwrap_or(0);
+            let mut sample_count = min_source_sample_number.saturating_sub(base_sample_number);
+            if sample_count >= video_state.flush_len {
+                if video_state.exact_flush {
+                    sample_count = video_state.flush_len;
+                }
+                let audio_buffer = &mut audio_state.audio_buffer;
+                // 输出之后仍然残留的采样过多，说明有的源领先于其他源，延迟在逐渐增大
+                // 此时多消耗一小部分采样，伸缩成 sample_count 个采样输出，平滑地把延迟降下来
+                // 多消耗的部分会让落后的源在下一次填充时从新的 base_sample_number 开始，相当于对齐位置前移，数据本身是连续的
+                let backlog = audio_buffer[0].len().saturating_sub(sample_count);
+                let extra = backlog.saturating_sub(video_state.flush_len).min(sample_count / DRIFT_CORRECTION_MAX_RATIO);
+                let consume_count = sample_count + extra;
+                // 限幅器会预读 consume_count 之后的数据，所以要在伸缩之前处理
+                if video_state.limiter_enabled {
+                    video_state.limiter.process(audio_buffer, consume_count);
+                    video_state.stats.limiter_gain_reduction_db = video_state.limiter.gain_reduction_db;
+                } else {
+                    video_state.stats.limiter_gain_reduction_db = 0.0;
+                }
+                let mut channel_0 = stretch_audio_buffer(&audio_buffer[0], consume_count, sample_count);
+                let mut channel_1 = stretch_audio_buffer(&audio_buffer[1], consume_count, sample_count);
+                // 按声道布局，一半格子是左声道，另一半是右声道，放不下的采样丢弃
+                // 低对比度模式头部中的采样数只有 16 位
+                let extra_header_count = extra_header_cell_count(video_state.cipher.is_some(), video_state.authenticator.is_some(), video_state.timestamped, video_state.redundant);
+                let channel_cell_count = (video_state.width / video_state.cell_width) * (video_state.height / video_state.cell_height) / 2;
+                let capacity = channel_cell_count.saturating_sub(encoder.header_cell_count() + extra_header_count).min(0xffff);
+                // 验证时采样之后还要放下结束标记和辅助位
+                let authenticated = video_state.authenticator.is_some();
+                let sample_capacity = if authenticated { auth::sample_capacity(capacity) } else { capacity };
+                video_state.stats.truncated_samples += channel_0.len().saturating_sub(sample_capacity);
+                channel_0.truncate(sample_capacity);
+                channel_1.truncate(sample_capacity);
+                // 冗余时先放上一个包的采样，观看端漏掉一个包时可以从下一个包中恢复，放不下时只保留上一个包末尾的部分
+                let previous_count = if video_state.redundant {
+                    let previous = std::mem::replace(&mut video_state.previous_block, [channel_0.clone(), channel_1.clone()]);
+                    let count = previous[0].len().min(sample_capacity - channel_0.len());
+                    channel_0.splice(0..0, previous[0][previous[0].len() - count..].iter().copied());
+                    channel_1.splice(0..0, previous[1][previous[1].len() - count..].iter().copied());
+                    count
+                } else {
+                    0
+                };
+                let max_0 = channel_0.iter().fold(0.00001f32, |acc, v| acc.max(v.abs()));
+                let max_1 = channel_1.iter().fold(0.00001f32, |acc, v| acc.max(v.abs()));
+                let amplifiers = [header_amplifier(1.0 / max_0), header_amplifier(1.0 / max_1)];
+                // 放大倍数至少是 1，超过满幅度的采样只能削波
+                video_state.stats.clipped_samples += [&channel_0, &channel_1].into_iter().zip(amplifiers).map(|(channel, amplifier)| {
+                    channel[previous_count..].iter().filter(|v| (*v * amplifier).abs() > 1.0).count()
+                }).sum::<usize>();
+                video_state.stats.amplifiers = amplifiers;
+                let frame_time = video_frame_time();
+                let audio_timestamp = sample_timestamp(&audio_state.timestamps, base_sample_number, video_state.sample_rate);
+                video_state.stats.latency = (!audio_state.timestamps.is_empty()).then(|| frame_time.saturating_sub(audio_timestamp));
+                video_state.stats.record_packet(Instant::now());
+                let packet = video_state.packet_index as u32;
+                if let Some(cipher) = &video_state.cipher {
+                    cipher.encrypt(&mut channel_0, amplifiers[0], packet, 0);
+                    cipher.encrypt(&mut channel_1, amplifiers[1], packet, 1);
+                }
+                // 采样之后空一个格子，验证时先放辅助位，剩下的格子用于数据通道
+                let payload_cell_count = |samples: usize| samples + 1 + if authenticated { auth::helper_cell_count(samples) } else { 0 };
+                let data_capacity = capacity.saturating_sub(payload_cell_count(channel_0.len()));
+                let max_data_capacity = capacity.saturating_sub(payload_cell_count(video_state.flush_len * (1 + video_state.redundant as usize)));
+                let mut data = {
+                    let mut data_state = self.data.lock().unwrap();
+                    let (data_0, dropped_0) = data_state.queue.take_bits(data_capacity, max_data_capacity);
+                    let (data_1, dropped_1) = data_state.queue.take_bits(data_capacity, max_data_capacity);
+                    if dropped_0 + dropped_1 > 0 {
+                        self.logger.log_limited(LOG_WARNING, "data_dropped", "数据太长，编码区域放不下，已丢弃");
+                    }
+                    [data_0, data_1]
+                };
+                // 先加密再计算标签，解码端先验证再解密
+                let tag = video_state.authenticator.as_ref().map(|authenticator| {
+                    let frames = [frame_bytes(&data[0]), frame_bytes(&data[1])];
+                    let (tag, helpers) = authenticator.authenticate(packet, previous_count, [&channel_0, &channel_1], amplifiers, [&frames[0], &frames[1]]);
+                    for (data, helpers) in data.iter_mut().zip(helpers) {
+                        data.splice(0..0, helpers);
+                    }
+                    tag
+                });
+                let mut extra_header = Vec::with_capacity(extra_header_count);
+                if let Some(cipher) = &video_state.cipher {
+                    push_header_bits(&mut extra_header, cipher.salt());
+                }
+                if video_state.cipher.is_some() || authenticated {
+                    push_header_bits(&mut extra_header, packet);
+                }
+                if let Some(tag) = tag {
+                    push_header_bits(&mut extra_header, tag);
+                }
+                // 观看端用两者之差估计声音比画面晚多少，冗余时声音的时间戳不包括上一个包的部分
+                if video_state.timestamped {
+                    for timestamp in [frame_time, audio_timestamp] {
+                        push_header_bits(&mut extra_header, timestamp as u32);
+                        push_header_bits(&mut extra_header, (timestamp >> 32) as u32);
+                    }
+                }
+                if video_state.redundant {
+                    push_header_bits(&mut extra_header, previous_count as u32);
+                }
+                match encoder {
+                    Encoder::Cpu { texture_buffer, .. } => {
+                        fill_texture_buffer(texture_buffer, channel_0.into_iter(), video_state.layout, 0, video_state.width, video_state.cell_width, video_state.cell_height, packet, amplifiers[0], &extra_header, &data[0]);
+                        fill_texture_buffer(texture_buffer, channel_1.into_iter(), video_state.layout, 1, video_state.width, video_state.cell_width, video_state.cell_height, packet, amplifiers[1], &extra_header, &data[1]);
+                    }
+                    Encoder::Gpu { sample_buffer, sample_count, extra_header_count, data_cell_count, amplifier, packet_index, .. } => {
+                        let row_len = sample_buffer.len() / 2;
+                        let header: Vec<f32> = extra_header.iter().map(|bit| *bit as u32 as f32).collect();
+                        for ((row, channel), data) in sample_buffer.chunks_exact_mut(row_len).zip([&channel_0, &channel_1]).zip(&data) {
+                            row.fill(0.0);
+                            row[..header.len()].copy_from_slice(&header);
+                            row[header.len()..header.len() + channel.len()].copy_from_slice(channel);
+                            let data_start = header.len() + channel.len() + 1;
+                            for (cell, bit) in row[data_start..].iter_mut().zip(data) {
+                                *cell = *bit as u32 as f32;
+                            }
+                        }
+                        *sample_count = channel_0.len();
+                        *extra_header_count = header.len();
+                        *data_cell_count = data[0].len().max(data[1].len());
+                        *amplifier = amplifiers;
+                        *packet_index = packet;
+                    }
+                }
+                truncate_front(&mut audio_buffer[0], consume_count);
+                truncate_front(&mut audio_buffer[1], consume_count);
+                audio_state.base_sample_number += consume_count;
+                // 只保留推算之后的采样需要的时间戳
+                while audio_state.timestamps.get(1).is_some_and(|(n, _)| *n <= audio_state.base_sample_number) {
+                    audio_state.timestamps.pop_front();
+                }
+                video_state.packet_index += 1;
+                modified = true;
+            }
+            // 缓冲长度过大时，清除 buffer，防止延迟过高
+            // 正常情况下上面的平滑追赶就能降低延迟，这里只在追赶不及时（例如画面长时间没有渲染）兜底
+            if audio_state.audio_buffer[0].len() >= video_state.flush_len * HARD_RESET_FLUSH_LEN_MULTIPLIER {
+                self.logger.log_limited(LOG_ERROR, "buffer_reset", "缓冲的声音太长，已清空");
+                audio_state.reset();
+                video_state.stats.resets += 1;
+            }
+            video_state.stats.source_fill = (0..MAX_AUDIO_SOURCE_COUNT).map(|i| {
+                audio_state.source_channels[i].as_ref().map(|_| audio_state.source_sample_number[i].saturating_sub(audio_state.base_sample_number))
+            }).collect();
+            video_state.stats.overrun_samples = audio_state.source_overrun.iter().sum();
+            // 此处释放 audio 的 Mutex
+        }
+        modified
+    }
+
+    /// 低对比度模式，用编码器的 effect 把编码叠加在所在的源的画面上
+    ///
+    /// `region` 是编码区域在所在的源中的位置，`draw` 用设置好参数的 effect 画出所在的源，effect 的 `image` 参数由 OBS 设置
+    ///
+    /// 设置无效没有调用 `draw` 时返回 false
+    pub fn render_low_contrast(&self, graphics: &GraphicsGuard, region: &Region, parent_width: u32, parent_height: u32, strength: f32, draw: impl FnOnce(&Effect)) -> bool {
+        let mut video_state = self.video.lock().unwrap();
+        let modified = std::mem::take(&mut video_state.pending_upload);
+        if let Some(effect) = prepare_gpu_encoder(graphics, &mut video_state, modified) {
+            effect.set_float("region_x", region.x as f32);
+            effect.set_float("region_y", region.y as f32);
+            effect.set_float("parent_width", parent_width as f32);
+            effect.set_float("parent_height", parent_height as f32);
+            effect.set_float("strength", strength);
+            draw(effect);
+            return true;
+        }
+        false
+    }
+
+    /// 当前生效的编码区域
+    pub fn region(&self) -> Region {
+        let video_state = self.video.lock().unwrap();
+        Region {
+            x: video_state.x as _,
+            y: video_state.y as _,
+            width: video_state.width as _,
+            height: video_state.height as _,
+            cell_width: video_state.cell_width as _,
+            cell_height: video_state.cell_height as _,
+            low_contrast: matches!(video_state.encoder, Some(Encoder::Gpu { low_contrast: true, .. })),
+            encrypted: video_state.cipher.is_some(),
+            authenticated: video_state.authenticator.is_some(),
+            timestamped: video_state.timestamped,
+            redundant: video_state.redundant,
+        }
+    }
+
+    /// 为还没有注册回调的声音源注册回调
+    fn attach_direct_captures(&self) {
+        let mut captures = self.captures.lock().unwrap();
+        for i in 0..MAX_AUDIO_SOURCE_COUNT {
+            if !captures.direct_capture_pending[i] {
+                continue;
+            }
+            let source_channel = self.audio.lock().unwrap().source_channels[i].clone();
+            let source_channel = match source_channel {
+                Some(source_channel) => source_channel,
+                None => {
+                    captures.direct_capture_pending[i] = false;
+                    continue;
+                }
+            };
+            match unsafe { attach(source_channel.clone()) } {
+                AttachResult::Attached(direct_capture) => {
+                    captures.direct_captures[i] = Some(direct_capture);
+                    captures.direct_capture_pending[i] = false;
+                }
+                AttachResult::Filter => {
+                    // 交给 Audio Capture 滤镜在 dispatch_audio 中写入
+                    SOURCE_CHANNEL_LIST.insert(source_channel);
+                    captures.direct_capture_pending[i] = false;
+                }
+                AttachResult::NotFound => {}
+            }
+        }
     }
-    obs_data_set_default_int(settings, "width\0".as_ptr().cast(), 32);
-    obs_data_set_default_int(settings, "height\0".as_ptr().cast(), 1072);
-    obs_data_set_default_int(settings, "cell_width\0".as_ptr().cast(), 2);
-    obs_data_set_default_int(settings, "cell_height\0".as_ptr().cast(), 2);
-    obs_data_set_default_int(settings, "flush_len\0".as_ptr().cast(), 2400);
 }
 
-unsafe extern "C" fn get_properties(_data: *mut ::std::os::raw::c_void) -> *mut obs_properties_t {
-    let props = obs_properties_create();
-    for i in 0..MAX_AUDIO_SOURCE_COUNT {
-        let list = obs_properties_add_list(props, format!("source{}\0", i).as_ptr().cast(), format!("声音源{}（必须为 Audio Capture 滤镜）\0", i + 1).as_ptr().cast(), obs_combo_type_OBS_COMBO_TYPE_LIST, obs_combo_format_OBS_COMBO_FORMAT_STRING);
-        obs_property_list_add_string(list, "\0".as_ptr().cast(), "\0".as_ptr().cast());
-        for audio_capture in &AUDIO_CAPTURE_LIST {
-            let uuid = obs_source_get_uuid((**audio_capture).source);
-            let name = obs_source_get_name((**audio_capture).source);
-            obs_property_list_add_string(list, name, uuid);
-        }
-        let _ = obs_properties_add_float_slider(props, format!("source{}_amplifier\0", i).as_ptr().cast(), format!("声音源{}放大倍数\0", i + 1).as_ptr().cast(), 0.01, 10.00, 0.01);
-    }
-    let _ = obs_properties_add_int(props, "width\0".as_ptr().cast(), "编码区域宽度（单位：像素）（推荐为 32）\0".as_ptr().cast(), 1, 7680, 1);
-    let _ = obs_properties_add_int(props, "height\0".as_ptr().cast(), "编码区域高度（单位：像素）（推荐为 1072）\0".as_ptr().cast(), 2, 4320, 2);
-    let _ = obs_properties_add_int(props, "cell_width\0".as_ptr().cast(), "每个数据编码的格子宽度（单位：像素）（推荐为 2）\0".as_ptr().cast(), 1, 16, 1);
-    let _ = obs_properties_add_int(props, "cell_height\0".as_ptr().cast(), "每个数据编码的格子高度（单位：像素）（推荐为 2）\0".as_ptr().cast(), 1, 16, 1);
-    let _ = obs_properties_add_int(props, "flush_len\0".as_ptr().cast(), "最少缓冲长度（单位：采样）（推荐为 2400）\0".as_ptr().cast(), 480, 9600, 1);
-    let _ = obs_properties_add_text(props, "help_1\0".as_ptr().cast(), "缓冲长度说明：如果按推荐设置的话，每个声道占用一半高度，每个声道是 32 * 1072 / 2 的画面区域，每个音频采样编码成 2x2 的格子，因此最多可以编码 (32 * 1072 / 2) / (2 * 2) = 4288 个采样。编码 2400 个采样对应 2400 / 48000 = 0.05s，因此编码区域大约每 3 帧画面会更新一次。同时，音频会比画面落后 0.05s。需要注意，这里并不一定恰好是 2400 个采样，如果声音源每批提交 512 采样的数据，那么声音源提交 5 批数据之后，画面上会显示 2560 个采样，这样的话画面会每 3 ~ 4 帧更新一次。\0".as_ptr().cast(), obs_text_type_OBS_TEXT_INFO);
-    let _ = obs_properties_add_text(props, "help_2\0".as_ptr().cast(), "编码原理说明：在目标声音源上添加 Audio Capture 滤镜，这个滤镜负责获取声音数据。然后添加一个 Audio Renderer 的视频源，这个视频源负责将 Audio Capture 获取到的声音数据渲染成视频形式。它将音频采样信息转换成一系列明暗变化的点的图像信息。上半部分是左声道，下半部分是右声道。每个音频采样数据是 -1.0 ~ 1.0 的浮点数，他会被编码为 16 ~ 255 的灰度值，这样编码声音的位深大概是 8bit。如果每个格子为 2 x 2 = 4 个像素，那么位深可以增加到 10bit。由于视频压缩是有损的，实际上会损失一些精度，不过这样的音频听感基本上足够了。\0".as_ptr().cast(), obs_text_type_OBS_TEXT_INFO);
-    let _ = obs_properties_add_text(props, "help_3\0".as_ptr().cast(), "多个声音源混合问题：由于声音混合的实现比较简单，如果声音源没有连续提交声音数据的话，会产生杂音。在声音源停止提供数据时会因为等待数据而卡住，并且之后因为追赶卡住的进度会故意丢帧，因此产生杂音。不过通常来自游戏的桌面声音、来自麦克风的声音、媒体源不会有这个问题。\0".as_ptr().cast(), obs_text_type_OBS_TEXT_INFO);
-    let _ = obs_properties_add_text(props, "LICENSE\0".as_ptr().cast(), "本插件基于 GPLv2 开源。你可以在 https://github.com/ganlvtech/obs-audio-renderer 免费下载。\0".as_ptr().cast(), obs_text_type_OBS_TEXT_INFO);
-    props
-}
-
-unsafe extern "C" fn filter_update(data: *mut ::std::os::raw::c_void, settings: *mut obs_data_t) {
-    let audio_renderer = &mut *(data as *mut AudioRenderer);
-    let width = obs_data_get_int(settings, "width\0".as_ptr().cast()) as usize;
-    let height = obs_data_get_int(settings, "height\0".as_ptr().cast()) as usize;
-    let cell_width = obs_data_get_int(settings, "cell_width\0".as_ptr().cast()) as usize;
-    let cell_height = obs_data_get_int(settings, "cell_height\0".as_ptr().cast()) as usize;
-    let flush_len = obs_data_get_int(settings, "flush_len\0".as_ptr().cast()) as usize;
-    if width == 0 {
-        return;
-    }
-    if height == 0 {
-        return;
-    }
-    if cell_width == 0 {
-        return;
-    }
-    if cell_height == 0 {
-        return;
-    }
-    if height % 2 != 0 { // 必须是 2 的倍数
-        blog(LOG_ERROR, format!("[audio_renderer] 编码区域高度必须是 2 的倍数\0").as_ptr().cast());
-        return;
-    }
-    if width % cell_width != 0 { // 必须整除
-        blog(LOG_ERROR, format!("[audio_renderer] 编码区域宽度必须整除格子宽度\0").as_ptr().cast());
-        return;
-    }
-    if (height / 2) % cell_height != 0 { // 必须整除
-        blog(LOG_ERROR, format!("[audio_renderer] 编码区域高度的一半必须整除格子高度\0").as_ptr().cast());
-        return;
-    }
-    if (width * height / 2) / (cell_width * cell_height) < flush_len { // 编码区域不够大
-        blog(LOG_ERROR, format!("[audio_renderer] 编码区域大小必须大于缓冲长度\0").as_ptr().cast());
-        return;
+impl Source for AudioRenderer {
+    const ID: &'static str = "audio_renderer\0";
+    const NAME: &'static str = "Audio Renderer\0";
+    const TYPE: obs_source_type = obs_source_type_OBS_SOURCE_TYPE_INPUT;
+    // 两种编码方式使用不同的 effect，所以自己调用 gs_effect_loop
+    const OUTPUT_FLAGS: u32 = OBS_SOURCE_VIDEO | OBS_SOURCE_CUSTOM_DRAW;
+
+    fn create(settings: &ObsData, source: SourceRef) -> Arc<Self> {
+        let audio_renderer = AudioRenderer::new();
+        audio_renderer.update(settings);
+        audio_renderer.attach_source(source);
+        audio_renderer
     }
+
+    fn destroy(&self) {
+        // 注销所有回调，之后音频线程不会再写入这个 AudioRenderer 的声音源通道
+        for direct_capture in &mut self.captures.lock().unwrap().direct_captures {
+            *direct_capture = None;
+        }
+        for source_channel in self.audio.lock().unwrap().source_channels.iter().flatten() {
+            SOURCE_CHANNEL_LIST.remove(Arc::as_ptr(source_channel));
+        }
+        // 之后通过 proc handler 发送的数据会被忽略
+        AUDIO_RENDERER_LIST.remove(self);
+    }
+
+    fn width(&self) -> u32 {
+        self.video.lock().unwrap().width as _
+    }
+
+    fn height(&self) -> u32 {
+        self.video.lock().unwrap().height as _
+    }
+
+    fn defaults(settings: &ObsData) {
+        settings.set_default_int("capture_mode", CAPTURE_MODE_SOURCES);
+        settings.set_default_int("mix_track", 1);
+        for i in 0..MAX_AUDIO_SOURCE_COUNT {
+            settings.set_default_double(&format!("source{}_amplifier", i), 1.0);
+        }
+        settings.set_default_int("placement", PLACEMENT_CUSTOM);
+        settings.set_default_int("position_x", 0);
+        settings.set_default_int("position_y", 0);
+        settings.set_default_int("width", 32);
+        settings.set_default_int("height", 1072);
+        settings.set_default_int("cell_width", 2);
+        settings.set_default_int("cell_height", 2);
+        settings.set_default_int("layout", LAYOUT_VERTICAL);
+        settings.set_default_int("flush_len", 2400);
+        settings.set_default_int("target_latency", 0);
+        settings.set_default_int("encoder", ENCODER_CPU);
+        settings.set_default_bool("limiter_enabled", true);
+        settings.set_default_double("limiter_ceiling", -1.0);
+        settings.set_default_int("limiter_release", 100);
+        settings.set_default_int("data_type", DATA_TYPE_SUBTITLE as i64);
+        settings.set_default_bool("embed_timestamps", false);
+        settings.set_default_bool("redundancy", false);
+        settings.set_default_bool("show_stats", false);
+    }
+
+    fn properties(this: Option<&Self>) -> Properties {
+        let mut props = Properties::new();
+        add_capture_properties(&mut props);
+        add_placement_properties::<Self>(&mut props);
+        props.add_int("position_x", "编码区域在画面中的横坐标（仅在自定义位置时有效，需要和 OBS 中的变换一致）", 0, 7680, 1);
+        props.add_int("position_y", "编码区域在画面中的纵坐标（仅在自定义位置时有效，需要和 OBS 中的变换一致）", 0, 4320, 1);
+        add_encoding_properties::<Self>(&mut props);
+        add_data_properties::<Self>(&mut props);
+        add_viewer_config_properties::<Self>(&mut props, this.map(|this| this.region()));
+        add_stats_properties(&mut props, this);
+        add_help_properties(&mut props);
+        props
+    }
+
+    fn update(&self, settings: &ObsData) {
+        self.update_with_encoder(settings, settings.get_int("encoder"));
+    }
+
+    fn button_clicked(&self, name: &str) -> bool {
+        match name {
+            "copy_viewer_config" => copy_viewer_config(&self.region(), &self.logger),
+            "send_data" => self.send_draft(),
+            _ => {}
+        }
+        // 刷新显示的配置字符串
+        true
+    }
+
+    fn property_modified(props: &mut Properties, name: &str, settings: &ObsData) -> bool {
+        encoding_property_modified(props, name, settings, settings.get_int("encoder"))
+    }
+
+    /// 每帧最多产生一个包，预览、投影和多视图多次渲染时画面相同，包序号也不会多跳
+    fn tick(&self, _seconds: f32) {
+        self.attach_direct_captures();
+        let refresh = {
+            let mut video_state = self.video.lock().unwrap();
+            if self.encode(&mut video_state) {
+                video_state.pending_upload = true;
+            }
+            video_state.show_stats && video_state.stats.refresh_due(Instant::now())
+        };
+        if let (true, Some(owner)) = (refresh, self.logger.source()) {
+            owner.update_properties();
+        }
+    }
+
+    fn render(&self, _effect: *mut gs_effect_t) {
+        let mut video_state = self.video.lock().unwrap();
+        let modified = std::mem::take(&mut video_state.pending_upload);
+
+        let graphics = GraphicsGuard::enter();
+        match &mut video_state.encoder {
+            Some(Encoder::Cpu { texture_buffer, texture }) => {
+                // 更新 texture 数据内容
+                if modified {
+                    texture.set_image(&graphics, texture_buffer);
+                }
+                // libobs/data/default.effect 只需要设置 texture2d image 参数
+                let effect = Effect::base_default();
+                effect.set_texture("image", texture);
+                effect.draw_sprite(&graphics, Some(texture), 0, 0);
+            }
+            // 低对比度模式只由滤镜调用 render_low_contrast
+            Some(Encoder::Gpu { low_contrast: false, .. }) => {
+                let (width, height) = (video_state.width as u32, video_state.height as u32);
+                if let Some(effect) = prepare_gpu_encoder(&graphics, &mut video_state, modified) {
+                    effect.draw_sprite(&graphics, None, width, height);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 声音来源相关的属性，视频源和滤镜共用
+pub fn add_capture_properties(props: &mut Properties) {
+    let capture_mode = props.add_int_list("capture_mode", "声音来源");
+    capture_mode.list_add_int("混合下面选择的声音源", CAPTURE_MODE_SOURCES);
+    capture_mode.list_add_int("OBS 输出音轨", CAPTURE_MODE_MIX_TRACK);
+    props.add_int("mix_track", "输出音轨（仅在声音来源为 OBS 输出音轨时有效）", 1, MAX_AUDIO_MIXES as _, 1);
     for i in 0..MAX_AUDIO_SOURCE_COUNT {
-        audio_renderer.source_uuids[i] = CString::from(CStr::from_ptr(obs_data_get_string(settings, format!("source{}\0", i).as_ptr().cast())));
-        audio_renderer.source_amplifier[i] = obs_data_get_double(settings, format!("source{}_amplifier\0", i).as_ptr().cast()) as f32;
-    }
-    audio_renderer.width = width;
-    audio_renderer.height = height;
-    audio_renderer.cell_width = cell_width;
-    audio_renderer.cell_height = cell_height;
-    audio_renderer.flush_len = flush_len;
-    let texture_buffer = vec![0u8; width * height * 4];
-    obs_enter_graphics();
-    if !audio_renderer.texture.is_null() {
-        gs_texture_destroy(audio_renderer.texture);
-    }
-    audio_renderer.texture_buffer = texture_buffer;
-    // GS_DYNAMIC 表示此 texture 会动态更新
-    audio_renderer.texture = gs_texture_create(width as _, height as _, gs_color_format_GS_BGRA, 1, null_mut(), GS_DYNAMIC);
-    obs_leave_graphics();
-}
-
-unsafe extern "C" fn video_render(data: *mut ::std::os::raw::c_void, effect: *mut gs_effect_t) {
-    let audio_renderer = &mut *(data as *mut AudioRenderer);
-    if audio_renderer.texture.is_null() {
-        return;
-    }
-
-    let mut modified = false;
-    {
-        let mut audio_buffer = audio_renderer.audio_buffer.lock().unwrap();
-        let base_sample_number = audio_renderer.base_sample_number;
-        // 忽略落后 3 * flush_len 的源
-        let max_source_sample_number = audio_renderer.source_sample_number.iter().map(|v| *v).max().unwrap_or(0);
-        if max_source_sample_number - base_sample_number >= audio_renderer.flush_len * 3 {
-            blog(LOG_ERROR, "[audio_renderer] some audio source not provide data\0".as_ptr().cast());
-            for x in &mut audio_renderer.source_sample_number {
-                if *x == base_sample_number {
-                    *x = 0;
+        let list = props.add_string_list(&format!("source{}", i), &format!("声音源{}", i + 1));
+        list.list_add_string("", &CString::default());
+        list.list_add_string("最终混音（音轨 1）", CStr::from_bytes_with_nul(FINAL_MIX.as_bytes()).unwrap());
+        unsafe { obs_enum_sources(Some(add_audio_source_to_list), list.as_ptr() as _) };
+        AUDIO_CAPTURE_LIST.for_each(|audio_capture| {
+            list.list_add_string(&format!("{}（Audio Capture 滤镜）", audio_capture.source.name().to_string_lossy()), audio_capture.source.uuid());
+        });
+        props.add_float_slider(&format!("source{}_amplifier", i), &format!("声音源{}放大倍数", i + 1), 0.01, 10.00, 0.01);
+    }
+}
+
+/// 编码区域位置的预设，修改时由 `S` 的 [`Source::property_modified`] 调用 [`encoding_property_modified`]
+pub fn add_placement_properties<S: Source>(props: &mut Properties) {
+    let placement = props.add_int_list("placement", "编码区域位置（选择预设时忽略自定义的位置和大小）");
+    placement.list_add_int("自定义", PLACEMENT_CUSTOM);
+    placement.list_add_int("左侧竖条（宽 32 像素）", PLACEMENT_LEFT_STRIP);
+    placement.list_add_int("顶部横条（高 16 像素）", PLACEMENT_TOP_STRIP);
+    placement.list_add_int("底部横条（高 16 像素）", PLACEMENT_BOTTOM_STRIP);
+    placement.set_modified::<S>();
+}
+
+/// 编码区域大小、编码方式和限幅器的属性，视频源和滤镜共用
+///
+/// 影响编码区域划分和容量的属性修改时由 `S` 的 [`Source::property_modified`] 调用 [`encoding_property_modified`]，设置无效时在 `validation_error` 中显示错误
+pub fn add_encoding_properties<S: Source>(props: &mut Properties) {
+    let modified = [
+        props.add_int("width", "编码区域宽度（单位：像素）（推荐为 32）", 1, MAX_WIDTH as _, 1),
+        props.add_int("height", "编码区域高度（单位：像素）（推荐为 1072）", 2, MAX_HEIGHT as _, 2),
+        props.add_int("cell_width", "每个数据编码的格子宽度（单位：像素）（推荐为 2）", 1, 16, 1),
+        props.add_int("cell_height", "每个数据编码的格子高度（单位：像素）（推荐为 2）", 1, 16, 1),
+    ];
+    let layout = props.add_int_list("layout", "声道布局");
+    layout.list_add_int("上下分布（上半部分左声道，下半部分右声道）", LAYOUT_VERTICAL);
+    layout.list_add_int("左右分布（左半部分左声道，右半部分右声道，适合顶部、底部横条）", LAYOUT_HORIZONTAL);
+    layout.list_add_int("逐行交错（偶数行格子左声道，奇数行格子右声道）", LAYOUT_INTERLEAVED);
+    let flush_len = props.add_int("flush_len", "最少缓冲长度（单位：采样）（推荐为 2400，不能超过编码区域的容量）", MIN_FLUSH_LEN as _, MAX_FLUSH_LEN as _, 1);
+    let target_latency = props.add_int("target_latency", "目标延迟（单位：毫秒）（0 表示使用最少缓冲长度，否则按帧率自动计算缓冲长度）", 0, 200, 1);
+    let validation_error = props.add_info("validation_error", "");
+    validation_error.set_info_error();
+    validation_error.set_visible(false);
+    let encoder = props.add_int_list("encoder", "编码方式");
+    encoder.list_add_int("CPU（兼容性最好）", ENCODER_CPU);
+    encoder.list_add_int("GPU shader（编码区域很大时 CPU 占用更低）", ENCODER_GPU);
+    let passphrase = props.add_password("passphrase", "观看端密码（留空表示不加密，观看端需要输入相同的密码）");
+    let auth_secret = props.add_password("auth_secret", "验证密钥（留空表示不验证，观看端可以用相同的密钥确认声音来自这个源）");
+    let embed_timestamps = props.add_bool("embed_timestamps", "在头部写入时间戳（观看端可以显示声音比画面晚多少）");
+    let redundancy = props.add_bool("redundancy", "冗余（每个包再带上上一个包的声音，观看端漏掉一帧时可以恢复，编码区域要能放下两倍的缓冲长度）");
+    for property in modified.iter().chain(&[layout, flush_len, target_latency, encoder, passphrase, auth_secret, embed_timestamps, redundancy]) {
+        property.set_modified::<S>();
+    }
+    props.add_bool("limiter_enabled", "启用限幅器（防止多个声音源混合放大后削波）");
+    props.add_float_slider("limiter_ceiling", "限幅器峰值上限（单位：dB）（推荐为 -1.0）", -12.0, 0.0, 0.1);
+    props.add_int("limiter_release", "限幅器释放时间（单位：毫秒）（推荐为 100）", 10, 1000, 1);
+}
+
+/// 编码区域相关的属性被修改，视频源和滤镜共用，`encoder` 同 [`AudioRenderer::update_with_encoder`]
+///
+/// 宽度、高度的步长跟随格子大小和布局；修改格子大小、布局或者编码方式之后，如果原来的宽度和高度不再有效，改成最接近的有效设置；
+/// 最少缓冲长度不能超过编码区域的容量。调整之后仍然无效时在属性中显示错误信息和建议的设置，update 会继续使用之前的设置
+pub fn encoding_property_modified(props: &mut Properties, name: &str, settings: &ObsData, encoder: i64) -> bool {
+    let (_, preset, mut geometry) = settings_geometry(settings, encoder);
+    if !preset && matches!(name, "cell_width" | "cell_height" | "layout" | "encoder" | "overlay_mode") && geometry.check().is_err() {
+        if let Some(nearest) = geometry.nearest_valid() {
+            settings.set_int("width", nearest.width as _);
+            settings.set_int("height", nearest.height as _);
+            settings.set_int("cell_width", nearest.cell_width as _);
+            settings.set_int("cell_height", nearest.cell_height as _);
+            geometry = nearest;
+        }
+    }
+    let (width_step, height_step) = geometry.size_steps();
+    if let Some(width) = props.get("width") {
+        width.int_set_limits(width_step as _, MAX_WIDTH as _, width_step as _);
+    }
+    if let Some(height) = props.get("height") {
+        height.int_set_limits(height_step as _, MAX_HEIGHT as _, height_step as _);
+    }
+    let max_flush_len = geometry.max_flush_len().clamp(MIN_FLUSH_LEN, MAX_FLUSH_LEN);
+    if let Some(flush_len) = props.get("flush_len") {
+        flush_len.int_set_limits(MIN_FLUSH_LEN as _, max_flush_len as _, 1);
+    }
+    // 设置了目标延迟时不使用最少缓冲长度
+    if settings.get_int("target_latency") <= 0 && geometry.flush_len > max_flush_len {
+        let clamped = Geometry { flush_len: max_flush_len, ..geometry };
+        if clamped.check().is_ok() {
+            settings.set_int("flush_len", max_flush_len as _);
+            geometry = clamped;
+        }
+    }
+    if let Some(validation_error) = props.get("validation_error") {
+        match geometry.check() {
+            Ok(()) => validation_error.set_visible(false),
+            Err(error) => {
+                validation_error.set_description(&geometry.describe_error(&error, preset));
+                validation_error.set_visible(true);
+            }
+        }
+    }
+    true
+}
+
+/// 数据通道的属性，按钮的点击由 `S` 的 [`Source::button_clicked`] 转发给 [`AudioRenderer::send_draft`]
+pub fn add_data_properties<S: Source>(props: &mut Properties) {
+    let data_type = props.add_int_list("data_type", "数据通道的数据类型");
+    data_type.list_add_int("字幕", DATA_TYPE_SUBTITLE as i64);
+    data_type.list_add_int("章节标记", DATA_TYPE_MARKER as i64);
+    data_type.list_add_int("JSON", DATA_TYPE_JSON as i64);
+    props.add_text("data_text", "数据通道的文本（也可以由脚本通过 proc handler 的 send_data 发送）");
+    props.add_button::<S>("send_data", "发送数据");
+}
+
+/// 显示观看端配置字符串并添加复制按钮，按钮的点击由 `S` 的 [`Source::button_clicked`] 处理
+pub fn add_viewer_config_properties<S: Source>(props: &mut Properties, region: Option<Region>) {
+    if let Some(region) = region {
+        props.add_info("viewer_config", &format!("观看端配置字符串：{}（在解码脚本的“自定义参数解码音频”中输入）", region.viewer_config()));
+    }
+    props.add_button::<S>("copy_viewer_config", "复制观看端配置字符串");
+}
+
+/// 实时统计的开关，打开时显示 `renderer` 的统计，每秒刷新一次属性
+pub fn add_stats_properties(props: &mut Properties, renderer: Option<&AudioRenderer>) {
+    props.add_bool("show_stats", "显示实时统计（每秒刷新一次属性，输入文字时可以先关闭）");
+    if let Some(renderer) = renderer {
+        let video_state = renderer.video.lock().unwrap();
+        if video_state.show_stats {
+            let (warnings, errors) = renderer.logger.counts();
+            let stats = video_state.stats.describe(Instant::now(), video_state.sample_rate);
+            props.add_info("stats", &format!("{}\n日志：警告 {} 条，错误 {} 条", stats, warnings, errors));
+        }
+    }
+}
+
+/// 说明文字，视频源和滤镜共用
+pub fn add_help_properties(props: &mut Properties) {
+    props.add_info("help_1", "缓冲长度说明：如果按推荐设置的话，每个声道占用一半高度，每个声道是 32 * 1072 / 2 的画面区域，每个音频采样编码成 2x2 的格子，因此最多可以编码 (32 * 1072 / 2) / (2 * 2) = 4288 个采样。编码 2400 个采样对应 2400 / 48000 = 0.05s，因此编码区域大约每 3 帧画面会更新一次。同时，音频会比画面落后 0.05s。需要注意，这里并不一定恰好是 2400 个采样，如果声音源每批提交 512 采样的数据，那么声音源提交 5 批数据之后，画面上会显示 2560 个采样，这样的话画面会每 3 ~ 4 帧更新一次。设置了目标延迟时，缓冲长度按 OBS 的帧率取整数帧，例如 60fps 下 50 毫秒是 3 帧 2400 个采样，每个包正好 2400 个采样，多出来的留给下一个包，画面每 3 帧更新一次。");
+    props.add_info("help_2", "编码原理说明：Audio Renderer 视频源可以直接选择任意带声音的源或者最终混音作为声音源，也兼容旧版的用法，即在目标声音源上添加 Audio Capture 滤镜，然后选择这个滤镜。Audio Renderer 负责将获取到的声音数据渲染成视频形式。它将音频采样信息转换成一系列明暗变化的点的图像信息。默认上半部分是左声道，下半部分是右声道，也可以选择左右分布或者逐行交错，布局会写在头部，解码脚本自动识别。每个音频采样数据是 -1.0 ~ 1.0 的浮点数，他会被编码为 16 ~ 255 的灰度值，这样编码声音的位深大概是 8bit。如果每个格子为 2 x 2 = 4 个像素，那么位深可以增加到 10bit。由于视频压缩是有损的，实际上会损失一些精度，不过这样的音频听感基本上足够了。");
+    props.add_info("help_3", "多个声音源混合问题：由于声音混合的实现比较简单，如果声音源没有连续提交声音数据的话，会产生杂音。在声音源停止提供数据时会因为等待数据而卡住，之后会通过轻微加快播放速度（最多 2%）的方式平滑地追赶进度，只有积压过多时才会清空缓冲并产生杂音。不过通常来自游戏的桌面声音、来自麦克风的声音、媒体源不会有这个问题。");
+    props.add_info("LICENSE", "本插件基于 GPLv2 开源。你可以在 https://github.com/ganlvtech/obs-audio-renderer 免费下载。");
+}
+
+/// 把观看端配置字符串复制到剪贴板，结果通过 `logger` 写入日志
+pub fn copy_viewer_config(region: &Region, logger: &Logger) {
+    let config = region.viewer_config();
+    match clipboard::set_text(&config) {
+        Ok(()) => logger.info(&format!("已复制观看端配置字符串 {}", config)),
+        Err(e) => logger.warn(&format!("复制观看端配置字符串 {} 失败：{}", config, e)),
+    }
+}
+
+impl Proc for AudioRenderer {
+    fn instances() -> &'static Registry<Self> {
+        &AUDIO_RENDERER_LIST
+    }
+
+    fn owner(&self) -> Option<SourceRef> {
+        self.logger.source()
+    }
+
+    /// [`SEND_DATA_PROC`]
+    fn call(&self, calldata: &CallData) {
+        match (calldata.get_int("type"), calldata.get_string("data")) {
+            (Some(data_type), Some(data)) => self.send_data(data_type, data.as_bytes()),
+            _ => self.logger.warn("send_data 缺少 type 或 data 参数"),
+        }
+    }
+}
+
+/// 将所有带声音输出的源添加到声音源列表中
+unsafe extern "C" fn add_audio_source_to_list(param: *mut ::std::os::raw::c_void, source: *mut obs_source_t) -> bool {
+    let source = SourceRef::from_raw(source);
+    if source.output_flags() & OBS_SOURCE_AUDIO != 0 {
+        obs_property_list_add_string(param as *mut obs_property_t, source.name().as_ptr(), source.uuid().as_ptr());
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// data/audio_encode.effect 中一个采样格子里每个像素的 (R, G)，和 shader 的写法保持一致
+    fn shader_cell(v: f32, amplifier: f32, cell_pixel_count: usize) -> Vec<(u8, u8)> {
+        let v1 = 16.0 + 120.0 * (v * amplifier + 1.0);
+        let base = v1.floor();
+        let f = v1 - base;
+        (0..cell_pixel_count).map(|p| {
+            let k = 2.0 * p as f32;
+            let r = if k < 0.5 { base } else { base + (k * f).ceil() - ((k - 1.0) * f).ceil() };
+            let g = base + ((k + 1.0) * f).ceil() - (k * f).ceil();
+            (r.clamp(16.0, 255.0) as u8, g.clamp(16.0, 255.0) as u8)
+        }).collect()
+    }
+
+    #[test]
+    fn cell_layouts_cover_every_cell_once() {
+        let (columns, rows) = (24, 6);
+        for layout in [LAYOUT_VERTICAL, LAYOUT_HORIZONTAL, LAYOUT_INTERLEAVED] {
+            let mut seen = vec![false; columns * rows];
+            for channel in 0..2 {
+                for index in 0..columns * rows / 2 {
+                    let (column, row) = cell_position(layout, channel, index, columns, rows);
+                    assert!(!seen[row * columns + column], "layout {} cell ({}, {})", layout, column, row);
+                    seen[row * columns + column] = true;
                 }
             }
+            // 左声道的头部都在第一行的开头
+            for index in 0..HEADER_CELL_COUNT {
+                assert_eq!(cell_position(layout, 0, index, columns, rows), (index, 0));
+            }
         }
-        // 输出数据
-        let min_source_sample_number = audio_renderer.source_sample_number.iter().filter_map(|v| {
-            if *v >= base_sample_number {
-                Some(*v)
-            } else {
-                None
-            }
-        }).min().unwrap_or(0);
-        let sample_count = min_source_sample_number.saturating_sub(base_sample_number);
-        if sample_count >= audio_renderer.flush_len {
-            // 一半是左声道，另一半是右声道
-            let max_0 = audio_buffer[0].iter().take(sample_count).fold(0.00001f32, |acc, v| acc.max(v.abs()));
-            let max_1 = audio_buffer[1].iter().take(sample_count).fold(0.00001f32, |acc, v| acc.max(v.abs()));
-            let half_index = audio_renderer.texture_buffer.len() / 2;
-            fill_texture_buffer(&mut audio_renderer.texture_buffer[..half_index], audio_buffer[0].iter().take(sample_count).map(|v| *v), audio_renderer.width, audio_renderer.cell_width, audio_renderer.cell_height, audio_renderer.packet_index as u32, 1.0 / max_0);
-            fill_texture_buffer(&mut audio_renderer.texture_buffer[half_index..], audio_buffer[1].iter().take(sample_count).map(|v| *v), audio_renderer.width, audio_renderer.cell_width, audio_renderer.cell_height, audio_renderer.packet_index as u32, 1.0 / max_1);
-            truncate_front(&mut audio_buffer[0], sample_count);
-            truncate_front(&mut audio_buffer[1], sample_count);
-            audio_renderer.base_sample_number += sample_count;
-            audio_renderer.packet_index += 1;
-            modified = true;
-        }
-        // 缓冲长度过大时，清除 buffer，防止延迟过高
-        if audio_buffer[0].len() >= audio_renderer.flush_len * 3 {
-            blog(LOG_ERROR, "[audio_renderer] audio_buffer too long\0".as_ptr().cast());
-            audio_buffer[0].clear();
-            audio_buffer[1].clear();
-            audio_renderer.source_sample_number.fill(0);
-            audio_renderer.base_sample_number = 1;
-        }
-        // 此处释放 audio_buffer 的 Mutex
-    }
-
-    obs_enter_graphics();
-    // 更新 texture 数据内容
-    if modified {
-        gs_texture_set_image(audio_renderer.texture, audio_renderer.texture_buffer.as_ptr(), (audio_renderer.width * 4) as _, false);
-    }
-    // 传进来的 effect 是 libobs/data/default.effect
-    // libobs 调用 video_render 之前，已经指定了使用的是 technique Draw 的唯一一个 pass
-    // 我们设置 texture2d image 参数即可
-    gs_effect_set_texture(gs_effect_get_param_by_name(effect, "image\0".as_ptr().cast()), audio_renderer.texture);
-    // gs_draw_sprite 时会自动构建矩形的 4 个顶点坐标 buffer、顶点 UV buffer、顶点索引 buffer
-    // 会自动设置 vertex_shader 的 VertInOut vert_in 参数
-    // gs_draw 时，device_draw 实现中会自动设置 float4x4 ViewProj 参数
-    gs_draw_sprite(audio_renderer.texture, 0, 0, 0);
-    obs_leave_graphics();
+    }
+
+    #[test]
+    fn preset_regions_match_readme() {
+        let config = |placement| preset_region(placement, 1920, 1080).unwrap().viewer_config();
+        assert_eq!(config(PLACEMENT_LEFT_STRIP), "0,0,32,1072,2,2");
+        assert_eq!(config(PLACEMENT_TOP_STRIP), "0,0,1920,16,2,2");
+        assert_eq!(config(PLACEMENT_BOTTOM_STRIP), "0,1064,1920,16,2,2");
+        assert_eq!(preset_region(PLACEMENT_CUSTOM, 1920, 1080), None);
+    }
+
+    #[test]
+    fn nearest_valid_geometry() {
+        let geometry = Geometry { width: 32, height: 1071, cell_width: 2, cell_height: 2, layout: LAYOUT_VERTICAL, low_contrast: false, header_cell_count: HEADER_CELL_COUNT, block_count: 1, authenticated: false, flush_len: 2400 };
+        assert!(geometry.check().is_err());
+        assert_eq!(geometry.nearest_valid().map(|g| (g.width, g.height)), Some((32, 1072)));
+        // 冗余时需要两倍的格子，加宽比加高改动更少
+        let redundant = Geometry { height: 1072, block_count: 2, ..geometry };
+        assert!(redundant.check().unwrap_err().contains("最多只能放下 2139 个"));
+        assert_eq!(redundant.nearest_valid().map(|g| (g.width, g.height)), Some((36, 1072)));
+        // 验证时还要放下结束标记和每 16 个采样 3 个辅助位
+        let authenticated = Geometry { authenticated: true, ..redundant };
+        assert!(authenticated.check().unwrap_err().contains("最多只能放下 1800 个"));
+        // 低对比度模式的格子先取偶数
+        let low_contrast = Geometry { height: 1072, cell_width: 3, cell_height: 3, low_contrast: true, header_cell_count: LOW_CONTRAST_HEADER_CELL_COUNT, ..geometry };
+        let nearest = low_contrast.nearest_valid().unwrap();
+        assert_eq!((nearest.cell_width, nearest.cell_height), (4, 4));
+        assert!(nearest.check().is_ok());
+    }
+
+    #[test]
+    fn target_latency_rounds_to_whole_frames() {
+        // 60fps 下 50 毫秒是 3 帧，正好 2400 个采样
+        assert_eq!(target_latency_frames(50, 60, 1), 3);
+        assert_eq!(frames_sample_count(3, 48000, 60, 1), 2400);
+        // 29.97fps 每帧 1601.6 个采样，向下取整
+        assert_eq!(target_latency_frames(100, 30000, 1001), 3);
+        assert_eq!(frames_sample_count(3, 48000, 30000, 1001), 4804);
+        assert_eq!(target_latency_frames(1, 60, 1), 1);
+    }
+
+    #[test]
+    fn stretch_keeps_endpoints_and_continuity() {
+        // 2448 个采样伸缩为 2400 个，相当于追赶时最大的加速比例
+        let input: VecDeque<f32> = (0..2448).map(|i| (i as f32 * 0.05).sin()).collect();
+        let output = stretch_audio_buffer(&input, 2448, 2400);
+        assert_eq!(output.len(), 2400);
+        assert_eq!(output[0], input[0]);
+        assert!((output[2399] - input[2447]).abs() < 1e-6);
+        // 相邻采样的差不超过输入中相邻采样最大的差按伸缩比例放大之后的值，没有断点
+        let max_input_step = input.iter().zip(input.iter().skip(1)).map(|(a, b)| (b - a).abs()).fold(0.0, f32::max);
+        let max_output_step = output.windows(2).map(|v| (v[1] - v[0]).abs()).fold(0.0, f32::max);
+        assert!(max_output_step <= max_input_step * 2448.0 / 2400.0 + 1e-6, "{} {}", max_output_step, max_input_step);
+        // 长度不变时原样输出
+        assert!(stretch_audio_buffer(&input, 2400, 2400).iter().eq(input.iter().take(2400)));
+    }
+
+    /// GPU 编码器的抖动公式和 CPU 编码器逐个扩散误差的结果等价
+    #[test]
+    fn shader_dithering_matches_cpu_encoder() {
+        for (cell_width, cell_height) in [(1, 1), (2, 2), (4, 4), (3, 2)] {
+            // 每行一个格子，上半部分的前 10 个格子是头部，第 11 个格子是采样
+            let height = cell_height * (HEADER_CELL_COUNT + 1) * 2;
+            let mut texture_buffer = vec![0u8; cell_width * height * 4];
+            for amplifier in [1.0, 2.0, 7.0] {
+                for i in 0..=400 {
+                    let v = (i as f32 / 200.0 - 1.0) / amplifier;
+                    fill_texture_buffer(&mut texture_buffer, std::iter::once(v), LAYOUT_VERTICAL, 0, cell_width, cell_width, cell_height, 0, amplifier, &[], &[]);
+                    let cpu: Vec<(u8, u8)> = (0..cell_width * cell_height).map(|p| {
+                        let index = 4 * (HEADER_CELL_COUNT * cell_height * cell_width + p);
+                        (texture_buffer[index], texture_buffer[index + 1])
+                    }).collect();
+                    let gpu = shader_cell(v, amplifier, cell_width * cell_height);
+                    // 解码时只看格子的平均值；v1 非常接近整数时两边的浮点误差不同，多出来的 1 可能落在不同的像素上
+                    let sum = |cell: &[(u8, u8)]| cell.iter().map(|(r, g)| *r as i32 + *g as i32).sum::<i32>();
+                    assert_eq!(sum(&cpu), sum(&gpu), "v = {}, amplifier = {}, cell = {}x{}", v, amplifier, cell_width, cell_height);
+                    let min = gpu.iter().map(|(r, g)| (*r).min(*g)).min().unwrap();
+                    assert!(gpu.iter().all(|(r, g)| *r <= min.saturating_add(1) && *g <= min.saturating_add(1)));
+                }
+            }
+        }
+    }
 }
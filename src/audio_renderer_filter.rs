@@ -0,0 +1,219 @@
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
+
+use bindings::{gs_effect_t, obs_source_type, obs_source_type_OBS_SOURCE_TYPE_FILTER, OBS_SOURCE_VIDEO};
+
+use crate::audio_renderer::{add_capture_properties, add_data_properties, add_encoding_properties, add_help_properties, add_placement_properties, add_stats_properties, add_viewer_config_properties, AudioRenderer, copy_viewer_config, ENCODER_LOW_CONTRAST, encoding_property_modified, PLACEMENT_CUSTOM, Region};
+use crate::obs::{canvas_size, Effect, GraphicsGuard, ObsData, Properties, register_source, Source, SourceRef, with_translation};
+
+/// 自定义位置时编码区域放在左上角
+const CORNER_TOP_LEFT: i64 = 0;
+/// 编码区域放在右上角
+const CORNER_TOP_RIGHT: i64 = 1;
+/// 编码区域放在左下角
+const CORNER_BOTTOM_LEFT: i64 = 2;
+/// 编码区域放在右下角
+const CORNER_BOTTOM_RIGHT: i64 = 3;
+
+/// 编码区域直接覆盖在画面上
+const OVERLAY_MODE_NORMAL: i64 = 0;
+/// 在画面上叠加很小的亮度变化，见 data/audio_encode.effect 中的 DrawLowContrast
+const OVERLAY_MODE_LOW_CONTRAST: i64 = 1;
+
+/// Audio Renderer Overlay 视频滤镜，把编码区域直接画在所在的源或者场景上
+///
+/// 声音的获取和编码完全交给内部的 [`AudioRenderer`]，这里只负责先画出原来的画面，再把编码区域画到指定的角落。
+pub struct AudioRendererFilter {
+    source: SourceRef,
+    renderer: Arc<AudioRenderer>,
+    placement: Mutex<Placement>,
+    /// 低对比度模式下叠加的亮度变化幅度（0 ~ 255），为 None 表示直接覆盖
+    low_contrast_strength: Mutex<Option<f32>>,
+}
+
+/// 编码区域相对于所在画面的位置
+struct Placement {
+    /// 使用预设位置时，编码区域的位置由 [`AudioRenderer`] 按画布大小计算，忽略下面的角落和边距
+    preset: bool,
+    corner: i64,
+    offset_x: u32,
+    offset_y: u32,
+}
+
+impl Placement {
+    /// 编码区域左上角的坐标，超出画面时贴着画面边缘
+    fn position(&self, parent_width: u32, parent_height: u32, width: u32, height: u32) -> (u32, u32) {
+        let right = matches!(self.corner, CORNER_TOP_RIGHT | CORNER_BOTTOM_RIGHT);
+        let bottom = matches!(self.corner, CORNER_BOTTOM_LEFT | CORNER_BOTTOM_RIGHT);
+        let max_x = parent_width.saturating_sub(width);
+        let max_y = parent_height.saturating_sub(height);
+        let x = if right { max_x.saturating_sub(self.offset_x) } else { self.offset_x.min(max_x) };
+        let y = if bottom { max_y.saturating_sub(self.offset_y) } else { self.offset_y.min(max_y) };
+        (x, y)
+    }
+}
+
+pub unsafe fn register() {
+    register_source::<AudioRendererFilter>();
+}
+
+/// 低对比度模式只能用 GPU 编码器，其他时候使用设置中的编码方式
+fn overlay_encoder(settings: &ObsData) -> i64 {
+    if settings.get_int("overlay_mode") == OVERLAY_MODE_LOW_CONTRAST {
+        ENCODER_LOW_CONTRAST
+    } else {
+        settings.get_int("encoder")
+    }
+}
+
+impl AudioRendererFilter {
+    fn apply_settings(&self, settings: &ObsData) {
+        let low_contrast = settings.get_int("overlay_mode") == OVERLAY_MODE_LOW_CONTRAST;
+        self.renderer.update_with_encoder(settings, overlay_encoder(settings));
+        *self.low_contrast_strength.lock().unwrap() = low_contrast.then(|| settings.get_int("low_contrast_strength").clamp(1, 255) as f32);
+        *self.placement.lock().unwrap() = Placement {
+            preset: settings.get_int("placement") != PLACEMENT_CUSTOM,
+            corner: settings.get_int("corner"),
+            offset_x: settings.get_int("offset_x").max(0) as u32,
+            offset_y: settings.get_int("offset_y").max(0) as u32,
+        };
+    }
+
+    /// 编码区域在所在画面中的位置和大小
+    fn region(&self, parent_width: u32, parent_height: u32) -> Region {
+        let mut region = self.renderer.region();
+        let placement = self.placement.lock().unwrap();
+        if !placement.preset {
+            (region.x, region.y) = placement.position(parent_width, parent_height, region.width, region.height);
+        }
+        region
+    }
+
+    /// 所在的源的大小，还没有添加到源上时使用画布大小
+    fn parent_size(&self) -> (u32, u32) {
+        match self.source.filter_target() {
+            Some(target) => (target.base_width(), target.base_height()),
+            None => canvas_size().unwrap_or_default(),
+        }
+    }
+}
+
+impl Source for AudioRendererFilter {
+    const ID: &'static str = "audio_renderer_filter\0";
+    const NAME: &'static str = "Audio Renderer Overlay\0";
+    const TYPE: obs_source_type = obs_source_type_OBS_SOURCE_TYPE_FILTER;
+    const OUTPUT_FLAGS: u32 = OBS_SOURCE_VIDEO;
+
+    fn create(settings: &ObsData, source: SourceRef) -> Arc<Self> {
+        let filter = Arc::new(AudioRendererFilter {
+            source,
+            renderer: AudioRenderer::new(),
+            placement: Mutex::new(Placement { preset: false, corner: CORNER_TOP_LEFT, offset_x: 0, offset_y: 0 }),
+            low_contrast_strength: Mutex::new(None),
+        });
+        filter.apply_settings(settings);
+        filter.renderer.attach_source(source);
+        filter
+    }
+
+    fn destroy(&self) {
+        self.renderer.destroy();
+    }
+
+    fn width(&self) -> u32 {
+        self.source.filter_target().map(|target| target.base_width()).unwrap_or(0)
+    }
+
+    fn height(&self) -> u32 {
+        self.source.filter_target().map(|target| target.base_height()).unwrap_or(0)
+    }
+
+    fn defaults(settings: &ObsData) {
+        AudioRenderer::defaults(settings);
+        settings.set_default_int("corner", CORNER_TOP_LEFT);
+        settings.set_default_int("offset_x", 0);
+        settings.set_default_int("offset_y", 0);
+        settings.set_default_int("overlay_mode", OVERLAY_MODE_NORMAL);
+        settings.set_default_int("low_contrast_strength", 4);
+    }
+
+    fn properties(this: Option<&Self>) -> Properties {
+        let mut props = Properties::new();
+        add_capture_properties(&mut props);
+        add_placement_properties::<Self>(&mut props);
+        let corner = props.add_int_list("corner", "编码区域所在的角落（仅在自定义位置时有效）");
+        corner.list_add_int("左上角", CORNER_TOP_LEFT);
+        corner.list_add_int("右上角", CORNER_TOP_RIGHT);
+        corner.list_add_int("左下角", CORNER_BOTTOM_LEFT);
+        corner.list_add_int("右下角", CORNER_BOTTOM_RIGHT);
+        props.add_int("offset_x", "距离画面左右边缘的距离（单位：像素）", 0, 7680, 1);
+        props.add_int("offset_y", "距离画面上下边缘的距离（单位：像素）", 0, 4320, 1);
+        let overlay_mode = props.add_int_list("overlay_mode", "叠加方式");
+        overlay_mode.list_add_int("直接覆盖", OVERLAY_MODE_NORMAL);
+        overlay_mode.list_add_int("低对比度（肉眼不易察觉，忽略编码方式，格子宽度和高度必须是偶数）", OVERLAY_MODE_LOW_CONTRAST);
+        overlay_mode.set_modified::<Self>();
+        props.add_int("low_contrast_strength", "低对比度模式的亮度变化幅度（推荐 4，越大越不容易受画面压缩影响）", 1, 32, 1);
+        add_encoding_properties::<Self>(&mut props);
+        add_data_properties::<Self>(&mut props);
+        add_viewer_config_properties::<Self>(&mut props, this.map(|this| {
+            let (parent_width, parent_height) = this.parent_size();
+            this.region(parent_width, parent_height)
+        }));
+        add_stats_properties(&mut props, this.map(|this| &*this.renderer));
+        add_help_properties(&mut props);
+        props
+    }
+
+    fn update(&self, settings: &ObsData) {
+        self.apply_settings(settings);
+    }
+
+    fn button_clicked(&self, name: &str) -> bool {
+        match name {
+            "copy_viewer_config" => {
+                let (parent_width, parent_height) = self.parent_size();
+                copy_viewer_config(&self.region(parent_width, parent_height), &self.renderer.logger);
+            }
+            "send_data" => self.renderer.send_draft(),
+            _ => {}
+        }
+        true
+    }
+
+    fn property_modified(props: &mut Properties, name: &str, settings: &ObsData) -> bool {
+        encoding_property_modified(props, name, settings, overlay_encoder(settings))
+    }
+
+    fn tick(&self, seconds: f32) {
+        self.renderer.tick(seconds);
+    }
+
+    fn render(&self, _effect: *mut gs_effect_t) {
+        let target = match self.source.filter_target() {
+            Some(target) => target,
+            None => return,
+        };
+        let graphics = GraphicsGuard::enter();
+        let (parent_width, parent_height) = (target.base_width(), target.base_height());
+        let low_contrast_strength = *self.low_contrast_strength.lock().unwrap();
+        if let Some(strength) = low_contrast_strength {
+            // 编码要叠加在画面上，所以所在的源必须先渲染到 texture
+            if !self.source.begin_filter(&graphics, false) {
+                return;
+            }
+            let region = self.region(parent_width, parent_height);
+            let drawn = self.renderer.render_low_contrast(&graphics, &region, parent_width, parent_height, strength, |effect| {
+                self.source.end_filter(&graphics, effect, "DrawLowContrast", parent_width, parent_height);
+            });
+            if !drawn {
+                self.source.end_filter(&graphics, &Effect::base_default(), "Draw", parent_width, parent_height);
+            }
+            return;
+        }
+        if !self.source.draw_filter_target(&graphics, parent_width, parent_height) {
+            return;
+        }
+        let region = self.region(parent_width, parent_height);
+        with_translation(&graphics, region.x as f32, region.y as f32, || self.renderer.render(null_mut()));
+    }
+}
@@ -0,0 +1,207 @@
+//! 观看端验证
+//!
+//! 视频压缩之后，解码端读到的采样和编码时不完全相同，不能直接对采样计算 HMAC。
+//! 这里把每个声道的采样每 16 个分成一块，每块按密钥决定的 ±1 符号加权求和，得到一个对所有采样都敏感、对小误差不敏感的特征值，
+//! 特征值按 16 个灰度一档量化成档位。采样本身不做任何改动，档位的边界由编码端决定：
+//! 每块有 3 个辅助位，记录特征值在档位中的位置，解码端按辅助位把读到的特征值对齐到档位中间再取整，特征值的误差小于 7 个灰度时能读出相同的档位。
+//!
+//! 辅助位放在采样和结束标记之后、数据通道之前，每个格子 1 位。
+//! 标签是 HMAC-SHA256(密钥, 完整的包序号 || 采样数 || 上一个包的采样数 || 放大倍数 || 每个声道的档位、辅助位和数据通道中各帧的字节) 的前 32 位，
+//! 和完整的包序号一起写在头部中，userscript/audio_decode.user.js 用 WebCrypto 验证。
+//!
+//! 改动任何一个采样超过 32 个灰度（满幅度的 1/8 左右），或者改动辅助位、数据通道和参与计算的头部，标签都会不同。
+//! 更小的改动和视频压缩的误差无法区分，不会被发现。
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// 每块的采样数
+const BLOCK_SIZE: usize = 16;
+/// 每块的辅助位数
+const HELPER_BITS: usize = 3;
+const HELPER_LEVELS: f64 = (1 << HELPER_BITS) as f64;
+/// 特征值每档的灰度数
+const LEVEL_STEP: f64 = 16.0;
+/// 每次 HMAC 得到 256 个采样的符号
+const SIGNS_PER_MAC: usize = 256;
+
+pub struct PayloadAuthenticator {
+    mac: Hmac<Sha256>,
+}
+
+impl PayloadAuthenticator {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            mac: Hmac::new_from_slice(secret.as_bytes()).expect("HMAC 可以使用任意长度的密钥"),
+        }
+    }
+
+    /// 第 `packet` 个包的标签和两个声道的辅助位
+    ///
+    /// `channels` 是写入格子的采样，加密时必须在加密之后调用，解码端先验证再解密。
+    /// `amplifiers` 是写入头部的放大倍数，`data` 是两个声道数据通道中各帧的字节，见 [`crate::data_channel::frame_bytes`]
+    pub fn authenticate(&self, packet: u32, previous_count: usize, channels: [&[f32]; 2], amplifiers: [f32; 2], data: [&[u8]; 2]) -> (u32, [Vec<bool>; 2]) {
+        let helpers = [0, 1].map(|channel| {
+            self.features(packet, channel, channels[channel as usize], amplifiers[channel as usize]).into_iter().map(helper).collect::<Vec<u8>>()
+        });
+        let tag = self.tag(packet, previous_count, channels, amplifiers, [&helpers[0], &helpers[1]], data);
+        let bits = helpers.map(|helpers| helpers.iter().flat_map(|helper| (0..HELPER_BITS).map(move |i| helper & (1 << i) != 0)).collect());
+        (tag, bits)
+    }
+
+    /// 解码端重新计算的标签，`channels` 是读到的采样，`helpers` 是读到的辅助位
+    #[cfg(test)]
+    fn decoded_tag(&self, packet: u32, previous_count: usize, channels: [&[f32]; 2], amplifiers: [f32; 2], helpers: [&[bool]; 2], data: [&[u8]; 2]) -> u32 {
+        let helpers = helpers.map(|bits| bits.chunks(HELPER_BITS).map(|bits| bits.iter().enumerate().map(|(i, bit)| (*bit as u8) << i).sum()).collect::<Vec<u8>>());
+        self.tag(packet, previous_count, channels, amplifiers, [&helpers[0], &helpers[1]], data)
+    }
+
+    fn tag(&self, packet: u32, previous_count: usize, channels: [&[f32]; 2], amplifiers: [f32; 2], helpers: [&[u8]; 2], data: [&[u8]; 2]) -> u32 {
+        let mut mac = self.mac.clone();
+        mac.update(&packet.to_be_bytes());
+        mac.update(&(channels[0].len() as u32).to_be_bytes());
+        mac.update(&(previous_count as u32).to_be_bytes());
+        mac.update(&[amplifiers[0] as u8, amplifiers[1] as u8]);
+        for channel in 0..2 {
+            let features = self.features(packet, channel as u32, channels[channel], amplifiers[channel]);
+            let symbols: Vec<u8> = features.into_iter().zip(helpers[channel]).map(|(feature, helper)| symbol(feature, *helper) as u8).collect();
+            mac.update(&symbols);
+            mac.update(helpers[channel]);
+            mac.update(&(data[channel].len() as u32).to_be_bytes());
+            mac.update(data[channel]);
+        }
+        let bytes = mac.finalize().into_bytes();
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// 每块的特征值，单位是灰度
+    fn features(&self, packet: u32, channel: u32, samples: &[f32], amplifier: f32) -> Vec<f64> {
+        let signs = self.signs(packet, channel, samples.len());
+        samples.chunks(BLOCK_SIZE).zip(signs.chunks(BLOCK_SIZE)).map(|(block, signs)| {
+            let sum: f64 = block.iter().zip(signs).map(|(v, positive)| {
+                let level = 120.0 * (*v * amplifier).clamp(-1.0, 1.0) as f64;
+                if *positive { level } else { -level }
+            }).sum();
+            sum / (block.len() as f64).sqrt()
+        }).collect()
+    }
+
+    /// 每个采样的符号，true 表示 +1。第 k 段 256 个采样的符号是 HMAC-SHA256(密钥, 包序号 || 声道 || k) 的各位，低位在前
+    ///
+    /// 这里的 HMAC 输入固定是 12 个字节，比标签的输入短，两者不会相同
+    fn signs(&self, packet: u32, channel: u32, count: usize) -> Vec<bool> {
+        (0..count.div_ceil(SIGNS_PER_MAC) as u32).flat_map(|counter| {
+            let mut mac = self.mac.clone();
+            mac.update(&packet.to_be_bytes());
+            mac.update(&channel.to_be_bytes());
+            mac.update(&counter.to_be_bytes());
+            mac.finalize().into_bytes().into_iter().flat_map(|byte| (0..8).map(move |i| byte & (1 << i) != 0)).collect::<Vec<bool>>()
+        }).take(count).collect()
+    }
+}
+
+/// `sample_count` 个采样的辅助位占用的格子数
+pub fn helper_cell_count(sample_count: usize) -> usize {
+    sample_count.div_ceil(BLOCK_SIZE) * HELPER_BITS
+}
+
+/// `cells` 个格子最多能放下的采样数，采样之后还要放下结束标记和辅助位
+pub fn sample_capacity(cells: usize) -> usize {
+    let fits = |n: usize| n + 1 + helper_cell_count(n) <= cells;
+    let mut n = cells.saturating_sub(1) * BLOCK_SIZE / (BLOCK_SIZE + HELPER_BITS);
+    while n > 0 && !fits(n) {
+        n -= 1;
+    }
+    while fits(n + 1) {
+        n += 1;
+    }
+    n
+}
+
+/// 特征值在档位中的位置，0 ~ 7
+fn helper(feature: f64) -> u8 {
+    let u = feature / LEVEL_STEP;
+    ((u - u.floor()) * HELPER_LEVELS).floor().min(HELPER_LEVELS - 1.0) as u8
+}
+
+/// 按辅助位对齐到档位中间之后取整。编码端的特征值和对齐点最多相差 1/16 档，所以解码端的误差小于 7/16 档时结果相同
+fn symbol(feature: f64, helper: u8) -> i8 {
+    (feature / LEVEL_STEP - (helper as f64 + 0.5) / HELPER_LEVELS + 0.5).floor() as i8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(count: usize, phase: f32) -> Vec<f32> {
+        (0..count).map(|i| 0.45 * (i as f32 * 0.03 + phase).sin() + 0.2 * (i as f32 * 0.71).sin()).collect()
+    }
+
+    #[test]
+    fn small_errors_keep_tag() {
+        let amplifiers = [2.0, 1.0];
+        let channel_0 = samples(2400, 0.0);
+        let channel_1 = samples(2400, 1.0);
+        let data = [&[1u8, 0, 1, 0x61, 0x12, 0x34][..], &[][..]];
+        let authenticator = PayloadAuthenticator::new("stream secret");
+        let (tag, helpers) = authenticator.authenticate(5, 0, [&channel_0, &channel_1], amplifiers, data);
+        assert_eq!(helpers[0].len(), helper_cell_count(2400));
+        assert_eq!(authenticator.decoded_tag(5, 0, [&channel_0, &channel_1], amplifiers, [&helpers[0], &helpers[1]], data), tag);
+        // 解码端读到的灰度有 ±3 的误差
+        for error in [-3.0, 3.0] {
+            let noisy = [(&channel_0, amplifiers[0]), (&channel_1, amplifiers[1])].map(|(channel, amplifier)| {
+                channel.iter().enumerate().map(|(i, v)| v + error * (i as f32 * 1.7).sin() / 120.0 / amplifier).collect::<Vec<f32>>()
+            });
+            assert_eq!(authenticator.decoded_tag(5, 0, [&noisy[0], &noisy[1]], amplifiers, [&helpers[0], &helpers[1]], data), tag);
+        }
+        assert_ne!(PayloadAuthenticator::new("other").decoded_tag(5, 0, [&channel_0, &channel_1], amplifiers, [&helpers[0], &helpers[1]], data), tag);
+    }
+
+    #[test]
+    fn every_cell_is_authenticated() {
+        let amplifiers = [1.0, 1.0];
+        let channel_0 = samples(2400, 0.0);
+        let channel_1 = samples(2400, 1.0);
+        let data = [&[1u8, 0, 1, 0x61, 0x12, 0x34][..], &[][..]];
+        let authenticator = PayloadAuthenticator::new("stream secret");
+        let (tag, helpers) = authenticator.authenticate(5, 0, [&channel_0, &channel_1], amplifiers, data);
+        let helpers = [&helpers[0][..], &helpers[1][..]];
+        // 任何一个采样改动 1/4 满幅度（60 个灰度）都会被发现
+        for i in 0..channel_0.len() {
+            let mut tampered = channel_0.clone();
+            tampered[i] += if tampered[i] > 0.0 { -0.5 } else { 0.5 };
+            assert_ne!(authenticator.decoded_tag(5, 0, [&tampered, &channel_1], amplifiers, helpers, data), tag, "sample {}", i);
+        }
+        // 辅助位、数据通道、放大倍数、包序号和上一个包的采样数
+        for i in 0..helpers[1].len() {
+            let mut tampered = helpers[1].to_vec();
+            tampered[i] = !tampered[i];
+            assert_ne!(authenticator.decoded_tag(5, 0, [&channel_0, &channel_1], amplifiers, [helpers[0], &tampered], data), tag, "helper {}", i);
+        }
+        assert_ne!(authenticator.decoded_tag(5, 0, [&channel_0, &channel_1], amplifiers, helpers, [&[1, 0, 1, 0x62, 0x12, 0x34], &[]]), tag);
+        assert_ne!(authenticator.decoded_tag(5, 0, [&channel_0, &channel_1], [1.0, 2.0], helpers, data), tag);
+        assert_ne!(authenticator.decoded_tag(6, 0, [&channel_0, &channel_1], amplifiers, helpers, data), tag);
+        assert_ne!(authenticator.decoded_tag(5, 100, [&channel_0, &channel_1], amplifiers, helpers, data), tag);
+    }
+
+    #[test]
+    fn sample_capacity_leaves_room_for_helpers() {
+        for cells in 0..200 {
+            let n = sample_capacity(cells);
+            assert!(n == 0 || n + 1 + helper_cell_count(n) <= cells);
+            assert!(n + 2 + helper_cell_count(n + 1) > cells);
+        }
+        assert_eq!(sample_capacity(1 + 16 + 3), 16);
+    }
+
+    #[test]
+    fn tag_matches_webcrypto() {
+        // 解码脚本中的 newAudioVerifier 用浏览器的 WebCrypto 计算得到的标签
+        let channel_0: Vec<f32> = (0..40).map(|i| 0.8 * (i as f32 * 0.37).sin()).collect();
+        let channel_1: Vec<f32> = (0..40).map(|i| 0.3 * (i as f32 * 0.11).cos()).collect();
+        let (tag, helpers) = PayloadAuthenticator::new("stream secret").authenticate(5, 8, [&channel_0, &channel_1], [1.0, 3.0], [&[1, 0, 0, 0x1d, 0x0f], &[]]);
+        // 辅助位依次是 1、1、2 和 4、1、4，低位在前
+        assert_eq!(helpers.map(|bits| bits.iter().map(|bit| *bit as u8).collect::<Vec<u8>>()), [vec![1, 0, 0, 1, 0, 0, 0, 1, 0], vec![0, 0, 1, 1, 0, 0, 0, 0, 1]]);
+        assert_eq!(tag, 0x4e764800);
+    }
+}
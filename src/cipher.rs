@@ -0,0 +1,122 @@
+//! 观看端密码加密
+//!
+//! 采样在格子中编码为 `120 * (v * amplifier + 1)`，即 0 ~ 240 的灰度（见 audio_renderer.rs 中的 fill_texture_buffer），
+//! 加密就是在这个范围内按密钥流循环平移。头部和格子的排列都不变，视频压缩造成的误差也不会被放大，只有接近满幅度的采样可能绕到另一端。
+//!
+//! 密钥由 PBKDF2-HMAC-SHA256 从密码和每次设置密码时随机生成的盐派生，密钥流是 AES-128-CTR，
+//! 都是浏览器 WebCrypto 直接支持的算法，userscript/audio_decode.user.js 中有相同的实现。
+
+use aes::Aes128;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+type Aes128Ctr32BE = ctr::Ctr32BE<Aes128>;
+
+/// PBKDF2 的迭代次数，只在修改密码时计算一次
+const KDF_ITERATIONS: u32 = 100_000;
+/// 采样平移的范围
+const LEVELS: f32 = 240.0;
+
+pub struct PayloadCipher {
+    passphrase: String,
+    salt: u32,
+    key: [u8; 16],
+}
+
+impl PayloadCipher {
+    /// 用新的随机盐派生密钥
+    pub fn new(passphrase: &str) -> Result<Self, String> {
+        let mut salt = [0u8; 4];
+        getrandom::getrandom(&mut salt).map_err(|e| e.to_string())?;
+        Ok(Self::with_salt(passphrase, u32::from_be_bytes(salt)))
+    }
+
+    fn with_salt(passphrase: &str, salt: u32) -> Self {
+        let mut key = [0u8; 16];
+        pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt.to_be_bytes(), KDF_ITERATIONS, &mut key);
+        Self {
+            passphrase: passphrase.to_string(),
+            salt,
+            key,
+        }
+    }
+
+    /// 密码没有变化时继续使用原来的盐和密钥
+    pub fn passphrase_matches(&self, passphrase: &str) -> bool {
+        self.passphrase == passphrase
+    }
+
+    pub fn salt(&self) -> u32 {
+        self.salt
+    }
+
+    /// 加密第 `packet` 个包中 `channel` 声道的采样，`amplifier` 是写入头部的放大倍数
+    ///
+    /// 加密后的采样乘以 `amplifier` 仍然在 -1.0 ~ 1.0 之间，编码器不需要区分是否加密
+    pub fn encrypt(&self, samples: &mut [f32], amplifier: f32, packet: u32, channel: u32) {
+        let offsets = self.keystream(samples.len(), packet, channel);
+        for (v, offset) in samples.iter_mut().zip(offsets) {
+            // 满幅度的正值和负值平移后是同一个灰度，所以只取到 239
+            let level = (120.0 * ((*v * amplifier).clamp(-1.0, 1.0) + 1.0)).min(LEVELS - 1.0);
+            *v = ((level + offset).rem_euclid(LEVELS) / 120.0 - 1.0) / amplifier;
+        }
+    }
+
+    #[cfg(test)]
+    fn decrypt(&self, samples: &mut [f32], amplifier: f32, packet: u32, channel: u32) {
+        let offsets = self.keystream(samples.len(), packet, channel);
+        for (v, offset) in samples.iter_mut().zip(offsets) {
+            let level = 120.0 * (*v * amplifier + 1.0);
+            *v = ((level - offset).rem_euclid(LEVELS) / 120.0 - 1.0) / amplifier;
+        }
+    }
+
+    /// 每个采样的平移量（0 ~ 239），由 2 个字节的密钥流对 240 取余得到
+    ///
+    /// 计数器的初始值是盐、包序号、声道和 0，各 4 个字节，大端序，和 WebCrypto 中 `length: 32` 的 AES-CTR 相同
+    fn keystream(&self, len: usize, packet: u32, channel: u32) -> Vec<f32> {
+        let mut iv = [0u8; 16];
+        iv[0..4].copy_from_slice(&self.salt.to_be_bytes());
+        iv[4..8].copy_from_slice(&packet.to_be_bytes());
+        iv[8..12].copy_from_slice(&channel.to_be_bytes());
+        let mut bytes = vec![0u8; len * 2];
+        Aes128Ctr32BE::new(&self.key.into(), &iv.into()).apply_keystream(&mut bytes);
+        bytes.chunks_exact(2).map(|b| (u16::from_be_bytes([b[0], b[1]]) % LEVELS as u16) as f32).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_round_trip() {
+        let cipher = PayloadCipher::with_salt("correct horse", 0x12345678);
+        let amplifier = 4.0;
+        let plain: Vec<f32> = (0..2000).map(|i| (i as f32 * 0.01).sin() * 0.24).collect();
+        let mut samples = plain.clone();
+        cipher.encrypt(&mut samples, amplifier, 7, 1);
+        assert!(samples.iter().all(|v| (v * amplifier).abs() <= 1.0));
+        // 密文和明文没有相关性
+        let same = samples.iter().zip(&plain).filter(|(a, b)| (*a - *b).abs() < 0.01).count();
+        assert!(same < 100, "{}", same);
+        cipher.decrypt(&mut samples, amplifier, 7, 1);
+        for (a, b) in samples.iter().zip(&plain) {
+            assert!((a - b).abs() < 1e-4, "{} {}", a, b);
+        }
+
+        // 不同的包使用不同的密钥流
+        let mut other = plain.clone();
+        cipher.encrypt(&mut other, amplifier, 8, 1);
+        cipher.decrypt(&mut other, amplifier, 7, 1);
+        assert!(other.iter().zip(&plain).filter(|(a, b)| (*a - *b).abs() < 0.01).count() < 100);
+    }
+
+    #[test]
+    fn keystream_matches_webcrypto() {
+        // 解码脚本中的 newAudioDecryptor 用浏览器的 WebCrypto 计算得到的平移量
+        let cipher = PayloadCipher::with_salt("correct horse", 0x12345678);
+        assert_eq!(cipher.keystream(8, 7, 1), vec![122.0, 222.0, 192.0, 82.0, 130.0, 197.0, 117.0, 138.0]);
+    }
+}
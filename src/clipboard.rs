@@ -0,0 +1,65 @@
+//! 把文字复制到系统剪贴板
+//!
+//! libobs 没有剪贴板相关的接口，插件也不链接 Qt，所以调用各个系统自带的命令行工具。
+
+use std::io::{Error, Result, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 在 UI 线程中最多等待命令退出的时间
+///
+/// xclip 和 wl-copy 会一直运行到剪贴板被其他程序占用，超时之后不再等待，由后台线程回收
+const WAIT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// 依次尝试的命令，前面的不存在或者失败时使用后面的
+#[cfg(target_os = "windows")]
+const COMMANDS: &[&[&str]] = &[&["clip"]];
+#[cfg(target_os = "macos")]
+const COMMANDS: &[&[&str]] = &[&["pbcopy"]];
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const COMMANDS: &[&[&str]] = &[&["wl-copy"], &["xclip", "-selection", "clipboard"], &["xsel", "--clipboard", "--input"]];
+
+pub fn set_text(text: &str) -> std::result::Result<(), String> {
+    let mut errors = Vec::new();
+    for command in COMMANDS {
+        match run(command, text) {
+            Ok(()) => return Ok(()),
+            Err(e) => errors.push(format!("{}: {}", command[0], e)),
+        }
+    }
+    Err(errors.join("; "))
+}
+
+fn run(command: &[&str], text: &str) -> Result<()> {
+    let mut builder = Command::new(command[0]);
+    builder.args(&command[1..]).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null());
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        // CREATE_NO_WINDOW，不弹出控制台窗口
+        builder.creation_flags(0x08000000);
+    }
+    let mut child = builder.spawn()?;
+    // 写完之后关闭 stdin，否则命令会一直等待输入
+    let written = child.stdin.take().unwrap().write_all(text.as_bytes());
+    // 按钮的回调在 UI 线程中执行，不能一直阻塞在 wait 上
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() >= WAIT_TIMEOUT {
+            // 输入已经写完，命令仍在运行时认为复制成功
+            thread::spawn(move || child.wait());
+            return written;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    written?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::other(format!("exit status {}", status)))
+    }
+}
@@ -0,0 +1,156 @@
+//! 数据通道
+//!
+//! 采样之后剩下的格子用来传输任意数据，例如字幕、章节标记和 JSON。
+//! 每个声道在采样之后先空出一个格子（CPU 编码器的黑色结束标记），之后每个格子 1 位，低位在前，依次是若干帧，最后是 8 位的 0。
+//! 每帧是 1 字节的类型、2 字节的长度（大端序）、数据和 2 字节的 CRC-16/CCITT-FALSE，解码端丢弃校验失败的帧。
+//! 一帧必须完整地放在一个声道中，放不下时留到下一个包。
+//! 数据通道不加密。设置了验证密钥时，结束标记和数据通道之间还有验证用的辅助位，各帧的字节和采样一起参与计算标签，见 src/auth.rs。
+
+use std::collections::VecDeque;
+
+/// 数据类型：字幕，UTF-8 文本
+pub const DATA_TYPE_SUBTITLE: u8 = 1;
+/// 数据类型：章节标记，UTF-8 文本
+pub const DATA_TYPE_MARKER: u8 = 2;
+/// 数据类型：JSON，UTF-8 文本
+pub const DATA_TYPE_JSON: u8 = 3;
+/// 类型、长度和 CRC 共 5 个字节
+const FRAME_OVERHEAD: usize = 5;
+/// 队列中等待发送的数据最多 64KB，推流断开时不会无限增长
+const MAX_QUEUED_BYTES: usize = 65536;
+
+/// 等待发送的数据帧，UI 线程和 proc handler 写入，渲染线程在编码时取出
+#[derive(Default)]
+pub struct DataQueue {
+    frames: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+}
+
+impl DataQueue {
+    /// 把一段数据编码成帧加入队列，返回错误信息
+    pub fn push(&mut self, data_type: u8, payload: &[u8]) -> Result<(), &'static str> {
+        if data_type == 0 {
+            return Err("数据类型不能是 0");
+        }
+        if payload.len() > u16::MAX as usize {
+            return Err("数据太长");
+        }
+        if self.queued_bytes + payload.len() + FRAME_OVERHEAD > MAX_QUEUED_BYTES {
+            return Err("等待发送的数据太多");
+        }
+        let mut frame = Vec::with_capacity(payload.len() + FRAME_OVERHEAD);
+        frame.push(data_type);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+        self.queued_bytes += frame.len();
+        self.frames.push_back(frame);
+        Ok(())
+    }
+
+    /// 取出能放进 `capacity` 个格子的帧，转换成各位，最后加上结束标记
+    ///
+    /// `capacity` 个格子连一帧都放不下、并且这一帧比 `max_capacity` 还长时，这一帧永远发不出去，丢弃它，返回值的第 2 项是丢弃的帧数
+    pub fn take_bits(&mut self, capacity: usize, max_capacity: usize) -> (Vec<bool>, usize) {
+        let mut bits = Vec::new();
+        let mut dropped = 0;
+        while let Some(frame) = self.frames.front() {
+            let frame_bits = frame.len() * 8;
+            if bits.len() + frame_bits + 8 > capacity {
+                if bits.is_empty() && frame_bits + 8 > max_capacity {
+                    self.queued_bytes -= frame.len();
+                    self.frames.pop_front();
+                    dropped += 1;
+                    continue;
+                }
+                break;
+            }
+            bits.extend(frame.iter().flat_map(|byte| (0..8).map(move |i| byte & (1 << i) != 0)));
+            self.queued_bytes -= frame.len();
+            self.frames.pop_front();
+        }
+        // 结束标记，剩下的格子不够时省略，解码端读到最后一个格子为止
+        bits.extend(std::iter::repeat_n(false, 8.min(capacity - bits.len())));
+        (bits, dropped)
+    }
+}
+
+/// [`DataQueue::take_bits`] 取出的各位中各帧的字节，不包括结束标记，和解码脚本的 readDataFrames 读出的部分相同
+pub fn frame_bytes(bits: &[bool]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = bits.chunks_exact(8).map(|byte| byte.iter().enumerate().map(|(i, bit)| (*bit as u8) << i).sum()).collect();
+    let mut end = 0;
+    while end + 3 <= bytes.len() && bytes[end] != 0 {
+        let len = u16::from_be_bytes([bytes[end + 1], bytes[end + 2]]) as usize;
+        if end + 3 + len + 2 > bytes.len() {
+            break;
+        }
+        end += 3 + len + 2;
+    }
+    bytes.truncate(end);
+    bytes
+}
+
+/// CRC-16/CCITT-FALSE，userscript/audio_decode.user.js 中有相同的实现
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 按照 userscript 的解码方式从各位中读出所有帧
+    fn parse(bits: &[bool]) -> Vec<(u8, Vec<u8>)> {
+        let bytes: Vec<u8> = bits.chunks_exact(8).map(|byte| byte.iter().enumerate().map(|(i, bit)| (*bit as u8) << i).sum()).collect();
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() && bytes[offset] != 0 {
+            let len = u16::from_be_bytes([bytes[offset + 1], bytes[offset + 2]]) as usize;
+            let end = offset + 3 + len;
+            assert_eq!(crc16(&bytes[offset..end]), u16::from_be_bytes([bytes[end], bytes[end + 1]]));
+            frames.push((bytes[offset], bytes[offset + 3..end].to_vec()));
+            offset = end + 2;
+        }
+        frames
+    }
+
+    #[test]
+    fn crc16_matches_reference() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn frames_wait_for_enough_cells() {
+        let mut queue = DataQueue::default();
+        queue.push(DATA_TYPE_SUBTITLE, "你好".as_bytes()).unwrap();
+        queue.push(DATA_TYPE_JSON, br#"{"chapter":2}"#).unwrap();
+        assert!(queue.push(0, b"x").is_err());
+
+        // 第一帧 11 个字节，第二帧 18 个字节，只放得下第一帧
+        let (bits, dropped) = queue.take_bits(8 * 20, 1000);
+        assert_eq!((bits.len(), dropped), (8 * 12, 0));
+        assert_eq!(parse(&bits), vec![(DATA_TYPE_SUBTITLE, "你好".as_bytes().to_vec())]);
+        assert_eq!(frame_bytes(&bits)[3..9], *"你好".as_bytes());
+        assert_eq!(frame_bytes(&bits).len(), 11);
+        let (bits, _) = queue.take_bits(8 * 20, 1000);
+        assert_eq!(parse(&bits), vec![(DATA_TYPE_JSON, br#"{"chapter":2}"#.to_vec())]);
+        // 队列为空时只有结束标记，格子不够时省略
+        assert_eq!(queue.take_bits(100, 1000), (vec![false; 8], 0));
+        assert_eq!(queue.take_bits(3, 1000), (vec![false; 3], 0));
+
+        // 永远放不下的帧被丢弃，不会挡住后面的帧
+        queue.push(DATA_TYPE_MARKER, &[b'x'; 200]).unwrap();
+        queue.push(DATA_TYPE_MARKER, b"ok").unwrap();
+        let (bits, dropped) = queue.take_bits(8 * 100, 8 * 100);
+        assert_eq!(dropped, 1);
+        assert_eq!(parse(&bits), vec![(DATA_TYPE_MARKER, b"ok".to_vec())]);
+        assert_eq!(queue.queued_bytes, 0);
+    }
+}
@@ -0,0 +1,95 @@
+use std::ffi::CStr;
+use std::ptr::{null, null_mut};
+use std::sync::Arc;
+
+use bindings::{audio_data, audio_output_get_channels, obs_add_raw_audio_callback, obs_get_audio, obs_get_source_by_uuid, obs_remove_raw_audio_callback, obs_source_add_audio_capture_callback, obs_source_get_id, obs_source_release, obs_source_remove_audio_capture_callback, obs_source_t};
+
+use crate::audio_renderer::{push_audio, SourceChannel};
+
+/// 声音源列表中表示“最终混音”（输出音轨 1）的值，其他的值都是声音源或 Audio Capture 滤镜的 uuid
+pub const FINAL_MIX: &str = "final_mix\0";
+
+/// 不经过 Audio Capture 滤镜，直接从声音源或最终混音获取声音数据
+///
+/// 每个 DirectCapture 只把数据写入所属 Audio Renderer 的一个声音源通道，drop 时自动注销回调
+pub struct DirectCapture {
+    pub source_channel: Arc<SourceChannel>,
+    /// 持有声音源的引用，为空表示捕获的是 OBS 输出音轨
+    pub source: *mut obs_source_t,
+    /// 捕获的输出音轨序号，0 ~ 5 对应音轨 1 ~ 6，仅在 source 为空时有效
+    pub mix_idx: usize,
+    pub channels: usize,
+}
+
+pub enum AttachResult {
+    Attached(Box<DirectCapture>),
+    /// 这个 uuid 是 Audio Capture 滤镜，数据由滤镜通过 [`crate::audio_renderer::dispatch_audio`] 送过来
+    Filter,
+    /// 声音源还不存在，通常是加载场景时声音源比 Audio Renderer 后创建，需要稍后重试
+    NotFound,
+}
+
+/// 按照 `source_channel` 的 uuid 注册回调，数据写入 `source_channel`
+pub unsafe fn attach(source_channel: Arc<SourceChannel>) -> AttachResult {
+    if source_channel.uuid.to_bytes_with_nul() == FINAL_MIX.as_bytes() {
+        return AttachResult::Attached(attach_mix(source_channel, 0));
+    }
+    let source = obs_get_source_by_uuid(source_channel.uuid.as_ptr());
+    if source.is_null() {
+        return AttachResult::NotFound;
+    }
+    if CStr::from_ptr(obs_source_get_id(source)).to_bytes() == b"audio_capture" {
+        obs_source_release(source);
+        return AttachResult::Filter;
+    }
+    let direct_capture = Box::new(DirectCapture {
+        source_channel,
+        source,
+        mix_idx: 0,
+        channels: audio_output_get_channels(obs_get_audio()),
+    });
+    obs_source_add_audio_capture_callback(source, Some(audio_capture_callback), &*direct_capture as *const DirectCapture as _);
+    AttachResult::Attached(direct_capture)
+}
+
+/// 注册 OBS 输出音轨 `mix_idx` 的回调，数据写入 `source_channel`
+///
+/// 拿到的数据和对应音轨输出给编码器的数据完全一致
+pub unsafe fn attach_mix(source_channel: Arc<SourceChannel>, mix_idx: usize) -> Box<DirectCapture> {
+    let direct_capture = Box::new(DirectCapture {
+        source_channel,
+        source: null_mut(),
+        mix_idx,
+        channels: audio_output_get_channels(obs_get_audio()),
+    });
+    // conversion 为空表示使用 OBS 输出的格式，也就是 float planar
+    obs_add_raw_audio_callback(mix_idx, null(), Some(raw_audio_callback), &*direct_capture as *const DirectCapture as _);
+    direct_capture
+}
+
+// source 是持有引用的 obs_source_t，libobs 的 obs_source_* 函数可以在任意线程调用
+unsafe impl Send for DirectCapture {}
+
+impl Drop for DirectCapture {
+    fn drop(&mut self) {
+        unsafe {
+            // 注销回调时 libobs 会等待正在执行的回调结束，之后才能释放 DirectCapture
+            if self.source.is_null() {
+                obs_remove_raw_audio_callback(self.mix_idx, Some(raw_audio_callback), self as *mut DirectCapture as _);
+            } else {
+                obs_source_remove_audio_capture_callback(self.source, Some(audio_capture_callback), self as *mut DirectCapture as _);
+                obs_source_release(self.source);
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn audio_capture_callback(param: *mut ::std::os::raw::c_void, _source: *mut obs_source_t, audio_data: *const audio_data, muted: bool) {
+    let direct_capture = &*(param as *const DirectCapture);
+    push_audio(&direct_capture.source_channel, &(*audio_data).data, (*audio_data).frames as usize, direct_capture.channels, muted, (*audio_data).timestamp);
+}
+
+unsafe extern "C" fn raw_audio_callback(param: *mut ::std::os::raw::c_void, _mix_idx: usize, audio_data: *mut audio_data) {
+    let direct_capture = &*(param as *const DirectCapture);
+    push_audio(&direct_capture.source_channel, &(*audio_data).data, (*audio_data).frames as usize, direct_capture.channels, false, (*audio_data).timestamp);
+}
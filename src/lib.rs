@@ -1,9 +1,25 @@
+// 导出给 OBS 调用的 unsafe extern "C" 函数由 libobs 保证调用约定；
+// 像素写入保留 `+ 0` 以便和 G、R、A 分量对齐；字符串沿用 "...\0" 的写法
+#![allow(clippy::missing_safety_doc, clippy::identity_op, clippy::manual_c_str_literals)]
+
 use std::ptr::null_mut;
 
 use bindings::{LIBOBS_API_MAJOR_VER, obs_module_t};
 
 mod audio_capture;
 mod audio_renderer;
+mod audio_renderer_filter;
+mod auth;
+mod cipher;
+mod clipboard;
+mod data_channel;
+mod direct_capture;
+mod limiter;
+mod logger;
+mod obs;
+mod registry;
+mod ring_buffer;
+mod stats;
 
 // region OBS_DECLARE_MODULE
 
@@ -30,5 +46,6 @@ pub unsafe extern "C" fn obs_module_ver() -> u32 {
 pub unsafe extern "C" fn obs_module_load() -> bool {
     audio_capture::register();
     audio_renderer::register();
+    audio_renderer_filter::register();
     true
 }
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+
+/// 预读长度（单位：采样），48000Hz 下约 1.3ms
+///
+/// 增益会在峰值到来之前的这段时间内线性下降，避免瞬间压低增益产生的爆音
+pub const LIMITER_LOOKAHEAD: usize = 64;
+
+/// 混音总线上的预读限幅器
+///
+/// 多个声音源混合并且放大之后很容易超过 ±1.0，而 [`crate::audio_renderer`] 编码时会把超出范围的值直接截断成 16 ~ 255 的灰度，相当于硬削波。
+/// 限幅器在编码之前把峰值压到 ceiling 以下，左右声道使用同一个增益，保证声像不偏移。
+pub struct Limiter {
+    /// 峰值上限（线性值）
+    pub ceiling: f32,
+    /// 每个采样的释放系数，增益按照 `gain = target + (gain - target) * release_coefficient` 回到 1.0
+    pub release_coefficient: f32,
+    /// 当前增益，1.0 表示不压缩
+    pub gain: f32,
+    /// 最近一次 [`Limiter::process`] 中最大的增益衰减（单位：dB，非负数）
+    pub gain_reduction_db: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self {
+            ceiling: 1.0,
+            release_coefficient: 0.0,
+            gain: 1.0,
+            gain_reduction_db: 0.0,
+        }
+    }
+
+    /// 设置峰值上限（单位：dBFS）和释放时间（单位：毫秒）
+    pub fn configure(&mut self, ceiling_db: f32, release_ms: f32, sample_rate: u32) {
+        self.ceiling = 10f32.powf(ceiling_db.min(0.0) / 20.0);
+        let release_samples = release_ms.max(1.0) / 1000.0 * sample_rate as f32;
+        self.release_coefficient = (-1.0 / release_samples).exp();
+    }
+
+    /// 对 `audio_buffer` 每个声道的前 `len` 个采样原地限幅
+    ///
+    /// 第 `len` 个采样之后的数据仅用于预读，不会被修改，下一次调用时再处理
+    pub fn process(&mut self, audio_buffer: &mut [VecDeque<f32>; 2], len: usize) {
+        // 每个采样需要的增益，预读部分也一起计算
+        let available = audio_buffer[0].len().min(audio_buffer[1].len());
+        let required_len = (len + LIMITER_LOOKAHEAD).min(available);
+        let required: Vec<f32> = (0..required_len).map(|i| {
+            let peak = audio_buffer[0][i].abs().max(audio_buffer[1][i].abs());
+            if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1.0
+            }
+        }).collect();
+        let mut min_gain = 1.0f32;
+        for i in 0..len.min(required_len) {
+            // 起音：在预读窗口内找到需要增益最低的点，增益在到达这个点之前线性下降
+            let mut attack = 1.0f32;
+            for (k, r) in required[i..required_len.min(i + LIMITER_LOOKAHEAD + 1)].iter().enumerate() {
+                attack = attack.min(r + (1.0 - r) * k as f32 / LIMITER_LOOKAHEAD as f32);
+            }
+            // 释放：增益按指数曲线回升
+            self.gain = if attack < self.gain {
+                attack
+            } else {
+                attack + (self.gain - attack) * self.release_coefficient
+            };
+            min_gain = min_gain.min(self.gain);
+            for channel in audio_buffer.iter_mut() {
+                // 预读不到后续数据时，增益可能来不及降下来，这里再截断一次保证不超过 ceiling
+                channel[i] = (channel[i] * self.gain).clamp(-self.ceiling, self.ceiling);
+            }
+        }
+        self.gain_reduction_db = -20.0 * min_gain.log10();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 左声道在第 `transient` 个采样处有一个 2.0 的峰值，其余采样和右声道都是 0.5
+    fn transient_buffer(len: usize, transient: usize) -> [VecDeque<f32>; 2] {
+        let mut left: VecDeque<f32> = std::iter::repeat_n(0.5, len).collect();
+        left[transient] = 2.0;
+        [left, std::iter::repeat_n(0.5, len).collect()]
+    }
+
+    #[test]
+    fn peaks_stay_below_ceiling() {
+        let mut limiter = Limiter::new();
+        limiter.configure(-1.0, 100.0, 48000);
+        let signal: Vec<f32> = (0..48000).map(|i| 3.0 * (i as f32 * 0.01).sin() * (i as f32 * 0.0003).sin()).collect();
+        let mut audio_buffer: [VecDeque<f32>; 2] = [signal.iter().copied().collect(), signal.iter().map(|v| -v * 0.5).collect()];
+        // 和编码时一样分成多个包处理，每次只输出前 480 个采样
+        let mut output = Vec::new();
+        while !audio_buffer[0].is_empty() {
+            let len = audio_buffer[0].len().min(480);
+            limiter.process(&mut audio_buffer, len);
+            for channel in audio_buffer.iter_mut() {
+                output.extend(channel.drain(..len));
+            }
+        }
+        assert!(output.iter().all(|v| v.abs() <= limiter.ceiling), "{}", output.iter().fold(0.0f32, |a, v| a.max(v.abs())));
+        assert!(limiter.gain_reduction_db > 0.0);
+    }
+
+    #[test]
+    fn gain_drops_within_lookahead() {
+        let mut limiter = Limiter::new();
+        limiter.configure(0.0, 10.0, 48000);
+        let mut audio_buffer = transient_buffer(2000, 1000);
+        limiter.process(&mut audio_buffer, 2000);
+        // 左右声道使用同一个增益，从右声道读出每个采样的增益
+        let gain = |i: usize| audio_buffer[1][i] / 0.5;
+        assert_eq!(gain(1000 - LIMITER_LOOKAHEAD - 1), 1.0);
+        assert!((gain(1000 - LIMITER_LOOKAHEAD / 2) - 0.75).abs() < 1e-6);
+        assert!((gain(1000) - 0.5).abs() < 1e-6);
+        assert!((audio_buffer[0][1000] - 1.0).abs() < 1e-6);
+        assert!((limiter.gain_reduction_db - 6.0206).abs() < 1e-3);
+    }
+
+    #[test]
+    fn gain_recovers_at_release_rate() {
+        let mut limiter = Limiter::new();
+        // 释放时间 10 毫秒，即 480 个采样
+        limiter.configure(0.0, 10.0, 48000);
+        let mut audio_buffer = transient_buffer(3000, 1000);
+        limiter.process(&mut audio_buffer, 3000);
+        let gain = |i: usize| audio_buffer[1][i] / 0.5;
+        for elapsed in [1, 100, 480, 1000] {
+            let expected = 1.0 - 0.5 * (-(elapsed as f32) / 480.0).exp();
+            assert!((gain(1000 + elapsed) - expected).abs() < 1e-4, "{} {} {}", elapsed, gain(1000 + elapsed), expected);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! 带有所属源的日志
+//!
+//! 每条日志都带上模块名和所属源的名称、uuid，同时有多个 Audio Renderer 时可以分清是哪一个。
+//! 渲染线程中每帧都可能出现的问题用 [`Logger::log_limited`] 输出，相同的日志 10 秒内只输出一次，下一次输出时带上省略的条数。
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use bindings::{LOG_ERROR, LOG_INFO, LOG_WARNING};
+
+use crate::obs::{log, SourceRef};
+
+/// 相同的日志最多每 10 秒输出一次
+const LIMIT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Logger {
+    /// 日志开头的模块名，例如 `audio_renderer`
+    module: &'static str,
+    /// 所属的源，设置之前日志中只有模块名
+    source: OnceLock<SourceRef>,
+    state: Mutex<LoggerState>,
+}
+
+#[derive(Default)]
+struct LoggerState {
+    /// 每种限速日志上一次输出的时间和之后省略的条数
+    limits: HashMap<&'static str, (Instant, usize)>,
+    /// 输出和省略的警告、错误的条数
+    warnings: usize,
+    errors: usize,
+}
+
+impl Logger {
+    pub fn new(module: &'static str) -> Self {
+        Self {
+            module,
+            source: OnceLock::new(),
+            state: Mutex::new(LoggerState::default()),
+        }
+    }
+
+    /// 设置所属的源，只有第一次设置有效
+    pub fn set_source(&self, source: SourceRef) {
+        let _ = self.source.set(source);
+    }
+
+    pub fn source(&self) -> Option<SourceRef> {
+        self.source.get().copied()
+    }
+
+    pub fn info(&self, message: &str) {
+        self.log(LOG_INFO, message);
+    }
+
+    pub fn warn(&self, message: &str) {
+        self.log(LOG_WARNING, message);
+    }
+
+    pub fn error(&self, message: &str) {
+        self.log(LOG_ERROR, message);
+    }
+
+    pub fn log(&self, level: i32, message: &str) {
+        self.state.lock().unwrap().count(level);
+        log(level, &self.format(message));
+    }
+
+    /// 相同 `key` 的日志 10 秒内只输出一次，省略的日志仍然计数
+    pub fn log_limited(&self, level: i32, key: &'static str, message: &str) {
+        let suppressed = self.state.lock().unwrap().limit(level, key, Instant::now());
+        match suppressed {
+            Some(0) => log(level, &self.format(message)),
+            Some(suppressed) => log(level, &self.format(&format!("{}（之前省略了 {} 条相同的日志）", message, suppressed))),
+            None => {}
+        }
+    }
+
+    /// 警告和错误的条数，包括省略的
+    pub fn counts(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.warnings, state.errors)
+    }
+
+    fn format(&self, message: &str) -> String {
+        match self.source.get() {
+            Some(source) => format!("[{}] [{} {}] {}", self.module, source.name().to_string_lossy(), source.uuid().to_string_lossy(), message),
+            None => format!("[{}] {}", self.module, message),
+        }
+    }
+}
+
+impl LoggerState {
+    fn count(&mut self, level: i32) {
+        match level {
+            LOG_ERROR => self.errors += 1,
+            LOG_WARNING => self.warnings += 1,
+            _ => {}
+        }
+    }
+
+    /// 需要输出时返回之前省略的条数，需要省略时返回 None
+    fn limit(&mut self, level: i32, key: &'static str, now: Instant) -> Option<usize> {
+        self.count(level);
+        match self.limits.get_mut(key) {
+            Some((last, suppressed)) if now.duration_since(*last) < LIMIT_INTERVAL => {
+                *suppressed += 1;
+                None
+            }
+            Some((last, suppressed)) => {
+                *last = now;
+                Some(std::mem::take(suppressed))
+            }
+            None => {
+                self.limits.insert(key, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limited_logs_are_counted() {
+        let mut state = LoggerState::default();
+        let start = Instant::now();
+        assert_eq!(state.limit(LOG_WARNING, "overrun", start), Some(0));
+        assert_eq!(state.limit(LOG_WARNING, "overrun", start + Duration::from_secs(1)), None);
+        assert_eq!(state.limit(LOG_WARNING, "overrun", start + Duration::from_secs(2)), None);
+        // 不同的日志分别限速
+        assert_eq!(state.limit(LOG_ERROR, "reset", start + Duration::from_secs(2)), Some(0));
+        assert_eq!(state.limit(LOG_WARNING, "overrun", start + Duration::from_secs(10)), Some(2));
+        assert_eq!(state.limit(LOG_WARNING, "overrun", start + Duration::from_secs(11)), None);
+        assert_eq!((state.warnings, state.errors), (5, 1));
+    }
+}
@@ -0,0 +1,605 @@
+//! libobs 的安全封装
+//!
+//! 插件里的视频源和滤镜只需要实现 [`Source`]，`unsafe extern "C"` 的回调、`\0` 结尾的字符串和裸指针转换都集中在这里。
+
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::mem::{size_of, zeroed};
+use std::ptr::{null, null_mut};
+use std::sync::Arc;
+
+use bindings::{bfree, blog, calldata_get_data, calldata_get_string, calldata_t, gs_color_format, gs_color_format_GS_BGRA, gs_color_format_GS_R32F, gs_color_format_GS_RGBA, gs_draw_sprite, GS_DYNAMIC, gs_effect_create, gs_effect_destroy, gs_effect_get_param_by_name, gs_effect_loop, gs_effect_set_float, gs_effect_set_texture, gs_effect_t, gs_matrix_pop, gs_matrix_push, gs_matrix_translate3f, gs_texture_create, gs_texture_destroy, gs_texture_set_image, gs_texture_t, obs_allow_direct_render_OBS_ALLOW_DIRECT_RENDERING, obs_allow_direct_render_OBS_NO_DIRECT_RENDERING, obs_audio_data, obs_base_effect_OBS_EFFECT_DEFAULT, obs_combo_format_OBS_COMBO_FORMAT_INT, obs_combo_format_OBS_COMBO_FORMAT_STRING, obs_combo_type_OBS_COMBO_TYPE_LIST, obs_data_get_bool, obs_data_get_double, obs_data_get_int, obs_data_get_string, obs_data_set_default_bool, obs_data_set_default_double, obs_data_set_default_int, obs_data_set_int, obs_data_t, obs_enter_graphics, obs_filter_get_target, obs_get_base_effect, obs_get_video_frame_time, obs_get_video_info, obs_leave_graphics, obs_properties_add_bool, obs_properties_add_button, obs_properties_add_float_slider, obs_properties_add_int, obs_properties_add_list, obs_properties_add_text, obs_properties_create, obs_properties_get, obs_properties_t, obs_property_int_set_limits, obs_property_list_add_int, obs_property_list_add_string, obs_property_name, obs_property_set_description, obs_property_set_modified_callback, obs_property_set_visible, obs_property_t, obs_property_text_set_info_type, obs_register_source_s, obs_source_get_base_height, obs_source_get_base_width, obs_source_get_name, obs_source_get_output_flags, obs_source_get_proc_handler, obs_source_get_uuid, obs_source_info, obs_source_process_filter_begin, obs_source_process_filter_tech_end, obs_source_t, obs_source_type, obs_source_type_OBS_SOURCE_TYPE_FILTER, obs_source_update_properties, OBS_SOURCE_AUDIO, OBS_SOURCE_VIDEO, obs_text_info_type_OBS_TEXT_INFO_ERROR, obs_text_type_OBS_TEXT_DEFAULT, obs_text_type_OBS_TEXT_INFO, obs_text_type_OBS_TEXT_PASSWORD, obs_video_info, proc_handler_add};
+
+use crate::registry::{borrow_obs_data, from_obs_data, into_obs_data, Registry};
+
+/// 转换成 `\0` 结尾的字符串，中间有 `\0` 时截断
+fn to_cstring(s: &str) -> CString {
+    CString::new(s.split('\0').next().unwrap_or_default()).unwrap_or_default()
+}
+
+/// 输出一行日志，`level` 为 LOG_ERROR、LOG_WARNING、LOG_INFO 等
+pub fn log(level: i32, message: &str) {
+    unsafe { blog(level, "%s\0".as_ptr().cast(), to_cstring(message).as_ptr()) };
+}
+
+/// 一个视频源或滤镜的类型
+///
+/// 实例由 [`Arc`] 持有，OBS 在不同线程调用回调，所以方法都只拿 `&self`，可变状态需要自己加锁。
+pub trait Source: Send + Sync + Sized + 'static {
+    /// 类型 id，必须以 `\0` 结尾
+    const ID: &'static str;
+    /// 显示名称，必须以 `\0` 结尾
+    const NAME: &'static str;
+    const TYPE: obs_source_type;
+    const OUTPUT_FLAGS: u32;
+
+    fn create(settings: &ObsData, source: SourceRef) -> Arc<Self>;
+
+    /// OBS 销毁实例之前调用，用于注销各种回调
+    fn destroy(&self) {}
+
+    fn update(&self, _settings: &ObsData) {}
+
+    fn defaults(_settings: &ObsData) {}
+
+    /// `this` 为 None 表示 OBS 在没有实例的情况下获取属性
+    fn properties(_this: Option<&Self>) -> Properties {
+        Properties::new()
+    }
+
+    fn width(&self) -> u32 {
+        0
+    }
+
+    fn height(&self) -> u32 {
+        0
+    }
+
+    /// 每一帧输出画面调用一次，不管这一帧中源被渲染了几次（预览、投影、多视图），调用时没有持有 graphics 的锁
+    fn tick(&self, _seconds: f32) {}
+
+    /// 调用时 OBS 已经持有 graphics 的锁，OUTPUT_FLAGS 包含 OBS_SOURCE_CUSTOM_DRAW 时 `effect` 为空
+    fn render(&self, _effect: *mut gs_effect_t) {}
+
+    fn filter_audio(&self, _audio: &mut obs_audio_data) {}
+
+    /// 属性中用 [`Properties::add_button`] 添加的按钮被点击，返回 true 时 OBS 重新获取属性
+    fn button_clicked(&self, _name: &str) -> bool {
+        false
+    }
+
+    /// 属性中用 [`Property::set_modified`] 设置了回调的一项被修改，`settings` 已经是修改之后的设置，可以调整其中的其他项
+    ///
+    /// 返回 true 时 OBS 按 `props` 和 `settings` 刷新属性界面，但不会重新获取属性
+    fn property_modified(_props: &mut Properties, _name: &str, _settings: &ObsData) -> bool {
+        false
+    }
+}
+
+pub unsafe fn register_source<S: Source>() {
+    let video = S::OUTPUT_FLAGS & OBS_SOURCE_VIDEO != 0;
+    // 视频滤镜不处理声音，只有声音滤镜需要 filter_audio
+    let audio_filter = S::TYPE == obs_source_type_OBS_SOURCE_TYPE_FILTER && S::OUTPUT_FLAGS & OBS_SOURCE_AUDIO != 0;
+    obs_register_source_s(&obs_source_info {
+        id: S::ID.as_ptr().cast(),
+        type_: S::TYPE,
+        output_flags: S::OUTPUT_FLAGS,
+        get_name: Some(get_name::<S>),
+        create: Some(create::<S>),
+        destroy: Some(destroy::<S>),
+        get_width: if video { Some(get_width::<S>) } else { None },
+        get_height: if video { Some(get_height::<S>) } else { None },
+        get_defaults: Some(get_defaults::<S>),
+        get_properties: Some(get_properties::<S>),
+        update: Some(update::<S>),
+        video_tick: if video { Some(video_tick::<S>) } else { None },
+        video_render: if video { Some(video_render::<S>) } else { None },
+        filter_audio: if audio_filter { Some(filter_audio::<S>) } else { None },
+        ..Default::default()
+    }, size_of::<obs_source_info>());
+}
+
+unsafe extern "C" fn get_name<S: Source>(_type_data: *mut ::std::os::raw::c_void) -> *const ::std::os::raw::c_char {
+    S::NAME.as_ptr().cast()
+}
+
+unsafe extern "C" fn create<S: Source>(settings: *mut obs_data_t, source: *mut obs_source_t) -> *mut ::std::os::raw::c_void {
+    into_obs_data(S::create(&ObsData::from_raw(settings), SourceRef::from_raw(source)))
+}
+
+unsafe extern "C" fn destroy<S: Source>(data: *mut ::std::os::raw::c_void) {
+    let this = from_obs_data::<S>(data);
+    this.destroy();
+}
+
+unsafe extern "C" fn update<S: Source>(data: *mut ::std::os::raw::c_void, settings: *mut obs_data_t) {
+    borrow_obs_data::<S>(data).update(&ObsData::from_raw(settings));
+}
+
+unsafe extern "C" fn get_defaults<S: Source>(settings: *mut obs_data_t) {
+    S::defaults(&ObsData::from_raw(settings));
+}
+
+unsafe extern "C" fn get_properties<S: Source>(data: *mut ::std::os::raw::c_void) -> *mut obs_properties_t {
+    let this = if data.is_null() { None } else { Some(borrow_obs_data::<S>(data)) };
+    S::properties(this).into_raw()
+}
+
+unsafe extern "C" fn get_width<S: Source>(data: *mut ::std::os::raw::c_void) -> u32 {
+    borrow_obs_data::<S>(data).width()
+}
+
+unsafe extern "C" fn get_height<S: Source>(data: *mut ::std::os::raw::c_void) -> u32 {
+    borrow_obs_data::<S>(data).height()
+}
+
+unsafe extern "C" fn video_tick<S: Source>(data: *mut ::std::os::raw::c_void, seconds: f32) {
+    borrow_obs_data::<S>(data).tick(seconds);
+}
+
+unsafe extern "C" fn video_render<S: Source>(data: *mut ::std::os::raw::c_void, effect: *mut gs_effect_t) {
+    borrow_obs_data::<S>(data).render(effect);
+}
+
+unsafe extern "C" fn filter_audio<S: Source>(data: *mut ::std::os::raw::c_void, audio: *mut obs_audio_data) -> *mut obs_audio_data {
+    borrow_obs_data::<S>(data).filter_audio(&mut *audio);
+    audio
+}
+
+unsafe extern "C" fn button_clicked<S: Source>(_props: *mut obs_properties_t, property: *mut obs_property_t, data: *mut ::std::os::raw::c_void) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let name = CStr::from_ptr(obs_property_name(property)).to_string_lossy();
+    borrow_obs_data::<S>(data).button_clicked(&name)
+}
+
+unsafe extern "C" fn property_modified<S: Source>(props: *mut obs_properties_t, property: *mut obs_property_t, settings: *mut obs_data_t) -> bool {
+    let name = CStr::from_ptr(obs_property_name(property)).to_string_lossy();
+    S::property_modified(&mut Properties(props), &name, &ObsData::from_raw(settings))
+}
+
+/// OBS 输出画面（画布）的大小，还没有初始化视频时返回 None
+pub fn canvas_size() -> Option<(u32, u32)> {
+    let mut video_info: obs_video_info = unsafe { zeroed() };
+    if unsafe { obs_get_video_info(&mut video_info) } {
+        Some((video_info.base_width, video_info.base_height))
+    } else {
+        None
+    }
+}
+
+/// OBS 的帧率，返回分子和分母，还没有初始化视频时返回 None
+pub fn video_fps() -> Option<(u32, u32)> {
+    let mut video_info: obs_video_info = unsafe { zeroed() };
+    if unsafe { obs_get_video_info(&mut video_info) } && video_info.fps_num > 0 && video_info.fps_den > 0 {
+        Some((video_info.fps_num, video_info.fps_den))
+    } else {
+        None
+    }
+}
+
+/// 正在渲染的视频帧的时间戳（单位：纳秒），和声音数据的时间戳使用同一个时钟
+pub fn video_frame_time() -> u64 {
+    unsafe { obs_get_video_frame_time() }
+}
+
+/// 借用的 obs_source_t，生命周期由 libobs 保证长于持有它的实例
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SourceRef(*mut obs_source_t);
+
+// libobs 的 obs_source_* 函数可以在任意线程调用
+unsafe impl Send for SourceRef {}
+unsafe impl Sync for SourceRef {}
+
+impl SourceRef {
+    pub unsafe fn from_raw(source: *mut obs_source_t) -> Self {
+        Self(source)
+    }
+
+    pub fn uuid(&self) -> &CStr {
+        unsafe { CStr::from_ptr(obs_source_get_uuid(self.0)) }
+    }
+
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(obs_source_get_name(self.0)) }
+    }
+
+    pub fn output_flags(&self) -> u32 {
+        unsafe { obs_source_get_output_flags(self.0) }
+    }
+
+    /// 让正在显示的属性窗口重新获取属性
+    pub fn update_properties(&self) {
+        unsafe { obs_source_update_properties(self.0) }
+    }
+
+    /// 滤镜所在的源，不是滤镜或者还没有添加到源上时返回 None
+    pub fn filter_target(&self) -> Option<SourceRef> {
+        let target = unsafe { obs_filter_get_target(self.0) };
+        if target.is_null() {
+            None
+        } else {
+            Some(SourceRef(target))
+        }
+    }
+
+    pub fn base_width(&self) -> u32 {
+        unsafe { obs_source_get_base_width(self.0) }
+    }
+
+    pub fn base_height(&self) -> u32 {
+        unsafe { obs_source_get_base_height(self.0) }
+    }
+
+    /// 视频滤镜开始渲染所在的源，返回 false 表示这一帧不能渲染
+    ///
+    /// `allow_direct` 为 false 时所在的源一定会先渲染到 texture，[`end_filter`](Self::end_filter) 的 effect 可以通过 `image` 参数读取
+    pub fn begin_filter(&self, _graphics: &GraphicsGuard, allow_direct: bool) -> bool {
+        let allow_direct = if allow_direct { obs_allow_direct_render_OBS_ALLOW_DIRECT_RENDERING } else { obs_allow_direct_render_OBS_NO_DIRECT_RENDERING };
+        unsafe { obs_source_process_filter_begin(self.0, gs_color_format_GS_RGBA, allow_direct) }
+    }
+
+    /// 用 `effect` 的 `technique` 画出 [`begin_filter`](Self::begin_filter) 渲染的所在的源
+    pub fn end_filter(&self, _graphics: &GraphicsGuard, effect: &Effect, technique: &str, width: u32, height: u32) {
+        unsafe { obs_source_process_filter_tech_end(self.0, effect.effect, width, height, to_cstring(technique).as_ptr()) };
+    }
+
+    /// 视频滤镜先把所在的源原样画出来，返回 false 表示这一帧不能渲染
+    pub fn draw_filter_target(&self, graphics: &GraphicsGuard, width: u32, height: u32) -> bool {
+        if !self.begin_filter(graphics, true) {
+            return false;
+        }
+        self.end_filter(graphics, &Effect::base_default(), "Draw", width, height);
+        true
+    }
+
+    /// 在源的 proc handler 中添加 `decl` 声明的函数，例如 `void send_data(in int type, in string data)`，
+    /// 调用时在 [`Proc::instances`] 中查找 [`Proc::owner`] 是这个源的实例，转发给它的 [`Proc::call`]
+    ///
+    /// libobs 不能移除添加的函数，所以 proc handler 中只保存源的指针，不保存实例的指针。
+    /// 实例 destroy 之后从 [`Proc::instances`] 中移除，之后的调用找不到实例，什么都不做。
+    ///
+    /// # Safety
+    ///
+    /// 这个源必须是 OBS 传给 create 的源，并且调用之前实例已经插入 [`Proc::instances`]
+    pub unsafe fn add_proc<P: Proc>(&self, decl: &str) {
+        proc_handler_add(obs_source_get_proc_handler(self.0), to_cstring(decl).as_ptr(), Some(call_proc::<P>), self.0 as _);
+    }
+}
+
+/// 通过源的 proc handler 调用的函数，见 [`SourceRef::add_proc`]
+pub trait Proc: Send + Sync + Sized + 'static {
+    /// 添加了函数的实例，实例必须在 destroy 中把自己移除
+    fn instances() -> &'static Registry<Self>;
+
+    /// 实例所属的源
+    fn owner(&self) -> Option<SourceRef>;
+
+    fn call(&self, calldata: &CallData);
+}
+
+unsafe extern "C" fn call_proc<P: Proc>(data: *mut ::std::os::raw::c_void, calldata: *mut calldata_t) {
+    let source = SourceRef(data as *mut obs_source_t);
+    // 在遍历之外调用，找到的 Arc 保证调用期间实例不会被释放
+    if let Some(target) = P::instances().find(|item| item.owner() == Some(source)) {
+        target.call(&CallData(calldata));
+    }
+}
+
+/// proc handler 的参数，只在调用期间有效
+pub struct CallData(*mut calldata_t);
+
+impl CallData {
+    /// `in int` 参数，没有时返回 None
+    pub fn get_int(&self, name: &str) -> Option<i64> {
+        let mut value = 0i64;
+        unsafe { calldata_get_data(self.0, to_cstring(name).as_ptr(), &mut value as *mut i64 as _, size_of::<i64>()) }.then_some(value)
+    }
+
+    /// `in string` 参数，没有时返回 None
+    pub fn get_string(&self, name: &str) -> Option<CString> {
+        let mut value = null();
+        if unsafe { calldata_get_string(self.0, to_cstring(name).as_ptr(), &mut value) } && !value.is_null() {
+            Some(unsafe { CStr::from_ptr(value) }.to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+/// 借用的 obs_data_t，只在回调期间有效
+pub struct ObsData<'a> {
+    data: *mut obs_data_t,
+    _marker: PhantomData<&'a obs_data_t>,
+}
+
+impl<'a> ObsData<'a> {
+    pub unsafe fn from_raw(data: *mut obs_data_t) -> Self {
+        Self {
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get_int(&self, name: &str) -> i64 {
+        unsafe { obs_data_get_int(self.data, to_cstring(name).as_ptr()) }
+    }
+
+    pub fn get_double(&self, name: &str) -> f64 {
+        unsafe { obs_data_get_double(self.data, to_cstring(name).as_ptr()) }
+    }
+
+    pub fn get_bool(&self, name: &str) -> bool {
+        unsafe { obs_data_get_bool(self.data, to_cstring(name).as_ptr()) }
+    }
+
+    pub fn get_string(&self, name: &str) -> CString {
+        unsafe { CString::from(CStr::from_ptr(obs_data_get_string(self.data, to_cstring(name).as_ptr()))) }
+    }
+
+    pub fn set_int(&self, name: &str, value: i64) {
+        unsafe { obs_data_set_int(self.data, to_cstring(name).as_ptr(), value) }
+    }
+
+    pub fn set_default_int(&self, name: &str, value: i64) {
+        unsafe { obs_data_set_default_int(self.data, to_cstring(name).as_ptr(), value) }
+    }
+
+    pub fn set_default_double(&self, name: &str, value: f64) {
+        unsafe { obs_data_set_default_double(self.data, to_cstring(name).as_ptr(), value) }
+    }
+
+    pub fn set_default_bool(&self, name: &str, value: bool) {
+        unsafe { obs_data_set_default_bool(self.data, to_cstring(name).as_ptr(), value) }
+    }
+}
+
+/// 属性列表，交给 OBS 之后由 OBS 负责释放
+pub struct Properties(*mut obs_properties_t);
+
+impl Default for Properties {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Properties {
+    pub fn new() -> Self {
+        Self(unsafe { obs_properties_create() })
+    }
+
+    pub fn into_raw(self) -> *mut obs_properties_t {
+        self.0
+    }
+
+    /// 按名称查找已经添加的一项
+    pub fn get(&self, name: &str) -> Option<Property> {
+        let property = unsafe { obs_properties_get(self.0, to_cstring(name).as_ptr()) };
+        (!property.is_null()).then_some(Property(property))
+    }
+
+    pub fn add_int(&mut self, name: &str, description: &str, min: i32, max: i32, step: i32) -> Property {
+        Property(unsafe { obs_properties_add_int(self.0, to_cstring(name).as_ptr(), to_cstring(description).as_ptr(), min, max, step) })
+    }
+
+    pub fn add_float_slider(&mut self, name: &str, description: &str, min: f64, max: f64, step: f64) -> Property {
+        Property(unsafe { obs_properties_add_float_slider(self.0, to_cstring(name).as_ptr(), to_cstring(description).as_ptr(), min, max, step) })
+    }
+
+    pub fn add_bool(&mut self, name: &str, description: &str) -> Property {
+        Property(unsafe { obs_properties_add_bool(self.0, to_cstring(name).as_ptr(), to_cstring(description).as_ptr()) })
+    }
+
+    /// 添加一段只读的说明文字
+    pub fn add_info(&mut self, name: &str, description: &str) -> Property {
+        Property(unsafe { obs_properties_add_text(self.0, to_cstring(name).as_ptr(), to_cstring(description).as_ptr(), obs_text_type_OBS_TEXT_INFO) })
+    }
+
+    /// 添加一个单行文本框
+    pub fn add_text(&mut self, name: &str, description: &str) -> Property {
+        Property(unsafe { obs_properties_add_text(self.0, to_cstring(name).as_ptr(), to_cstring(description).as_ptr(), obs_text_type_OBS_TEXT_DEFAULT) })
+    }
+
+    /// 添加一个输入时不显示内容的文本框
+    pub fn add_password(&mut self, name: &str, description: &str) -> Property {
+        Property(unsafe { obs_properties_add_text(self.0, to_cstring(name).as_ptr(), to_cstring(description).as_ptr(), obs_text_type_OBS_TEXT_PASSWORD) })
+    }
+
+    /// 添加一个按钮，点击时调用实例的 [`Source::button_clicked`]，`S` 必须是获取这个属性列表的类型
+    pub fn add_button<S: Source>(&mut self, name: &str, text: &str) -> Property {
+        Property(unsafe { obs_properties_add_button(self.0, to_cstring(name).as_ptr(), to_cstring(text).as_ptr(), Some(button_clicked::<S>)) })
+    }
+
+    pub fn add_int_list(&mut self, name: &str, description: &str) -> Property {
+        Property(unsafe { obs_properties_add_list(self.0, to_cstring(name).as_ptr(), to_cstring(description).as_ptr(), obs_combo_type_OBS_COMBO_TYPE_LIST, obs_combo_format_OBS_COMBO_FORMAT_INT) })
+    }
+
+    pub fn add_string_list(&mut self, name: &str, description: &str) -> Property {
+        Property(unsafe { obs_properties_add_list(self.0, to_cstring(name).as_ptr(), to_cstring(description).as_ptr(), obs_combo_type_OBS_COMBO_TYPE_LIST, obs_combo_format_OBS_COMBO_FORMAT_STRING) })
+    }
+}
+
+/// 属性列表中的一项，由所属的 [`Properties`] 持有
+#[derive(Clone, Copy)]
+pub struct Property(*mut obs_property_t);
+
+impl Property {
+    pub fn as_ptr(&self) -> *mut obs_property_t {
+        self.0
+    }
+
+    pub fn list_add_int(&self, name: &str, value: i64) {
+        unsafe { obs_property_list_add_int(self.0, to_cstring(name).as_ptr(), value) };
+    }
+
+    pub fn list_add_string(&self, name: &str, value: &CStr) {
+        unsafe { obs_property_list_add_string(self.0, to_cstring(name).as_ptr(), value.as_ptr()) };
+    }
+
+    /// 修改时调用 [`Source::property_modified`]，`S` 必须是获取这个属性列表的类型
+    pub fn set_modified<S: Source>(&self) {
+        unsafe { obs_property_set_modified_callback(self.0, Some(property_modified::<S>)) };
+    }
+
+    pub fn set_description(&self, description: &str) {
+        unsafe { obs_property_set_description(self.0, to_cstring(description).as_ptr()) };
+    }
+
+    pub fn set_visible(&self, visible: bool) {
+        unsafe { obs_property_set_visible(self.0, visible) };
+    }
+
+    /// 把 [`Properties::add_info`] 添加的说明文字显示成错误的样式
+    pub fn set_info_error(&self) {
+        unsafe { obs_property_text_set_info_type(self.0, obs_text_info_type_OBS_TEXT_INFO_ERROR) };
+    }
+
+    pub fn int_set_limits(&self, min: i32, max: i32, step: i32) {
+        unsafe { obs_property_int_set_limits(self.0, min, max, step) };
+    }
+}
+
+/// 进入 graphics 上下文，drop 时离开
+pub struct GraphicsGuard(PhantomData<*mut ()>);
+
+impl GraphicsGuard {
+    pub fn enter() -> Self {
+        unsafe { obs_enter_graphics() };
+        Self(PhantomData)
+    }
+}
+
+impl Drop for GraphicsGuard {
+    fn drop(&mut self) {
+        unsafe { obs_leave_graphics() };
+    }
+}
+
+/// 把坐标原点移动到 (x, y) 之后调用 `f`，之后恢复原来的变换矩阵
+pub fn with_translation(_graphics: &GraphicsGuard, x: f32, y: f32, f: impl FnOnce()) {
+    unsafe {
+        gs_matrix_push();
+        gs_matrix_translate3f(x, y, 0.0);
+    }
+    f();
+    unsafe { gs_matrix_pop() };
+}
+
+/// 动态更新的 texture，drop 时自动销毁
+pub struct Texture {
+    texture: *mut gs_texture_t,
+    width: u32,
+    height: u32,
+    /// 每个像素的字节数
+    pixel_size: u32,
+}
+
+// texture 只在持有 GraphicsGuard 时访问
+unsafe impl Send for Texture {}
+
+impl Texture {
+    /// BGRA 格式的 texture
+    pub fn new(graphics: &GraphicsGuard, width: u32, height: u32) -> Option<Self> {
+        Self::with_format(graphics, width, height, gs_color_format_GS_BGRA, 4)
+    }
+
+    /// 每个像素一个 f32 的 texture，用于把原始数据交给 shader
+    pub fn new_r32f(graphics: &GraphicsGuard, width: u32, height: u32) -> Option<Self> {
+        Self::with_format(graphics, width, height, gs_color_format_GS_R32F, 4)
+    }
+
+    fn with_format(_graphics: &GraphicsGuard, width: u32, height: u32, format: gs_color_format, pixel_size: u32) -> Option<Self> {
+        // GS_DYNAMIC 表示此 texture 会动态更新
+        let texture = unsafe { gs_texture_create(width, height, format, 1, null_mut(), GS_DYNAMIC) };
+        if texture.is_null() {
+            None
+        } else {
+            Some(Self { texture, width, height, pixel_size })
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut gs_texture_t {
+        self.texture
+    }
+
+    /// 更新 texture 数据内容，每行 width * pixel_size 字节
+    pub fn set_image(&self, _graphics: &GraphicsGuard, data: &[u8]) {
+        assert!(data.len() >= (self.width * self.height * self.pixel_size) as usize);
+        unsafe { gs_texture_set_image(self.texture, data.as_ptr(), self.width * self.pixel_size, false) };
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        let _graphics = GraphicsGuard::enter();
+        unsafe { gs_texture_destroy(self.texture) };
+    }
+}
+
+/// 一个 effect（shader），从源码编译的 effect 在 drop 时自动销毁
+pub struct Effect {
+    effect: *mut gs_effect_t,
+    /// libobs 内置的 effect 不需要销毁
+    owned: bool,
+}
+
+// effect 只在持有 GraphicsGuard 时访问
+unsafe impl Send for Effect {}
+
+impl Effect {
+    /// 编译 effect 源码，`name` 仅用于错误信息
+    pub fn new(_graphics: &GraphicsGuard, source: &str, name: &str) -> Result<Self, String> {
+        let mut error: *mut ::std::os::raw::c_char = null_mut();
+        let effect = unsafe { gs_effect_create(to_cstring(source).as_ptr(), to_cstring(name).as_ptr(), &mut error) };
+        let message = if error.is_null() {
+            String::new()
+        } else {
+            let message = unsafe { CStr::from_ptr(error) }.to_string_lossy().into_owned();
+            unsafe { bfree(error as _) };
+            message
+        };
+        if effect.is_null() {
+            Err(message)
+        } else {
+            Ok(Self { effect, owned: true })
+        }
+    }
+
+    /// libobs 内置的 default.effect，直接绘制一张 texture
+    pub fn base_default() -> Self {
+        Self {
+            effect: unsafe { obs_get_base_effect(obs_base_effect_OBS_EFFECT_DEFAULT) },
+            owned: false,
+        }
+    }
+
+    pub fn set_texture(&self, name: &str, texture: &Texture) {
+        unsafe { gs_effect_set_texture(gs_effect_get_param_by_name(self.effect, to_cstring(name).as_ptr()), texture.as_ptr()) };
+    }
+
+    pub fn set_float(&self, name: &str, value: f32) {
+        unsafe { gs_effect_set_float(gs_effect_get_param_by_name(self.effect, to_cstring(name).as_ptr()), value) };
+    }
+
+    /// 使用 technique Draw 绘制一个 `width` x `height` 的矩形，`texture` 为 None 时由 shader 自己决定每个像素的颜色
+    pub fn draw_sprite(&self, _graphics: &GraphicsGuard, texture: Option<&Texture>, width: u32, height: u32) {
+        let texture = texture.map(|v| v.as_ptr()).unwrap_or(null_mut());
+        unsafe {
+            // gs_draw_sprite 时会自动构建矩形的 4 个顶点坐标 buffer、顶点 UV buffer、顶点索引 buffer
+            // gs_draw 时，device_draw 实现中会自动设置 float4x4 ViewProj 参数
+            while gs_effect_loop(self.effect, "Draw\0".as_ptr().cast()) {
+                gs_draw_sprite(texture, 0, width, height);
+            }
+        }
+    }
+}
+
+impl Drop for Effect {
+    fn drop(&mut self) {
+        if self.owned {
+            let _graphics = GraphicsGuard::enter();
+            unsafe { gs_effect_destroy(self.effect) };
+        }
+    }
+}
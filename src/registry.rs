@@ -0,0 +1,115 @@
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwapOption;
+
+/// 线程安全的实例列表
+///
+/// OBS 拿到的 `data` 指针是 [`Arc::into_raw`] 得到的，列表里再持有一份 Arc，
+/// 所以即使 destroy 和音频线程的 dispatch 同时发生，正在被遍历的实例也不会被释放。
+///
+/// 列表按 RCU 的方式更新：修改时复制一份新的列表再整体替换，遍历时读取当前列表的快照，
+/// 所以音频线程遍历时不加锁，也不会等待 UI 线程。
+///
+/// 加锁顺序：`writer` 总是最后一个锁，持有实例内部的 Mutex 时可以修改列表，修改列表时不会再锁其他 Mutex。
+/// 插入和移除需要和实例内部的状态在同一个临界区内完成，否则另一个线程可能在两者之间移除刚插入的实例。
+pub struct Registry<T> {
+    items: ArcSwapOption<Vec<Arc<T>>>,
+    /// 串行化修改，避免两个线程同时复制列表时丢失其中一个修改
+    writer: Mutex<()>,
+}
+
+impl<T> Registry<T> {
+    pub const fn new() -> Self {
+        Self {
+            items: ArcSwapOption::const_empty(),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn insert(&self, item: Arc<T>) {
+        self.modify(|items| items.push(item));
+    }
+
+    pub fn remove(&self, item: *const T) {
+        self.modify(|items| items.retain(|v| Arc::as_ptr(v) != item));
+    }
+
+    fn modify(&self, f: impl FnOnce(&mut Vec<Arc<T>>)) {
+        let _writer = self.writer.lock().unwrap();
+        let mut items = self.items.load().as_deref().cloned().unwrap_or_default();
+        f(&mut items);
+        self.items.store(Some(Arc::new(items)));
+    }
+
+    /// 在当前列表的快照中查找第一个满足 `f` 的实例
+    pub fn find(&self, f: impl Fn(&T) -> bool) -> Option<Arc<T>> {
+        self.items.load().as_deref()?.iter().find(|item| f(item)).cloned()
+    }
+
+    /// 不加锁地遍历当前列表的快照，遍历期间插入或移除的实例不影响这一次遍历
+    pub fn for_each(&self, mut f: impl FnMut(&Arc<T>)) {
+        if let Some(items) = self.items.load().as_deref() {
+            for item in items {
+                f(item);
+            }
+        }
+    }
+}
+
+/// 把 Arc 交给 OBS 作为 `data` 指针，在 destroy 中用 [`from_obs_data`] 取回
+pub fn into_obs_data<T>(item: Arc<T>) -> *mut ::std::os::raw::c_void {
+    Arc::into_raw(item) as _
+}
+
+/// 在 OBS 的回调中借用 `data` 指针指向的实例
+pub unsafe fn borrow_obs_data<'a, T>(data: *mut ::std::os::raw::c_void) -> &'a T {
+    &*(data as *const T)
+}
+
+/// 在 destroy 中取回 [`into_obs_data`] 交出去的 Arc
+pub unsafe fn from_obs_data<T>(data: *mut ::std::os::raw::c_void) -> Arc<T> {
+    Arc::from_raw(data as *const T)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::*;
+
+    struct Item {
+        counter: Mutex<usize>,
+    }
+
+    /// 模拟 UI 线程反复创建、销毁实例，同时音频线程不停地向所有实例写数据
+    #[test]
+    fn concurrent_insert_remove_and_dispatch() {
+        static REGISTRY: Registry<Item> = Registry::new();
+        let dispatchers: Vec<_> = (0..4).map(|_| thread::spawn(|| {
+            for _ in 0..10000 {
+                REGISTRY.for_each(|item| {
+                    *item.counter.lock().unwrap() += 1;
+                });
+            }
+        })).collect();
+        let creators: Vec<_> = (0..4).map(|_| thread::spawn(|| {
+            for _ in 0..1000 {
+                let item = Arc::new(Item { counter: Mutex::new(0) });
+                REGISTRY.insert(item.clone());
+                let data = into_obs_data(item);
+                *unsafe { borrow_obs_data::<Item>(data) }.counter.lock().unwrap() += 1;
+                REGISTRY.remove(data as *const Item);
+                // 移除之后的快照中不再有这个实例，之前的快照中仍然持有 Arc，所以实例不会在遍历中被释放
+                REGISTRY.for_each(|item| assert_ne!(Arc::as_ptr(item), data as *const Item));
+                drop(unsafe { from_obs_data::<Item>(data) });
+            }
+        })).collect();
+        for handle in dispatchers.into_iter().chain(creators) {
+            handle.join().unwrap();
+        }
+        let mut remaining = 0;
+        REGISTRY.for_each(|_| remaining += 1);
+        assert_eq!(remaining, 0);
+    }
+}
@@ -0,0 +1,86 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// 单生产者单消费者的无锁环形缓冲区
+///
+/// 生产者是 OBS 的音频线程，消费者是渲染线程。两边都只做原子读写，不会互相等待，
+/// 缓冲区满时生产者直接丢弃写不下的数据并计数，而不是等待消费者。
+pub struct SpscRing<T> {
+    buffer: Box<[UnsafeCell<T>]>,
+    /// 生产者累计写入的数量，只有生产者修改
+    head: AtomicUsize,
+    /// 消费者累计读出的数量，只有消费者修改
+    tail: AtomicUsize,
+    /// 缓冲区满时丢弃的数量
+    overrun: AtomicUsize,
+}
+
+// 同一时刻只有一个线程写 head 之后的位置，只有一个线程读 tail 到 head 之间的位置，两者不重叠
+unsafe impl<T: Send> Sync for SpscRing<T> {}
+
+impl<T: Copy + Default> SpscRing<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: (0..capacity).map(|_| UnsafeCell::new(T::default())).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overrun: AtomicUsize::new(0),
+        }
+    }
+
+    /// 写入 `len` 个数据，第 i 个数据为 `f(i)`，返回实际写入的数量
+    ///
+    /// 只能由生产者线程调用
+    pub fn push_with(&self, len: usize, f: impl Fn(usize) -> T) -> usize {
+        let capacity = self.buffer.len();
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let n = len.min(capacity - (head - tail));
+        for i in 0..n {
+            unsafe {
+                *self.buffer[(head + i) % capacity].get() = f(i);
+            }
+        }
+        self.head.store(head + n, Ordering::Release);
+        if n < len {
+            self.overrun.fetch_add(len - n, Ordering::Relaxed);
+        }
+        n
+    }
+
+    /// 读出当前所有的数据，返回读出的数量
+    ///
+    /// 只能由消费者线程调用
+    pub fn pop_all(&self, mut f: impl FnMut(T)) -> usize {
+        let capacity = self.buffer.len();
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        for i in tail..head {
+            f(unsafe { *self.buffer[i % capacity].get() });
+        }
+        self.tail.store(head, Ordering::Release);
+        head - tail
+    }
+
+    /// 累计丢弃的数量
+    pub fn overrun(&self) -> usize {
+        self.overrun.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_around_and_overrun() {
+        let ring = SpscRing::<u32>::new(4);
+        assert_eq!(ring.push_with(3, |i| i as u32), 3);
+        let mut out = Vec::new();
+        assert_eq!(ring.pop_all(|v| out.push(v)), 3);
+        assert_eq!(ring.push_with(6, |i| 10 + i as u32), 4);
+        assert_eq!(ring.overrun(), 2);
+        ring.pop_all(|v| out.push(v));
+        assert_eq!(out, [0, 1, 2, 10, 11, 12, 13]);
+    }
+}
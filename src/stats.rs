@@ -0,0 +1,106 @@
+//! 实时统计
+//!
+//! 渲染线程在每次编码时更新，属性中的“实时统计”每秒刷新一次显示这里的内容。
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// 统计每秒包数的时间窗口，同时也是刷新属性的间隔
+const WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct Stats {
+    /// 最近 1 秒内每个包的编码时间
+    packet_times: VecDeque<Instant>,
+    /// 上一次刷新属性的时间
+    last_refresh: Option<Instant>,
+    /// 每个声音源槽位已经缓冲、还没有输出的采样数，None 表示没有选择声音源
+    pub source_fill: Vec<Option<usize>>,
+    /// 上一个包左右声道的放大倍数
+    pub amplifiers: [f32; 2],
+    /// 放大之后超出 -1.0 ~ 1.0 的采样数
+    pub clipped_samples: usize,
+    /// 上一个包中限幅器最大的增益衰减（单位：dB），没有启用限幅器时为 0
+    pub limiter_gain_reduction_db: f32,
+    /// 编码区域放不下而丢弃的采样数
+    pub truncated_samples: usize,
+    /// 渲染线程来不及读取，音频线程丢弃的采样数
+    pub overrun_samples: usize,
+    /// 缓冲过长被清空的次数
+    pub resets: usize,
+    /// 上一个包的第一个采样到输出这一帧画面经过的时间（单位：纳秒），没有时间戳时为 None
+    pub latency: Option<u64>,
+}
+
+impl Stats {
+    pub fn record_packet(&mut self, now: Instant) {
+        self.packet_times.push_back(now);
+        while self.packet_times.front().is_some_and(|t| now.duration_since(*t) > WINDOW) {
+            self.packet_times.pop_front();
+        }
+    }
+
+    /// 最近 1 秒内的包数
+    pub fn packets_per_second(&self, now: Instant) -> usize {
+        self.packet_times.iter().filter(|t| now.duration_since(**t) <= WINDOW).count()
+    }
+
+    /// 距离上一次刷新属性超过 1 秒时返回 true，并记下这一次的时间
+    pub fn refresh_due(&mut self, now: Instant) -> bool {
+        if self.last_refresh.is_some_and(|t| now.duration_since(t) < WINDOW) {
+            return false;
+        }
+        self.last_refresh = Some(now);
+        true
+    }
+
+    /// 属性中显示的文字，`sample_rate` 用于把采样数换算成毫秒
+    pub fn describe(&self, now: Instant, sample_rate: u32) -> String {
+        let ms = |samples: usize| samples as u64 * 1000 / sample_rate.max(1) as u64;
+        let mut text = String::from("实时统计：\n");
+        let _ = writeln!(text, "每秒包数：{}", self.packets_per_second(now));
+        match self.latency {
+            Some(latency) => {
+                let _ = writeln!(text, "当前延迟：{} 毫秒（从采集声音到输出画面）", latency / 1_000_000);
+            }
+            None => text.push_str("当前延迟：未知\n"),
+        }
+        let fill: Vec<String> = self.source_fill.iter().enumerate().filter_map(|(i, fill)| {
+            fill.map(|fill| format!("声音源{} {} 毫秒", i + 1, ms(fill)))
+        }).collect();
+        let _ = writeln!(text, "缓冲：{}", if fill.is_empty() { "没有声音源".to_string() } else { fill.join("，") });
+        let _ = writeln!(text, "放大倍数：左声道 {}，右声道 {}", self.amplifiers[0], self.amplifiers[1]);
+        let _ = writeln!(text, "削波的采样：{}", self.clipped_samples);
+        let _ = writeln!(text, "限幅器增益衰减：{:.1} dB", self.limiter_gain_reduction_db);
+        let _ = write!(text, "丢弃的采样：编码区域放不下 {}，来不及读取 {}；清空缓冲 {} 次", self.truncated_samples, self.overrun_samples, self.resets);
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_per_second_uses_last_window() {
+        let start = Instant::now();
+        let mut stats = Stats::default();
+        for i in 0..30 {
+            stats.record_packet(start + Duration::from_millis(i * 50));
+        }
+        // 最后一个包在 1450ms，1 秒内是 450ms 之后的 21 个包
+        let now = start + Duration::from_millis(1450);
+        assert_eq!(stats.packets_per_second(now), 21);
+        assert_eq!(stats.packets_per_second(now + Duration::from_secs(2)), 0);
+
+        assert!(stats.refresh_due(now));
+        assert!(!stats.refresh_due(now + Duration::from_millis(500)));
+        assert!(stats.refresh_due(now + Duration::from_millis(1000)));
+
+        stats.source_fill = vec![Some(2400), None];
+        assert!(stats.describe(now, 48000).contains("缓冲：声音源1 50 毫秒\n"));
+        stats.limiter_gain_reduction_db = 3.52;
+        assert!(stats.describe(now, 48000).contains("限幅器增益衰减：3.5 dB\n"));
+    }
+}
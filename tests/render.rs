@@ -0,0 +1,781 @@
+//! 通过 obs-shim 模拟 libobs，在没有 OBS 的环境下测试从声音数据到 texture 的完整流程
+
+use std::ops::Range;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use bindings::{LOG_ERROR, obs_text_info_type_OBS_TEXT_INFO_ERROR, obs_source_t, OBS_SOURCE_AUDIO, OBS_SOURCE_VIDEO};
+use obs_audio_renderer::obs_module_load;
+use obs_shim::{call_proc, CallData, create_source, Data, EffectParam, filter_draws, FilterDraw, Instance, last_effect_param, module_session, properties_updates, push_mix_audio, push_source_audio, push_source_audio_at, set_filter_target, set_video_frame_time, set_source_size, source_info, sprite_draws, SpriteDraw, take_properties, texture_uploads, TextureUpload};
+
+const WIDTH: usize = 32;
+const HEIGHT: usize = 1072;
+const CELL: usize = 2;
+
+/// 默认设置的 Audio Renderer，关闭限幅器以便精确比较数据
+fn renderer_settings() -> Box<Data> {
+    let settings = Data::new();
+    unsafe { source_info("audio_renderer").get_defaults.unwrap()(settings.as_ptr()) };
+    settings.set_bool("limiter_enabled", false);
+    settings
+}
+
+/// 声道布局，和 src/audio_renderer.rs 中的 LAYOUT_* 相同
+const LAYOUT_VERTICAL: i64 = 0;
+const LAYOUT_HORIZONTAL: i64 = 1;
+const LAYOUT_INTERLEAVED: i64 = 2;
+
+/// `channel` 声道第 `index` 个格子的平均灰度，格子按 `layout` 排列，`channel` 为 0 表示左声道
+fn cell_gray(upload: &TextureUpload, layout: i64, channel: usize, index: usize) -> f32 {
+    let width = upload.width as usize;
+    let columns = width / CELL;
+    let rows = upload.height as usize / CELL;
+    let (column, row) = match layout {
+        LAYOUT_HORIZONTAL => (index % (columns / 2) + channel * columns / 2, index / (columns / 2)),
+        LAYOUT_INTERLEAVED => (index % columns, index / columns * 2 + channel),
+        _ => (index % columns, index / columns + channel * rows / 2),
+    };
+    let (x0, y0) = (column * CELL, row * CELL);
+    let mut sum = 0.0;
+    for y in y0..y0 + CELL {
+        for x in x0..x0 + CELL {
+            let i = (y * width + x) * 4;
+            // B 和 R 相同，G 是另一个抖动值
+            sum += (upload.data[i] as f32 + upload.data[i + 1] as f32) / 2.0;
+        }
+    }
+    sum / (CELL * CELL) as f32
+}
+
+/// 头部从第 `offset` 个格子开始的 `count` 位
+fn header_bits(upload: &TextureUpload, layout: i64, channel: usize, offset: usize, count: usize) -> u32 {
+    (0..count).map(|i| if cell_gray(upload, layout, channel, offset + i) > 127.0 { 1 << i } else { 0 }).sum()
+}
+
+/// 按照 userscript 的解码方式还原第 `index` 个采样
+fn decode_sample(upload: &TextureUpload, layout: i64, channel: usize, index: usize) -> f32 {
+    let amplifier = (header_bits(upload, layout, channel, 4, 4) + 1) as f32;
+    ((cell_gray(upload, layout, channel, 10 + index) - 16.0) / 120.0 - 1.0) / amplifier
+}
+
+fn test_signal(len: usize, offset: usize) -> (Vec<f32>, Vec<f32>) {
+    let left: Vec<f32> = (offset..offset + len).map(|i| 0.4 * (i as f32 * 0.05).sin()).collect();
+    let right = left.iter().map(|v| -v).collect();
+    (left, right)
+}
+
+fn assert_no_errors() {
+    let errors: Vec<_> = obs_shim::logs().into_iter().filter(|(level, _)| *level == LOG_ERROR).collect();
+    assert!(errors.is_empty(), "{:?}", errors);
+}
+
+/// 创建一个麦克风和以它为声音源1 的 Audio Renderer，返回 Audio Renderer 和麦克风
+fn new_renderer(settings: &Data) -> (Instance, *mut obs_source_t) {
+    let mic = create_source("pulse_input_capture", "Mic", "mic-uuid", OBS_SOURCE_AUDIO);
+    settings.set_string("source0", "mic-uuid");
+    let renderer_source = create_source("audio_renderer", "Audio Renderer", "renderer-uuid", OBS_SOURCE_VIDEO);
+    (unsafe { Instance::create("audio_renderer", settings, renderer_source) }, mic)
+}
+
+/// 向 `source` 提交 `batches` 中的每一批测试信号，每批 1024 个采样，第 n 批从第 n * 1024 个采样开始
+fn push_batches(source: *mut obs_source_t, batches: Range<usize>) {
+    for batch in batches {
+        let (left, right) = test_signal(1024, batch * 1024);
+        unsafe { push_source_audio(source, &[&left, &right]) };
+    }
+}
+
+/// 属性中显示的观看端配置字符串
+fn viewer_config(instance: &Instance) -> String {
+    let properties = unsafe { take_properties(instance.info.get_properties.unwrap()(instance.data)) };
+    properties.get("viewer_config").unwrap().description.clone()
+}
+
+#[test]
+fn audio_capture_filter_to_texture() {
+    let _session = module_session(obs_module_load);
+    let filter_source = create_source("audio_capture", "Audio Capture", "filter-uuid", OBS_SOURCE_AUDIO);
+    let filter = unsafe { Instance::create("audio_capture", &Data::new(), filter_source) };
+    let settings = renderer_settings();
+    settings.set_string("source0", "filter-uuid");
+    let renderer_source = create_source("audio_renderer", "Audio Renderer", "renderer-uuid", OBS_SOURCE_VIDEO);
+    let renderer = unsafe { Instance::create("audio_renderer", &settings, renderer_source) };
+
+    // 3 批共 3072 个采样，超过默认的 flush_len 2400
+    for batch in 0..3 {
+        let (left, right) = test_signal(1024, batch * 1024);
+        filter.filter_audio(&[&left, &right]);
+    }
+    renderer.render();
+
+    let uploads = texture_uploads();
+    assert_eq!(uploads.len(), 1);
+    let upload = &uploads[0];
+    assert_eq!((upload.width as usize, upload.height as usize), (WIDTH, HEIGHT));
+    assert_eq!(upload.linesize as usize, WIDTH * 4);
+    // 包序号从 0 开始，峰值 0.4 对应放大 2 倍
+    assert_eq!(header_bits(upload, LAYOUT_VERTICAL, 0, 0, 4), 0);
+    assert_eq!(header_bits(upload, LAYOUT_VERTICAL, 0, 4, 4), 1);
+    assert_eq!(header_bits(upload, LAYOUT_VERTICAL, 0, 8, 2), LAYOUT_VERTICAL as u32);
+    let (left, right) = test_signal(3072, 0);
+    for i in 0..3072 {
+        assert!((decode_sample(upload, LAYOUT_VERTICAL, 0, i) - left[i]).abs() < 0.01, "left sample {}", i);
+        assert!((decode_sample(upload, LAYOUT_VERTICAL, 1, i) - right[i]).abs() < 0.01, "right sample {}", i);
+    }
+    assert_no_errors();
+
+    // 数据不够 flush_len 时不更新 texture
+    let (left, right) = test_signal(1024, 3072);
+    filter.filter_audio(&[&left, &right]);
+    renderer.render();
+    assert_eq!(texture_uploads().len(), 1);
+}
+
+#[test]
+fn direct_source_capture_to_texture() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    let (renderer, mic) = new_renderer(&settings);
+
+    push_batches(mic, 0..3);
+    renderer.render();
+
+    let uploads = texture_uploads();
+    assert_eq!(uploads.len(), 1);
+    let (left, _) = test_signal(3072, 0);
+    for (i, v) in left.iter().enumerate() {
+        assert!((decode_sample(&uploads[0], LAYOUT_VERTICAL, 0, i) - v).abs() < 0.01, "left sample {}", i);
+    }
+    assert_no_errors();
+
+    // destroy 之后回调已经注销，再提交数据不会有任何效果
+    drop(renderer);
+    let (left, right) = test_signal(1024, 0);
+    unsafe { push_source_audio(mic, &[&left, &right]) };
+}
+
+#[test]
+fn mix_track_capture_to_texture() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_int("capture_mode", 1);
+    settings.set_int("mix_track", 2);
+    let renderer_source = create_source("audio_renderer", "Audio Renderer", "renderer-uuid", OBS_SOURCE_VIDEO);
+    let renderer = unsafe { Instance::create("audio_renderer", &settings, renderer_source) };
+
+    for batch in 0..3 {
+        let (left, right) = test_signal(1024, batch * 1024);
+        // 音轨 1 的数据不应该被使用
+        push_mix_audio(0, &[&right, &left]);
+        push_mix_audio(1, &[&left, &right]);
+    }
+    renderer.render();
+
+    let uploads = texture_uploads();
+    assert_eq!(uploads.len(), 1);
+    let (left, right) = test_signal(3072, 0);
+    for i in 0..3072 {
+        assert!((decode_sample(&uploads[0], LAYOUT_VERTICAL, 0, i) - left[i]).abs() < 0.01, "left sample {}", i);
+        assert!((decode_sample(&uploads[0], LAYOUT_VERTICAL, 1, i) - right[i]).abs() < 0.01, "right sample {}", i);
+    }
+    assert_no_errors();
+}
+
+#[test]
+fn channel_layouts_recorded_in_header() {
+    let _session = module_session(obs_module_load);
+    let mic = create_source("pulse_input_capture", "Mic", "mic-uuid", OBS_SOURCE_AUDIO);
+    for layout in [LAYOUT_HORIZONTAL, LAYOUT_INTERLEAVED] {
+        let settings = renderer_settings();
+        settings.set_string("source0", "mic-uuid");
+        // 192x16 的横条，上下分布时每个声道只有 4 行格子
+        settings.set_int("width", 192);
+        settings.set_int("height", 16);
+        settings.set_int("flush_len", 300);
+        settings.set_int("layout", layout);
+        let renderer_source = create_source("audio_renderer", "Audio Renderer", "renderer-uuid", OBS_SOURCE_VIDEO);
+        let renderer = unsafe { Instance::create("audio_renderer", &settings, renderer_source) };
+        for batch in 0..3 {
+            let (left, right) = test_signal(100, batch * 100);
+            unsafe { push_source_audio(mic, &[&left, &right]) };
+        }
+        renderer.render();
+
+        let upload = texture_uploads().pop().unwrap();
+        assert_eq!((upload.width, upload.height), (192, 16));
+        // 左声道的头部在所有布局中都在第一行的开头
+        assert_eq!(header_bits(&upload, LAYOUT_VERTICAL, 0, 8, 2), layout as u32);
+        assert_eq!(header_bits(&upload, layout, 1, 8, 2), layout as u32);
+        let (left, right) = test_signal(300, 0);
+        for i in 0..300 {
+            assert!((decode_sample(&upload, layout, 0, i) - left[i]).abs() < 0.01, "layout {} left sample {}", layout, i);
+            assert!((decode_sample(&upload, layout, 1, i) - right[i]).abs() < 0.01, "layout {} right sample {}", layout, i);
+        }
+    }
+    assert_no_errors();
+}
+
+#[test]
+fn passphrase_encrypts_samples() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_string("passphrase", "correct horse");
+    let (renderer, mic) = new_renderer(&settings);
+
+    push_batches(mic, 0..3);
+    renderer.render();
+
+    let uploads = texture_uploads();
+    assert_eq!(uploads.len(), 1);
+    // 两个声道的头部之后都是相同的盐和完整的包序号，之后才是采样
+    let salt = header_bits(&uploads[0], LAYOUT_VERTICAL, 0, 10, 32);
+    assert_eq!(header_bits(&uploads[0], LAYOUT_VERTICAL, 1, 10, 32), salt);
+    assert_eq!(header_bits(&uploads[0], LAYOUT_VERTICAL, 0, 42, 32), 0);
+    // 不知道密码时解出来的是噪声
+    let (left, _) = test_signal(3072, 0);
+    let close = (0..3072).filter(|i| (decode_sample(&uploads[0], LAYOUT_VERTICAL, 0, 64 + i) - left[*i]).abs() < 0.01).count();
+    assert!(close < 300, "{}", close);
+    // 采样数不变，之后仍然是黑色的结束标记
+    assert!(cell_gray(&uploads[0], LAYOUT_VERTICAL, 0, 10 + 64 + 3071) >= 16.0);
+    assert_eq!(cell_gray(&uploads[0], LAYOUT_VERTICAL, 0, 10 + 64 + 3072), 0.0);
+
+    // 配置字符串只标记加密，不包含密码
+    let viewer_config = viewer_config(&renderer);
+    assert!(viewer_config.contains("0,0,32,1072,2,2,2"), "{}", viewer_config);
+    assert!(!viewer_config.contains("correct horse"));
+    assert_no_errors();
+}
+
+#[test]
+fn auth_secret_adds_tag_to_header() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_int("encoder", 1);
+    settings.set_string("passphrase", "correct horse");
+    settings.set_string("auth_secret", "stream secret");
+    let (renderer, mic) = new_renderer(&settings);
+
+    let mut tags = Vec::new();
+    for packet in 0..2 {
+        push_batches(mic, packet * 3..packet * 3 + 3);
+        renderer.render();
+
+        // 每行先是盐、完整的包序号和标签各 32 位，之后是采样
+        let upload = texture_uploads().pop().unwrap();
+        let row_len = upload.width as usize;
+        let samples: Vec<f32> = upload.data.chunks(4).map(|v| f32::from_ne_bytes(v.try_into().unwrap())).collect();
+        let bits = |row: usize, offset: usize| (0..32).map(|i| (samples[row * row_len + offset + i] as u32) << i).sum::<u32>();
+        assert!(samples[..96].iter().all(|v| *v == 0.0 || *v == 1.0));
+        assert_eq!(&samples[..96], &samples[row_len..row_len + 96]);
+        assert_eq!(bits(0, 32), packet as u32);
+        tags.push(bits(0, 64));
+        assert_eq!(last_effect_param("extra_header_count"), Some(EffectParam::Float(96.0)));
+        assert_eq!(last_effect_param("sample_count"), Some(EffectParam::Float(3072.0)));
+        // 采样和结束标记之后是每 16 个采样 3 位的辅助位，之后是只有结束标记的数据通道
+        let helpers_start = 96 + 3072 + 1;
+        assert!(samples[helpers_start..helpers_start + 576].iter().all(|v| *v == 0.0 || *v == 1.0));
+        assert!(samples[helpers_start..helpers_start + 576].contains(&1.0));
+        assert_eq!(last_effect_param("data_cell_count"), Some(EffectParam::Float(576.0 + 8.0)));
+    }
+    assert_ne!(tags[0], tags[1]);
+
+    let viewer_config = viewer_config(&renderer);
+    assert!(viewer_config.contains("0,0,32,1072,2,2,6"), "{}", viewer_config);
+    assert!(!viewer_config.contains("stream secret"));
+    assert_no_errors();
+}
+
+#[test]
+fn timestamps_recorded_in_header() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_bool("embed_timestamps", true);
+    let (renderer, mic) = new_renderer(&settings);
+
+    // 每批 1024 个采样，相隔 1024 / 48000 秒
+    let start = 5_000_000_000u64;
+    let batch_time = |batch: usize| start + batch as u64 * 1024 * 1_000_000_000 / 48000;
+    for packet in 0..2 {
+        for batch in 0..3 {
+            let (left, right) = test_signal(1024, (packet * 3 + batch) * 1024);
+            unsafe { push_source_audio_at(mic, &[&left, &right], batch_time(packet * 3 + batch)) };
+        }
+        let video_time = 7_000_000_000 + packet as u64 * 16_666_667;
+        set_video_frame_time(video_time);
+        renderer.render();
+
+        // 头部之后是画面和第一个采样的时间戳，各 64 位，低 32 位在前
+        let upload = texture_uploads().pop().unwrap();
+        let timestamp = |offset: usize| header_bits(&upload, LAYOUT_VERTICAL, 0, offset, 32) as u64 | (header_bits(&upload, LAYOUT_VERTICAL, 0, offset + 32, 32) as u64) << 32;
+        for channel in 0..2 {
+            assert_eq!(header_bits(&upload, LAYOUT_VERTICAL, channel, 10, 32), video_time as u32);
+        }
+        assert_eq!(timestamp(10), video_time);
+        assert_eq!(timestamp(74), batch_time(packet * 3));
+    }
+
+    let viewer_config = viewer_config(&renderer);
+    assert!(viewer_config.contains("0,0,32,1072,2,2,8"), "{}", viewer_config);
+    assert_no_errors();
+}
+
+#[test]
+fn target_latency_flushes_whole_frames() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_int("encoder", 1);
+    // 测试环境是 60fps，50 毫秒是 3 帧 2400 个采样
+    settings.set_int("target_latency", 50);
+    let (renderer, mic) = new_renderer(&settings);
+
+    // 每个包正好 2400 个采样，多出来的留给下一个包
+    for (batches, offset) in [(3, 0), (2, 2400)] {
+        for _ in 0..batches {
+            let (left, right) = test_signal(1024, 0);
+            unsafe { push_source_audio(mic, &[&left, &right]) };
+        }
+        renderer.render();
+        assert_eq!(last_effect_param("sample_count"), Some(EffectParam::Float(2400.0)), "offset {}", offset);
+    }
+    assert_eq!(texture_uploads().len(), 2);
+
+    // 200 毫秒需要 9600 个采样，编码区域只能放下 5 帧 4000 个采样
+    settings.set_int("target_latency", 200);
+    renderer.update(&settings);
+    assert!(obs_shim::logs().iter().any(|(_, message)| message.contains("每 5 帧输出 4000 个采样")));
+    assert_no_errors();
+}
+
+#[test]
+fn packets_advance_once_per_frame() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_int("encoder", 1);
+    let (renderer, mic) = new_renderer(&settings);
+
+    for packet in 0..2 {
+        push_batches(mic, packet * 3..packet * 3 + 3);
+        renderer.render();
+        assert_eq!(last_effect_param("packet_index"), Some(EffectParam::Float(packet as f32)));
+        // 同一帧再渲染两次，不会产生新的包，也不会重复上传
+        for _ in 0..2 {
+            renderer.render_again();
+            assert_eq!(last_effect_param("packet_index"), Some(EffectParam::Float(packet as f32)));
+        }
+        assert_eq!(texture_uploads().len(), packet + 1);
+    }
+    assert_no_errors();
+}
+
+#[test]
+fn stalled_source_logged_once() {
+    let _session = module_session(obs_module_load);
+    let desktop = create_source("pulse_output_capture", "Desktop", "desktop-uuid", OBS_SOURCE_AUDIO);
+    let settings = renderer_settings();
+    settings.set_string("source1", "desktop-uuid");
+    settings.set_int("flush_len", 2048);
+    let (renderer, mic) = new_renderer(&settings);
+
+    // 第一个包两个声音源都有数据，之后声音源2 不再提供数据，渲染很多帧也只输出一次警告
+    let (left, right) = test_signal(1024, 0);
+    for frame in 0..30 {
+        for _ in 0..2 {
+            unsafe { push_source_audio(mic, &[&left, &right]) };
+            if frame == 0 {
+                unsafe { push_source_audio(desktop, &[&left, &right]) };
+            }
+        }
+        renderer.render();
+    }
+    let logs = obs_shim::logs();
+    let stalled: Vec<_> = logs.iter().filter(|(_, message)| message.contains("停止提供数据")).collect();
+    assert_eq!(stalled.len(), 1, "{:?}", logs);
+    assert!(stalled[0].1.starts_with("[audio_renderer] [Audio Renderer renderer-uuid] 声音源2 "), "{}", stalled[0].1);
+    assert_eq!(logs.iter().filter(|(_, message)| message.contains("开始提供数据")).count(), 2);
+    assert_no_errors();
+}
+
+#[test]
+fn redundancy_repeats_previous_block() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_int("encoder", 1);
+    settings.set_int("flush_len", 2048);
+    settings.set_bool("redundancy", true);
+    let (renderer, mic) = new_renderer(&settings);
+
+    for packet in 0..3 {
+        for batch in 0..2 {
+            let (left, right) = test_signal(1024, (packet * 2 + batch) * 1024);
+            unsafe { push_source_audio(mic, &[&left, &right]) };
+        }
+        renderer.render();
+
+        // 头部之后是 32 位的上一个包的采样数，然后是上一个包和这一个包的采样
+        let upload = texture_uploads().pop().unwrap();
+        let row_len = upload.width as usize;
+        let samples: Vec<f32> = upload.data.chunks(4).map(|v| f32::from_ne_bytes(v.try_into().unwrap())).collect();
+        let previous_count = (0..32).map(|i| (samples[i] as u32) << i).sum::<u32>() as usize;
+        assert_eq!(previous_count, if packet == 0 { 0 } else { 2048 });
+        let (left, right) = test_signal(previous_count + 2048, packet * 2048 - previous_count);
+        assert_eq!(&samples[32..32 + previous_count + 2048], &left[..]);
+        assert_eq!(&samples[row_len + 32..row_len + 32 + previous_count + 2048], &right[..]);
+        assert_eq!(last_effect_param("sample_count"), Some(EffectParam::Float((previous_count + 2048) as f32)));
+    }
+
+    let viewer_config = viewer_config(&renderer);
+    assert!(viewer_config.contains("0,0,32,1072,2,2,16"), "{}", viewer_config);
+    assert_no_errors();
+}
+
+#[test]
+fn stats_shown_in_properties() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_bool("show_stats", true);
+    let (renderer, mic) = new_renderer(&settings);
+
+    push_batches(mic, 0..3);
+    // 超出满幅度的采样被削波
+    let loud = vec![1.5f32; 1024];
+    unsafe { push_source_audio(mic, &[&loud, &loud]) };
+    renderer.render();
+    renderer.render();
+    // 第一帧就刷新属性，之后每秒一次
+    assert_eq!(unsafe { properties_updates(renderer.source) }, 1);
+
+    let properties = unsafe { take_properties(renderer.info.get_properties.unwrap()(renderer.data)) };
+    let stats = properties.get("stats").unwrap();
+    assert!(stats.description.contains("每秒包数：1\n"), "{}", stats.description);
+    assert!(stats.description.contains("放大倍数：左声道 1，右声道 1\n"), "{}", stats.description);
+    assert!(stats.description.contains("削波的采样：2048\n"), "{}", stats.description);
+    assert!(stats.description.contains("缓冲：声音源1 0 毫秒\n"), "{}", stats.description);
+    assert_no_errors();
+}
+
+/// 按照 userscript 的解码方式读出数据通道中的帧，数据通道从第 `offset` 个格子开始
+fn data_frames(upload: &TextureUpload, channel: usize, offset: usize) -> Vec<(u8, Vec<u8>)> {
+    let byte = |i: usize| header_bits(upload, LAYOUT_VERTICAL, channel, offset + i * 8, 8) as u8;
+    let mut frames = Vec::new();
+    let mut i = 0;
+    while byte(i) != 0 {
+        let len = (byte(i + 1) as usize) << 8 | byte(i + 2) as usize;
+        frames.push((byte(i), (0..len).map(|j| byte(i + 3 + j)).collect()));
+        i += 3 + len + 2;
+    }
+    frames
+}
+
+#[test]
+fn send_data_proc_multiplexes_frames() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    let (renderer, mic) = new_renderer(&settings);
+
+    for (data_type, data) in [(1, "你好，世界"), (3, r#"{"chapter":2}"#)] {
+        let mut calldata = CallData::default();
+        calldata.set_int("type", data_type);
+        calldata.set_string("data", data);
+        assert!(unsafe { call_proc(renderer.source, "send_data", &mut calldata) });
+    }
+    push_batches(mic, 0..3);
+    renderer.render();
+
+    // 3072 个采样之后是黑色的结束标记，之后是数据通道，两帧都放得下左声道
+    let uploads = texture_uploads();
+    assert_eq!(uploads.len(), 1);
+    assert!(cell_gray(&uploads[0], LAYOUT_VERTICAL, 0, 10 + 3072) < 16.0);
+    assert_eq!(data_frames(&uploads[0], 0, 10 + 3072 + 1), vec![
+        (1, "你好，世界".as_bytes().to_vec()),
+        (3, br#"{"chapter":2}"#.to_vec()),
+    ]);
+    assert_eq!(data_frames(&uploads[0], 1, 10 + 3072 + 1), vec![]);
+    // 类型必须是 1 ~ 255
+    let mut calldata = CallData::default();
+    calldata.set_int("type", 256);
+    calldata.set_string("data", "x");
+    unsafe { call_proc(renderer.source, "send_data", &mut calldata) };
+    assert!(obs_shim::logs().iter().any(|(_, message)| message.contains("发送数据失败")));
+    assert_no_errors();
+}
+
+#[test]
+fn send_data_after_destroy_is_ignored() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    let (renderer, _) = new_renderer(&settings);
+    let renderer_source = renderer.source;
+    drop(renderer);
+
+    // libobs 不能移除 proc handler 中的函数，源销毁之前仍然可以调用，这时找不到实例，什么都不做
+    let mut calldata = CallData::default();
+    calldata.set_int("type", 256);
+    calldata.set_string("data", "x");
+    assert!(unsafe { call_proc(renderer_source, "send_data", &mut calldata) });
+    assert!(!obs_shim::logs().iter().any(|(_, message)| message.contains("发送数据失败")));
+    assert_no_errors();
+}
+
+#[test]
+fn gpu_encoder_uploads_raw_samples() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    settings.set_int("encoder", 1);
+    let (renderer, mic) = new_renderer(&settings);
+
+    push_batches(mic, 0..3);
+    renderer.render();
+
+    // 每个声道一行，每个格子一个 f32
+    let cell_count = (WIDTH / CELL) * (HEIGHT / 2 / CELL);
+    let uploads = texture_uploads();
+    assert_eq!(uploads.len(), 1);
+    assert_eq!((uploads[0].width as usize, uploads[0].height as usize), (cell_count, 2));
+    let samples: Vec<f32> = uploads[0].data.chunks(4).map(|v| f32::from_ne_bytes(v.try_into().unwrap())).collect();
+    let (left, right) = test_signal(3072, 0);
+    assert_eq!(&samples[..3072], &left[..]);
+    assert!(samples[3072..cell_count].iter().all(|v| *v == 0.0));
+    assert_eq!(&samples[cell_count..cell_count + 3072], &right[..]);
+
+    assert_eq!(last_effect_param("sample_count"), Some(EffectParam::Float(3072.0)));
+    assert_eq!(last_effect_param("packet_index"), Some(EffectParam::Float(0.0)));
+    assert_eq!(last_effect_param("amplifier_0"), Some(EffectParam::Float(2.0)));
+    assert_eq!(last_effect_param("amplifier_1"), Some(EffectParam::Float(2.0)));
+    assert_eq!(last_effect_param("channel_layout"), Some(EffectParam::Float(LAYOUT_VERTICAL as f32)));
+    assert_eq!(last_effect_param("extra_header_count"), Some(EffectParam::Float(0.0)));
+    // 没有数据时数据通道只有结束标记
+    assert_eq!(last_effect_param("data_cell_count"), Some(EffectParam::Float(8.0)));
+    // 由 shader 决定颜色，不使用 texture
+    assert_eq!(sprite_draws().last(), Some(&SpriteDraw { texture: 0, x: 0.0, y: 0.0, width: WIDTH as u32, height: HEIGHT as u32 }));
+    assert_no_errors();
+}
+
+#[test]
+fn overlay_filter_draws_at_corner() {
+    let _session = module_session(obs_module_load);
+    let mic = create_source("pulse_input_capture", "Mic", "mic-uuid", OBS_SOURCE_AUDIO);
+    let scene = create_source("scene", "Scene", "scene-uuid", OBS_SOURCE_VIDEO);
+    unsafe { set_source_size(scene, 1920, 1080) };
+    let filter_source = create_source("audio_renderer_filter", "Audio Renderer Overlay", "overlay-uuid", OBS_SOURCE_VIDEO);
+    unsafe { set_filter_target(filter_source, scene) };
+    let settings = Data::new();
+    unsafe { source_info("audio_renderer_filter").get_defaults.unwrap()(settings.as_ptr()) };
+    settings.set_bool("limiter_enabled", false);
+    settings.set_string("source0", "mic-uuid");
+    settings.set_int("corner", 3);
+    settings.set_int("offset_x", 10);
+    settings.set_int("offset_y", 4);
+    let filter = unsafe { Instance::create("audio_renderer_filter", &settings, filter_source) };
+    // 滤镜的大小和所在的源相同
+    assert_eq!(unsafe { filter.info.get_width.unwrap()(filter.data) }, 1920);
+    assert_eq!(unsafe { filter.info.get_height.unwrap()(filter.data) }, 1080);
+
+    push_batches(mic, 0..3);
+    filter.render();
+
+    // 先原样画出所在的源，再把编码区域画到右下角
+    assert_eq!(filter_draws(), vec![FilterDraw { target: scene as usize, technique: "Draw".to_string(), width: 1920, height: 1080 }]);
+    let draw = sprite_draws().last().cloned().unwrap();
+    assert_eq!((draw.x, draw.y), ((1920 - WIDTH - 10) as f32, (1080 - HEIGHT - 4) as f32));
+    let uploads = texture_uploads();
+    assert_eq!(uploads.len(), 1);
+    let (left, _) = test_signal(3072, 0);
+    for (i, v) in left.iter().enumerate() {
+        assert!((decode_sample(&uploads[0], LAYOUT_VERTICAL, 0, i) - v).abs() < 0.01, "left sample {}", i);
+    }
+    assert_no_errors();
+}
+
+#[test]
+fn overlay_filter_low_contrast_mode() {
+    let _session = module_session(obs_module_load);
+    let mic = create_source("pulse_input_capture", "Mic", "mic-uuid", OBS_SOURCE_AUDIO);
+    let scene = create_source("scene", "Scene", "scene-uuid", OBS_SOURCE_VIDEO);
+    unsafe { set_source_size(scene, 1920, 1080) };
+    let filter_source = create_source("audio_renderer_filter", "Audio Renderer Overlay", "overlay-uuid", OBS_SOURCE_VIDEO);
+    unsafe { set_filter_target(filter_source, scene) };
+    let settings = Data::new();
+    unsafe { source_info("audio_renderer_filter").get_defaults.unwrap()(settings.as_ptr()) };
+    settings.set_bool("limiter_enabled", false);
+    settings.set_string("source0", "mic-uuid");
+    settings.set_int("corner", 1);
+    settings.set_int("overlay_mode", 1);
+    settings.set_int("low_contrast_strength", 6);
+    let filter = unsafe { Instance::create("audio_renderer_filter", &settings, filter_source) };
+
+    push_batches(mic, 0..3);
+    filter.render();
+
+    // 编码由 shader 叠加在所在的源的画面上，不再单独画编码区域
+    assert_eq!(filter_draws(), vec![FilterDraw { target: scene as usize, technique: "DrawLowContrast".to_string(), width: 1920, height: 1080 }]);
+    assert!(sprite_draws().is_empty());
+    assert_eq!(last_effect_param("region_x"), Some(EffectParam::Float((1920 - WIDTH) as f32)));
+    assert_eq!(last_effect_param("region_y"), Some(EffectParam::Float(0.0)));
+    assert_eq!(last_effect_param("strength"), Some(EffectParam::Float(6.0)));
+    assert_eq!(last_effect_param("sample_count"), Some(EffectParam::Float(3072.0)));
+    let uploads = texture_uploads();
+    assert_eq!(uploads.len(), 1);
+    let samples: Vec<f32> = uploads[0].data.chunks(4).map(|v| f32::from_ne_bytes(v.try_into().unwrap())).collect();
+    let (left, _) = test_signal(3072, 0);
+    assert_eq!(&samples[..3072], &left[..]);
+
+    // 观看端配置字符串带上低对比度标记
+    let viewer_config = viewer_config(&filter);
+    assert!(viewer_config.contains(&format!("{},0,{},{},2,2,1", 1920 - WIDTH, WIDTH, HEIGHT)), "{}", viewer_config);
+    assert_no_errors();
+}
+
+#[test]
+fn placement_preset_and_viewer_config() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    // 底部横条，画布为 1920x1080
+    settings.set_int("placement", 3);
+    let (renderer, _) = new_renderer(&settings);
+    assert_eq!(unsafe { renderer.info.get_width.unwrap()(renderer.data) }, 1920);
+    assert_eq!(unsafe { renderer.info.get_height.unwrap()(renderer.data) }, 16);
+
+    let viewer_config = viewer_config(&renderer);
+    assert!(viewer_config.contains("0,1064,1920,16,2,2"), "{}", viewer_config);
+    // 测试环境不一定有剪贴板，复制成功或者失败都会在日志中写出配置字符串
+    let properties = unsafe { take_properties(renderer.info.get_properties.unwrap()(renderer.data)) };
+    let button = properties.get("copy_viewer_config").unwrap();
+    assert!(unsafe { button.click(renderer.data) });
+    let logs = obs_shim::logs();
+    let copied = logs.iter().find(|(_, message)| message.contains("0,1064,1920,16,2,2")).unwrap();
+    assert!(copied.1.starts_with("[audio_renderer] [Audio Renderer renderer-uuid] "), "{}", copied.1);
+    assert_no_errors();
+}
+
+#[test]
+fn properties_list_audio_sources() {
+    let _session = module_session(obs_module_load);
+    create_source("pulse_input_capture", "Mic", "mic-uuid", OBS_SOURCE_AUDIO);
+    create_source("image_source", "Image", "image-uuid", OBS_SOURCE_VIDEO);
+    let properties = unsafe { take_properties(source_info("audio_renderer").get_properties.unwrap()(null_mut())) };
+    let source0 = properties.properties.iter().find(|p| p.name == "source0").unwrap();
+    assert!(source0.list_items.iter().any(|v| v == "Mic"));
+    assert!(!source0.list_items.iter().any(|v| v == "Image"));
+}
+
+#[test]
+fn invalid_settings_shown_in_properties() {
+    let _session = module_session(obs_module_load);
+    let settings = renderer_settings();
+    let (renderer, _) = new_renderer(&settings);
+    let mut properties = unsafe { take_properties(renderer.info.get_properties.unwrap()(renderer.data)) };
+    assert!(properties.get("validation_error").unwrap().hidden);
+
+    // 直接修改宽度、高度时不自动调整，只显示错误和建议的设置，update 继续使用之前的设置
+    settings.set_int("height", 1071);
+    assert!(unsafe { properties.modify("height", &settings) });
+    let validation_error = properties.get("validation_error").unwrap();
+    assert!(!validation_error.hidden);
+    assert_eq!(validation_error.info_type, obs_text_info_type_OBS_TEXT_INFO_ERROR);
+    assert!(validation_error.description.contains("最接近的有效设置：编码区域 32x1072，格子 2x2"), "{}", validation_error.description);
+    assert_eq!(settings.get_int("height"), 1071);
+    renderer.update(&settings);
+    assert!(obs_shim::logs().iter().any(|(level, message)| *level == LOG_ERROR && message.contains("继续使用之前的设置")));
+    assert!(viewer_config(&renderer).contains("0,0,32,1072,2,2"));
+
+    // 修改格子大小之后宽度和高度改成最接近的有效设置，步长跟随格子大小
+    settings.set_int("cell_width", 3);
+    settings.set_int("cell_height", 3);
+    assert!(unsafe { properties.modify("cell_height", &settings) });
+    assert!(properties.get("validation_error").unwrap().hidden);
+    let (width, height) = (settings.get_int("width"), settings.get_int("height"));
+    assert!(width % 3 == 0 && height % 6 == 0, "{}x{}", width, height);
+    assert_eq!(properties.get("height").unwrap().int_limits, Some((6, 4320, 6)));
+
+    // 开启冗余之后最少缓冲长度不能超过编码区域的容量
+    settings.set_int("cell_width", 2);
+    settings.set_int("cell_height", 2);
+    settings.set_int("width", 32);
+    settings.set_int("height", 1072);
+    settings.set_int("flush_len", 2400);
+    settings.set_bool("redundancy", true);
+    assert!(unsafe { properties.modify("redundancy", &settings) });
+    assert_eq!(settings.get_int("flush_len"), 2123);
+    assert_eq!(properties.get("flush_len").unwrap().int_limits, Some((480, 2123, 1)));
+    assert!(properties.get("validation_error").unwrap().hidden);
+    renderer.update(&settings);
+}
+
+
+/// 属性中显示的声音源2 的缓冲长度，单位为毫秒
+fn second_source_fill(instance: &Instance) -> u64 {
+    let properties = unsafe { take_properties(instance.info.get_properties.unwrap()(instance.data)) };
+    let stats = &properties.get("stats").unwrap().description;
+    let start = stats.find("声音源2 ").unwrap() + "声音源2 ".len();
+    stats[start..].split(' ').next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn leading_source_catches_up_without_reset() {
+    let _session = module_session(obs_module_load);
+    let desktop = create_source("pulse_output_capture", "Desktop", "desktop-uuid", OBS_SOURCE_AUDIO);
+    let settings = renderer_settings();
+    settings.set_string("source1", "desktop-uuid");
+    settings.set_int("flush_len", 2048);
+    settings.set_bool("show_stats", true);
+    let (renderer, mic) = new_renderer(&settings);
+
+    // 声音源2 一开始领先 6 批，之后两个声音源速度相同，领先的部分逐渐被追赶回来
+    push_batches(desktop, 0..6);
+    let mut fills = Vec::new();
+    for packet in 0..20 {
+        push_batches(mic, packet * 2..packet * 2 + 2);
+        push_batches(desktop, packet * 2 + 6..packet * 2 + 8);
+        renderer.render();
+        fills.push(second_source_fill(&renderer));
+    }
+    assert!(fills.windows(2).all(|v| v[1] <= v[0]), "{:?}", fills);
+    assert!(fills[19] + 10 < fills[0], "{:?}", fills);
+    let logs = obs_shim::logs();
+    assert!(!logs.iter().any(|(_, message)| message.contains("缓冲的声音太长")), "{:?}", logs);
+    assert_no_errors();
+}
+
+/// 音频线程不停地提交数据，同时 UI 线程反复修改声音源、销毁并重新创建 Audio Renderer
+#[test]
+fn concurrent_audio_update_and_destroy() {
+    let _session = module_session(obs_module_load);
+    let mic = create_source("pulse_input_capture", "Mic", "mic-uuid", OBS_SOURCE_AUDIO);
+    let filter_source = create_source("audio_capture", "Audio Capture", "filter-uuid", OBS_SOURCE_AUDIO);
+    let filter = unsafe { Instance::create("audio_capture", &Data::new(), filter_source) };
+    let selected = renderer_settings();
+    selected.set_string("source0", "mic-uuid");
+    selected.set_string("source1", "filter-uuid");
+    let cleared = renderer_settings();
+
+    let running = AtomicBool::new(true);
+    // 裸指针不能跨线程，用整数传给音频线程
+    let mic_address = mic as usize;
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let (left, right) = test_signal(1024, 0);
+            while running.load(Ordering::Relaxed) {
+                unsafe { push_source_audio(mic_address as *mut obs_source_t, &[&left, &right]) };
+                filter.filter_audio(&[&left, &right]);
+                thread::sleep(Duration::from_micros(500));
+            }
+        });
+        for round in 0..50 {
+            let renderer_source = create_source("audio_renderer", "Audio Renderer", &format!("renderer-{}", round), OBS_SOURCE_VIDEO);
+            let renderer = unsafe { Instance::create("audio_renderer", &selected, renderer_source) };
+            for _ in 0..5 {
+                thread::sleep(Duration::from_millis(2));
+                renderer.render();
+                renderer.update(&cleared);
+                renderer.render();
+                renderer.update(&selected);
+            }
+            // drop 时调用 destroy，之后音频线程不能再写入这个 Audio Renderer
+        }
+        running.store(false, Ordering::Relaxed);
+    });
+    assert!(!texture_uploads().is_empty());
+    assert_no_errors();
+}
@@ -1,7 +1,7 @@
 // ==UserScript==
 // @name         obs-audio-renderer 音频解码
 // @namespace    http://tampermonkey.net/
-// @version      0.1
+// @version      0.9
 // @description  try to take over the world!
 // @author       Ganlv
 // @homepage     https://github.com/ganlvtech/obs-audio-renderer
@@ -144,15 +144,52 @@ void main(void) {
     return ((g - 136.0) * 2 + (r - 136.0) + (b - 136.0)) / 4.0 / 120.0;
   }
 
+  // 每个声道开头的头部格子数：第 1~4 个是包序号，第 5~8 个是音量缩放系数，第 9~10 个是声道布局
+  const HEADER_CELL_COUNT = 10;
+  // 声道布局，和 src/audio_renderer.rs 中的 LAYOUT_* 相同
+  const LAYOUT_VERTICAL = 0; // 上半部分是左声道，下半部分是右声道
+  const LAYOUT_HORIZONTAL = 1; // 左半部分是左声道，右半部分是右声道
+  const LAYOUT_INTERLEAVED = 2; // 偶数行格子是左声道，奇数行格子是右声道
+  // 配置字符串第 7 项的各位，和 src/audio_renderer.rs 中的 Region::viewer_config 相同
+  const FLAG_LOW_CONTRAST = 1; // Audio Renderer Overlay 滤镜的低对比度模式
+  const FLAG_ENCRYPTED = 2; // 设置了观看端密码
+  const FLAG_AUTHENTICATED = 4; // 设置了验证密钥
+  const FLAG_TIMESTAMPED = 8; // 头部中有画面和声音的时间戳
+  const FLAG_REDUNDANT = 16; // 每个包的采样之前还有上一个包的采样
+
+  /**
+   * 某个声道第 index 个格子在编码区域中的位置，和 src/audio_renderer.rs 中的 cell_position 相同
+   *
+   * @param {number} layout 声道布局
+   * @param {number} channel 0 表示左声道，1 表示右声道
+   * @param {number} index
+   * @param {number} columns 编码区域的格子列数
+   * @param {number} rows 编码区域的格子行数
+   * @returns {[number, number]} 格子所在的列和行
+   */
+  function cellPosition(layout, channel, index, columns, rows) {
+    switch (layout) {
+      case LAYOUT_HORIZONTAL: {
+        const halfColumns = columns / 2;
+        return [index % halfColumns + channel * halfColumns, Math.floor(index / halfColumns)];
+      }
+      case LAYOUT_INTERLEAVED:
+        return [index % columns, Math.floor(index / columns) * 2 + channel];
+      default:
+        return [index % columns, Math.floor(index / columns) + channel * rows / 2];
+    }
+  }
+
   /**
    * @param {Uint8Array|Uint8ClampedArray} data RGBA 数据 0 ~ 255。长度至少应该为 width * height * 4
    * @param {number} width
    * @param {number} height
    * @param {number} cellWidth
    * @param {number} cellHeight
-   * @returns {[Float32Array, number]} 返回声音数据，范围是 -1.0 ~ 1.0
+   * @param {number} flags 配置字符串的第 7 项，加密时返回的声音数据还需要用 newAudioDecryptor 解密
+   * @returns {[Float32Array[], number, ExtraHeader|null, DataFrame[]]} 返回左右声道的声音数据、包序号、附加头部和数据通道的帧，声音数据的范围是 -1.0 ~ 1.0
    */
-  function decodeRgbaDataToAudio(data, width, height, cellWidth, cellHeight) {
+  function decodeRgbaDataToAudio(data, width, height, cellWidth, cellHeight, flags) {
     if (width * height * 4 > data.length) {
       throw new Error('data RGBA 数据的长度至少应该为 width * height * 4');
     }
@@ -162,62 +199,446 @@ void main(void) {
     if (height % cellHeight !== 0) {
       throw new Error('height 必须能整除 cellHeight');
     }
+    const columns = width / cellWidth;
+    const rows = height / cellHeight;
     const cellPixelCount = cellWidth * cellHeight;
-    const audioBuffer = new Float32Array(width * height);
-    let audioBufferIndex = 0;
-    const packetIndexArray = [];
-    let packetIndex = 0;
-    const amplifierArray = [];
-    let amplifier = 1.0;
-
-    for (let y = 0; y < height; y += cellHeight) {
-      for (let x = 0; x < width; x += cellWidth) {
-        let r = 0;
-        let g = 0;
-        let b = 0;
-        for (let j = 0; j < cellHeight; j++) {
-          for (let i = 0; i < cellWidth; i++) {
-            const index = 4 * ((y + j) * width + (x + i));
-            r += data[index];
-            g += data[index + 1];
-            b += data[index + 2];
-          }
+
+    // 返回格子的平均 RGB
+    const readCell = ([column, row]) => {
+      let r = 0;
+      let g = 0;
+      let b = 0;
+      for (let j = 0; j < cellHeight; j++) {
+        for (let i = 0; i < cellWidth; i++) {
+          const index = 4 * ((row * cellHeight + j) * width + (column * cellWidth + i));
+          r += data[index];
+          g += data[index + 1];
+          b += data[index + 2];
         }
-        r = r / cellPixelCount;
-        g = g / cellPixelCount;
-        b = b / cellPixelCount;
-        if (packetIndexArray.length < 4) { // buffer 前 4 个数据点是包序号，用于同步
-          packetIndexArray.push((r + g + b) / 3 > 128);
-          if (packetIndexArray.length >= 4) {
-            packetIndex = (packetIndexArray[0] ? 0x1 : 0) |
-                (packetIndexArray[1] ? 0x2 : 0) |
-                (packetIndexArray[2] ? 0x4 : 0) |
-                (packetIndexArray[3] ? 0x8 : 0);
-          }
-        } else if (amplifierArray.length < 4) { // buffer 前 4 个数据点是包序号，用于同步
-          amplifierArray.push((r + g + b) / 3 > 128);
-          if (amplifierArray.length >= 4) {
-            amplifier = (amplifierArray[0] ? 0x1 : 0) |
-                (amplifierArray[1] ? 0x2 : 0) |
-                (amplifierArray[2] ? 0x4 : 0) |
-                (amplifierArray[3] ? 0x8 : 0) + 1;
+      }
+      return [r / cellPixelCount, g / cellPixelCount, b / cellPixelCount];
+    };
+    // 头部从第 offset 个格子开始的 count 位
+    const readBits = (layout, channel, offset, count) => {
+      let value = 0;
+      for (let i = 0; i < count; i++) {
+        const [r, g, b] = readCell(cellPosition(layout, channel, offset + i, columns, rows));
+        if ((r + g + b) / 3 > 128) {
+          value |= 1 << i;
+        }
+      }
+      return value >>> 0;
+    };
+
+    // 左声道的头部在所有布局中都位于第一行的开头，所以先按上下分布读出声道布局
+    const layout = readBits(LAYOUT_VERTICAL, 0, 8, 2);
+    const packetIndex = readBits(layout, 0, 0, 4);
+    const channelCellCount = Math.floor(columns * rows / 2);
+    const [extraHeader, headerCellCount] = readExtraHeader(readBits, layout, HEADER_CELL_COUNT, flags);
+    const dataFrames = [];
+    const channelsData = [0, 1].map((channel) => {
+      const amplifier = readBits(layout, channel, 4, 4) + 1;
+      if (extraHeader) {
+        extraHeader.amplifiers[channel] = amplifier;
+      }
+      const audioBuffer = new Float32Array(Math.max(channelCellCount - headerCellCount, 0));
+      let audioBufferIndex = 0;
+      for (let i = headerCellCount; i < channelCellCount; i++) {
+        const [r, g, b] = readCell(cellPosition(layout, channel, i, columns, rows));
+        if (r < 16 && g < 16 && b < 16) {
+          break;
+        }
+        audioBuffer[audioBufferIndex] = decodeAudioSample(r, g, b) / amplifier;
+        audioBufferIndex++;
+      }
+      // 黑色的结束标记之后是验证用的辅助位和数据通道
+      const dataOffset = readAuthHelpers(readBits, layout, channel, headerCellCount + audioBufferIndex + 1, audioBufferIndex, extraHeader, flags);
+      const [frames, frameBytes] = readDataFrames(readBits, layout, channel, dataOffset, channelCellCount);
+      dataFrames.push(...frames);
+      if (extraHeader) {
+        extraHeader.data[channel] = frameBytes;
+      }
+      return audioBuffer.subarray(0, audioBufferIndex);
+    });
+    return [channelsData, packetIndex, extraHeader, dataFrames];
+  }
+
+  // 数据通道的数据类型，和 src/data_channel.rs 中的 DATA_TYPE_* 相同，其他类型由使用者自己约定
+  const DATA_TYPE_SUBTITLE = 1; // 字幕
+  const DATA_TYPE_MARKER = 2; // 章节标记
+  const DATA_TYPE_JSON = 3; // JSON
+
+  /**
+   * @typedef {{type: number, payload: Uint8Array}} DataFrame
+   */
+
+  /**
+   * CRC-16/CCITT-FALSE，和 src/data_channel.rs 中的 crc16 相同
+   *
+   * @param {Uint8Array} bytes
+   * @returns {number}
+   */
+  function crc16(bytes) {
+    let crc = 0xffff;
+    for (const byte of bytes) {
+      crc ^= byte << 8;
+      for (let i = 0; i < 8; i++) {
+        crc = (crc & 0x8000) ? ((crc << 1) ^ 0x1021) & 0xffff : (crc << 1) & 0xffff;
+      }
+    }
+    return crc;
+  }
+
+  /**
+   * 读出一个声道数据通道中的帧，每个格子 1 位，见 src/data_channel.rs
+   *
+   * 每帧是 1 字节的类型、2 字节的长度（大端序）、数据和 2 字节的 CRC，类型为 0 表示结束，校验失败时丢弃这一帧和之后的帧
+   *
+   * @param {function(number, number, number, number): number} readBits
+   * @param {number} layout
+   * @param {number} channel
+   * @param {number} offset 数据通道的第一个格子
+   * @param {number} end 这个声道的格子数
+   * @returns {[DataFrame[], Uint8Array]} 各帧和它们的字节，不包括结束标记，验证时参与计算标签
+   */
+  function readDataFrames(readBits, layout, channel, offset, end) {
+    const frames = [];
+    const readByte = (i) => readBits(layout, channel, offset + i * 8, 8);
+    const byteCount = Math.floor((end - offset) / 8);
+    let i = 0;
+    while (i + 3 <= byteCount) {
+      const type = readByte(i);
+      if (type === 0) {
+        break;
+      }
+      const length = readByte(i + 1) << 8 | readByte(i + 2);
+      if (i + 3 + length + 2 > byteCount) {
+        break;
+      }
+      const frame = new Uint8Array(3 + length);
+      for (let j = 0; j < frame.length; j++) {
+        frame[j] = readByte(i + j);
+      }
+      if (crc16(frame) !== (readByte(i + 3 + length) << 8 | readByte(i + 4 + length))) {
+        break;
+      }
+      frames.push({type, payload: frame.subarray(3)});
+      i += 3 + length + 2;
+    }
+    const bytes = new Uint8Array(i);
+    for (let j = 0; j < i; j++) {
+      bytes[j] = readByte(j);
+    }
+    return [frames, bytes];
+  }
+
+  /**
+   * 创建数据通道的处理函数，每一帧都以 obs-audio-renderer-data 事件发给页面上的其他脚本，字幕显示在视频下方
+   *
+   * @param {HTMLVideoElement} video
+   * @returns {function(DataFrame): void}
+   */
+  function newDataFrameHandler(video) {
+    const subtitle = document.createElement('div');
+    subtitle.style.cssText = 'position: absolute; left: 0; right: 0; bottom: 10%; text-align: center; color: #fff; font-size: 24px; text-shadow: 0 0 4px #000; pointer-events: none; z-index: 10;';
+    video.parentElement.appendChild(subtitle);
+    let subtitleTimer = null;
+    const textDecoder = new TextDecoder();
+    return ({type, payload}) => {
+      const text = textDecoder.decode(payload);
+      window.dispatchEvent(new CustomEvent('obs-audio-renderer-data', {detail: {type, payload, text}}));
+      switch (type) {
+        case DATA_TYPE_SUBTITLE:
+          // 字幕显示 5 秒，或者直到下一条字幕
+          subtitle.textContent = text;
+          clearTimeout(subtitleTimer);
+          subtitleTimer = setTimeout(() => subtitle.textContent = '', 5000);
+          break;
+        case DATA_TYPE_MARKER:
+          console.log('marker', text);
+          break;
+        case DATA_TYPE_JSON:
+          try {
+            console.log('json', JSON.parse(text));
+          } catch (e) {
+            console.warn('invalid json data frame', text);
           }
-        } else {
-          if (r < 16 && g < 16 && b < 16) {
-            return [audioBuffer.subarray(0, audioBufferIndex), packetIndex];
+          break;
+        default:
+          console.log('data frame', type, payload);
+      }
+    };
+  }
+
+  // 加密和验证用的参数，和 src/cipher.rs、src/auth.rs 相同
+  const KDF_ITERATIONS = 100000;
+  const CIPHER_LEVELS = 240;
+  const AUTH_BLOCK_SIZE = 16;
+  const AUTH_HELPER_BITS = 3;
+  const AUTH_LEVEL_STEP = 16;
+  const AUTH_SIGNS_PER_MAC = 256;
+
+  /**
+   * @typedef {{salt: number, packet: number, tag: number, videoTimestamp: number, audioTimestamp: number, previousCount: number, amplifiers: number[], helpers: Uint8Array[], data: Uint8Array[]}} ExtraHeader
+   */
+
+  /**
+   * 读出左声道固定头部之后的附加头部，和 src/audio_renderer.rs 中的 extra_header_cell_count 相同：
+   * 加密时是 32 位的盐，加密或者验证时是 32 位的完整包序号，验证时是 32 位的标签，
+   * 之后是画面和第一个采样的时间戳（纳秒），各 64 位，低 32 位在前，冗余时最后是 32 位的上一个包的采样数
+   *
+   * @param {function(number, number, number, number): number} readBits
+   * @param {number} layout
+   * @param {number} offset 固定头部的格子数
+   * @param {number} flags 配置字符串的第 7 项
+   * @returns {[ExtraHeader|null, number]} 附加头部和包括附加头部在内的头部格子数，没有附加头部时为 null
+   */
+  function readExtraHeader(readBits, layout, offset, flags) {
+    if (!(flags & (FLAG_ENCRYPTED | FLAG_AUTHENTICATED | FLAG_TIMESTAMPED | FLAG_REDUNDANT))) {
+      return [null, offset];
+    }
+    const header = {salt: 0, packet: 0, tag: 0, videoTimestamp: 0, audioTimestamp: 0, previousCount: 0, amplifiers: [1, 1], helpers: [new Uint8Array(0), new Uint8Array(0)], data: [new Uint8Array(0), new Uint8Array(0)]};
+    if (flags & FLAG_ENCRYPTED) {
+      header.salt = readBits(layout, 0, offset, 32);
+      offset += 32;
+    }
+    if (flags & (FLAG_ENCRYPTED | FLAG_AUTHENTICATED)) {
+      header.packet = readBits(layout, 0, offset, 32);
+      offset += 32;
+    }
+    if (flags & FLAG_AUTHENTICATED) {
+      header.tag = readBits(layout, 0, offset, 32);
+      offset += 32;
+    }
+    if (flags & FLAG_TIMESTAMPED) {
+      // 纳秒时间戳超过 2^53 时会损失精度，但 OBS 的时间戳从开机开始计算，不会这么大
+      header.videoTimestamp = readBits(layout, 0, offset + 32, 32) * 2 ** 32 + readBits(layout, 0, offset, 32);
+      header.audioTimestamp = readBits(layout, 0, offset + 96, 32) * 2 ** 32 + readBits(layout, 0, offset + 64, 32);
+      offset += 128;
+    }
+    if (flags & FLAG_REDUNDANT) {
+      header.previousCount = readBits(layout, 0, offset, 32);
+      offset += 32;
+    }
+    return [header, offset];
+  }
+
+  /**
+   * 验证时读出结束标记之后的辅助位，每 16 个采样 3 位，和 src/auth.rs 相同
+   *
+   * @param {function(number, number, number, number): number} readBits
+   * @param {number} layout
+   * @param {number} channel
+   * @param {number} offset 辅助位的第一个格子
+   * @param {number} sampleCount 这个声道的采样数
+   * @param {ExtraHeader|null} extraHeader 读出的辅助位保存在 extraHeader.helpers 中
+   * @param {number} flags 配置字符串的第 7 项
+   * @returns {number} 数据通道的第一个格子
+   */
+  function readAuthHelpers(readBits, layout, channel, offset, sampleCount, extraHeader, flags) {
+    if (!(flags & FLAG_AUTHENTICATED)) {
+      return offset;
+    }
+    const helpers = new Uint8Array(Math.ceil(sampleCount / AUTH_BLOCK_SIZE));
+    for (let i = 0; i < helpers.length; i++) {
+      helpers[i] = readBits(layout, channel, offset + i * AUTH_HELPER_BITS, AUTH_HELPER_BITS);
+    }
+    extraHeader.helpers[channel] = helpers;
+    return offset + helpers.length * AUTH_HELPER_BITS;
+  }
+
+  /**
+   * 创建验证函数，和 src/auth.rs 相同：每 16 个采样按密钥决定的 ±1 符号加权求和，按辅助位量化成档位，
+   * 对档位、辅助位、数据通道中各帧的字节和头部计算 HMAC-SHA256，和头部中的标签比较
+   *
+   * 必须在解密之前调用，声音数据的长度必须和编码时的采样数相同
+   *
+   * @param {string} secret 验证密钥
+   * @returns {function(Float32Array[], ExtraHeader): Promise<boolean>}
+   */
+  function newAudioVerifier(secret) {
+    const keyPromise = crypto.subtle.importKey('raw', new TextEncoder().encode(secret), {name: 'HMAC', hash: 'SHA-256'}, false, ['sign']);
+    const sign = async (message) => new Uint8Array(await crypto.subtle.sign('HMAC', await keyPromise, message));
+    // 第 k 段 256 个采样的符号是 HMAC-SHA256(密钥, 包序号 || 声道 || k) 的各位，低位在前，1 表示 +1
+    const signs = async (packet, channel, count) => {
+      const macs = await Promise.all(Array.from({length: Math.ceil(count / AUTH_SIGNS_PER_MAC)}, (_, counter) => {
+        const message = new Uint8Array(12);
+        const view = new DataView(message.buffer);
+        view.setUint32(0, packet);
+        view.setUint32(4, channel);
+        view.setUint32(8, counter);
+        return sign(message);
+      }));
+      return (i) => (macs[Math.floor(i / AUTH_SIGNS_PER_MAC)][(i % AUTH_SIGNS_PER_MAC) >> 3] >> (i & 7)) & 1;
+    };
+    return async (channelsData, {packet, tag, previousCount, amplifiers, helpers, data}) => {
+      const count = channelsData[0].length;
+      const blockCount = Math.ceil(count / AUTH_BLOCK_SIZE);
+      const head = new Uint8Array(14);
+      const headView = new DataView(head.buffer);
+      headView.setUint32(0, packet);
+      headView.setUint32(4, count);
+      headView.setUint32(8, previousCount);
+      head[12] = amplifiers[0];
+      head[13] = amplifiers[1];
+      const parts = [head];
+      for (let channel = 0; channel < 2; channel++) {
+        const channelData = channelsData[channel];
+        const isPositive = await signs(packet, channel, count);
+        // 档位是 -30 ~ 30，按 8 位补码写入
+        const symbols = new Uint8Array(blockCount);
+        for (let block = 0; block < blockCount; block++) {
+          const start = block * AUTH_BLOCK_SIZE;
+          const end = Math.min(start + AUTH_BLOCK_SIZE, count);
+          let sum = 0;
+          for (let i = start; i < end; i++) {
+            const level = 120 * Math.max(-1, Math.min(1, channelData[i] * amplifiers[channel]));
+            sum += isPositive(i) ? level : -level;
           }
-          audioBuffer[audioBufferIndex] = decodeAudioSample(r, g, b) / amplifier;
-          audioBufferIndex++;
+          const feature = sum / Math.sqrt(end - start);
+          symbols[block] = Math.floor(feature / AUTH_LEVEL_STEP - (helpers[channel][block] + 0.5) / (1 << AUTH_HELPER_BITS) + 0.5);
         }
+        const length = new Uint8Array(4);
+        new DataView(length.buffer).setUint32(0, data[channel].length);
+        parts.push(symbols, helpers[channel], length, data[channel]);
+      }
+      const message = new Uint8Array(parts.reduce((sum, part) => sum + part.length, 0));
+      parts.reduce((offset, part) => {
+        message.set(part, offset);
+        return offset + part.length;
+      }, 0);
+      const signature = new DataView((await sign(message)).buffer);
+      return signature.getUint32(0) === tag;
+    };
+  }
+
+  /**
+   * 创建解密函数，用 PBKDF2-HMAC-SHA256 从密码和盐派生 AES-128 密钥，盐不变时复用密钥
+   *
+   * @param {string} passphrase
+   * @returns {function(Float32Array[], ExtraHeader): Promise<Float32Array[]>}
+   */
+  function newAudioDecryptor(passphrase) {
+    let keySalt = null;
+    let keyPromise = null;
+    return async (channelsData, {salt, packet, amplifiers}) => {
+      if (salt !== keySalt) {
+        keySalt = salt;
+        const saltBytes = new Uint8Array(4);
+        new DataView(saltBytes.buffer).setUint32(0, salt);
+        keyPromise = crypto.subtle.importKey('raw', new TextEncoder().encode(passphrase), 'PBKDF2', false, ['deriveKey'])
+          .then((material) => crypto.subtle.deriveKey({name: 'PBKDF2', salt: saltBytes, iterations: KDF_ITERATIONS, hash: 'SHA-256'}, material, {name: 'AES-CTR', length: 128}, false, ['encrypt']));
       }
+      const key = await keyPromise;
+      return Promise.all(channelsData.map(async (channelData, channel) => {
+        // 计数器的初始值是盐、包序号、声道和 0，各 4 个字节，大端序
+        const counter = new Uint8Array(16);
+        const view = new DataView(counter.buffer);
+        view.setUint32(0, salt);
+        view.setUint32(4, packet);
+        view.setUint32(8, channel);
+        const keystream = new DataView(await crypto.subtle.encrypt({name: 'AES-CTR', counter, length: 32}, key, new Uint8Array(channelData.length * 2)));
+        const amplifier = amplifiers[channel];
+        return channelData.map((v, i) => {
+          const offset = keystream.getUint16(i * 2) % CIPHER_LEVELS;
+          const level = 120 * (v * amplifier + 1) - offset;
+          return ((level % CIPHER_LEVELS + CIPHER_LEVELS) % CIPHER_LEVELS / 120 - 1) / amplifier;
+        });
+      }));
+    };
+  }
+
+  // 低对比度模式中，头部之后的 16 个格子是采样数
+  const LOW_CONTRAST_HEADER_CELL_COUNT = HEADER_CELL_COUNT + 16;
+
+  /**
+   * 解码 Audio Renderer Overlay 滤镜低对比度模式的画面
+   *
+   * 每个格子分成 4 个象限，左上和右下加上信号，右上和左下减去信号。对角象限的均值之差抵消了底下的画面，只剩下信号。
+   * 头部的格子信号是 ±strength，用它们的平均幅度估计 strength，再把采样格子的信号换算回 -1.0 ~ 1.0。
+   *
+   * @param {Uint8Array|Uint8ClampedArray} data RGBA 数据 0 ~ 255。长度至少应该为 width * height * 4
+   * @param {number} width
+   * @param {number} height
+   * @param {number} cellWidth 必须是偶数
+   * @param {number} cellHeight 必须是偶数
+   * @param {number} flags 配置字符串的第 7 项，加密时返回的声音数据还需要用 newAudioDecryptor 解密
+   * @returns {[Float32Array[], number, ExtraHeader|null, DataFrame[]]} 返回左右声道的声音数据、包序号、附加头部和数据通道的帧，声音数据的范围是 -1.0 ~ 1.0
+   */
+  function decodeLowContrastRgbaDataToAudio(data, width, height, cellWidth, cellHeight, flags) {
+    if (width * height * 4 > data.length) {
+      throw new Error('data RGBA 数据的长度至少应该为 width * height * 4');
+    }
+    if (cellWidth % 2 !== 0 || cellHeight % 2 !== 0) {
+      throw new Error('低对比度模式的 cellWidth 和 cellHeight 必须是偶数');
     }
-    return [audioBuffer, packetIndex];
+    const columns = width / cellWidth;
+    const rows = height / cellHeight;
+    const halfWidth = cellWidth / 2;
+    const halfHeight = cellHeight / 2;
+
+    // 象限的平均亮度
+    const readQuadrant = (column, row, right, bottom) => {
+      let sum = 0;
+      for (let j = 0; j < halfHeight; j++) {
+        for (let i = 0; i < halfWidth; i++) {
+          const x = column * cellWidth + right * halfWidth + i;
+          const y = row * cellHeight + bottom * halfHeight + j;
+          const index = 4 * (y * width + x);
+          sum += data[index] + data[index + 1] + data[index + 2];
+        }
+      }
+      return sum / 3 / (halfWidth * halfHeight);
+    };
+    // 格子的信号，单位和画面的 0 ~ 255 相同
+    const readCell = ([column, row]) => (readQuadrant(column, row, 0, 0) + readQuadrant(column, row, 1, 1) - readQuadrant(column, row, 1, 0) - readQuadrant(column, row, 0, 1)) / 4;
+    const readBits = (layout, channel, offset, count) => {
+      let value = 0;
+      for (let i = 0; i < count; i++) {
+        if (readCell(cellPosition(layout, channel, offset + i, columns, rows)) > 0) {
+          value |= 1 << i;
+        }
+      }
+      return value >>> 0;
+    };
+
+    const layout = readBits(LAYOUT_VERTICAL, 0, 8, 2);
+    const packetIndex = readBits(layout, 0, 0, 4);
+    const channelCellCount = Math.floor(columns * rows / 2);
+    const [extraHeader, headerCellCount] = readExtraHeader(readBits, layout, LOW_CONTRAST_HEADER_CELL_COUNT, flags);
+    const dataFrames = [];
+    const channelsData = [0, 1].map((channel) => {
+      let strength = 0;
+      for (let i = 0; i < HEADER_CELL_COUNT; i++) {
+        strength += Math.abs(readCell(cellPosition(layout, channel, i, columns, rows)));
+      }
+      strength = Math.max(strength / HEADER_CELL_COUNT, 0.001);
+      const amplifier = readBits(layout, channel, 4, 4) + 1;
+      if (extraHeader) {
+        extraHeader.amplifiers[channel] = amplifier;
+      }
+      const sampleCount = Math.min(readBits(layout, channel, HEADER_CELL_COUNT, 16), Math.max(channelCellCount - headerCellCount, 0));
+      const audioBuffer = new Float32Array(sampleCount);
+      for (let i = 0; i < sampleCount; i++) {
+        const v = readCell(cellPosition(layout, channel, headerCellCount + i, columns, rows)) / strength;
+        audioBuffer[i] = Math.max(-1, Math.min(1, v)) / amplifier;
+      }
+      // 和其他模式相同，采样之后空一个格子
+      const dataOffset = readAuthHelpers(readBits, layout, channel, headerCellCount + sampleCount + 1, sampleCount, extraHeader, flags);
+      const [frames, frameBytes] = readDataFrames(readBits, layout, channel, dataOffset, channelCellCount);
+      dataFrames.push(...frames);
+      if (extraHeader) {
+        extraHeader.data[channel] = frameBytes;
+      }
+      return audioBuffer;
+    });
+    return [channelsData, packetIndex, extraHeader, dataFrames];
   }
 
   /**
    * 创建音频播放器
    *
-   * @returns {(function(Float32Array[]): void)} 每次调用都会播放声音，如果间隔小于 0.5 秒则连续播放，大于 0.5 秒则重新播放
+   * @returns {(function(Float32Array[]): number)} 每次调用都会播放声音，如果间隔小于 0.5 秒则连续播放，大于 0.5 秒则重新播放，返回这段声音在多少秒之后开始播放
    */
   function newAudioPlayer() {
     // 注意：
@@ -284,11 +705,38 @@ void main(void) {
       });
       source.connect(audioCtx.destination);
       source.start(startTime);
+      const delay = startTime - currentTime;
       startTime += audioBuffer.duration;
+      return delay;
     }
   }
 
-  function run(x, y, width, height, cellWidth, cellHeight) {
+  /**
+   * 创建音画同步的统计函数，每个包都以 obs-audio-renderer-sync 事件发给页面上的其他脚本，控制台中每 5 秒输出一次
+   *
+   * 包中的第一个采样比当前的画面早 (画面时间戳 - 声音时间戳)，又要等播放器中排在前面的声音播放完，两者之和就是声音比画面晚多少
+   *
+   * @returns {function(ExtraHeader, number): void} 参数是附加头部和播放器返回的延迟（秒）
+   */
+  function newLipSyncReporter() {
+    let lastLogTime = 0;
+    return ({videoTimestamp, audioTimestamp}, playbackDelay) => {
+      const offset = (videoTimestamp - audioTimestamp) / 1e6 + playbackDelay * 1000;
+      window.dispatchEvent(new CustomEvent('obs-audio-renderer-sync', {detail: {offset, videoTimestamp, audioTimestamp, playbackDelay}}));
+      const now = performance.now();
+      if (now - lastLogTime >= 5000) {
+        lastLogTime = now;
+        console.log('audio is behind video by', offset.toFixed(1), 'ms');
+      }
+    };
+  }
+
+  /**
+   * @param {number} flags 配置字符串的第 7 项，没有时为 0
+   * @param {string} passphrase 观看端密码，没有加密时不使用
+   * @param {string} secret 验证密钥，没有验证时不使用
+   */
+  function run(x, y, width, height, cellWidth, cellHeight, flags, passphrase, secret) {
     if (width <= 0) {
       throw new Error('width 必须 >= 0');
     }
@@ -301,14 +749,11 @@ void main(void) {
     if (cellHeight <= 0) {
       throw new Error('cellHeight 必须 >= 0');
     }
-    if (height % 2 !== 0) {
-      throw new Error('height 必须是 2 的倍数');
-    }
     if (width % cellWidth !== 0) {
       throw new Error('width 必须能整除 cellWidth');
     }
-    if ((height / 2) % cellHeight !== 0) {
-      throw new Error('height 的一半必须能整除 cellHeight');
+    if (height % cellHeight !== 0) {
+      throw new Error('height 必须能整除 cellHeight');
     }
 
     const video = document.querySelector('video');
@@ -323,19 +768,49 @@ void main(void) {
       }
     }
     const playAudioBuffer = newAudioPlayer();
+    const handleDataFrame = newDataFrameHandler(video);
+    const decode = (flags & FLAG_LOW_CONTRAST) ? decodeLowContrastRgbaDataToAudio : decodeRgbaDataToAudio;
+    const decrypt = (flags & FLAG_ENCRYPTED) ? newAudioDecryptor(passphrase) : null;
+    const verify = (flags & FLAG_AUTHENTICATED) ? newAudioVerifier(secret) : null;
+    const reportLipSync = (flags & FLAG_TIMESTAMPED) ? newLipSyncReporter() : null;
     let prevPacketIndex = null;
-    const update = () => {
+    const update = async () => {
       if (video.paused) {
         requestAnimationFrame(update);
         return;
       }
 
       const rgbaData = getVideoRgbaData(x, y, width, height);
-      const [leftChannelData, packetIndex] = decodeRgbaDataToAudio(rgbaData.subarray(0, rgbaData.length / 2), width, height / 2, cellWidth, cellHeight);
+      let [[leftChannelData, rightChannelData], packetIndex, extraHeader, dataFrames] = decode(rgbaData, width, height, cellWidth, cellHeight, flags);
       if (leftChannelData.length >= 240) { // buffer 太短不播放
         if (packetIndex !== prevPacketIndex) { // audio buffer 和上一帧相似则不播放
-          const [rightChannelData, _] = decodeRgbaDataToAudio(rgbaData.subarray(rgbaData.length / 2, rgbaData.length), width, height / 2, cellWidth, cellHeight);
-          playAudioBuffer([leftChannelData, rightChannelData]);
+          // 左右声道的长度相同，取较短的一个，防止某个格子受视频压缩影响被误判为静音
+          const length = Math.min(leftChannelData.length, rightChannelData.length);
+          leftChannelData = leftChannelData.subarray(0, length);
+          rightChannelData = rightChannelData.subarray(0, length);
+          // 验证失败说明不是来自设置了相同密钥的 Audio Renderer，或者画面被压缩得太严重，都不播放
+          if (verify && !await verify([leftChannelData, rightChannelData], extraHeader)) {
+            console.warn('audio packet verification failed', packetIndex);
+          } else {
+            if (decrypt) {
+              [leftChannelData, rightChannelData] = await decrypt([leftChannelData, rightChannelData], extraHeader);
+            }
+            // 冗余时采样的前一部分是上一个包，正好漏掉一个包时先播放这一部分，否则跳过
+            const previousCount = (flags & FLAG_REDUNDANT) ? Math.min(extraHeader.previousCount, length) : 0;
+            if (previousCount > 0) {
+              if (prevPacketIndex !== null && packetIndex === (prevPacketIndex + 2) % 16) {
+                console.log('recovered dropped audio packet', (prevPacketIndex + 1) % 16);
+                playAudioBuffer([leftChannelData.subarray(0, previousCount), rightChannelData.subarray(0, previousCount)]);
+              }
+              leftChannelData = leftChannelData.subarray(previousCount);
+              rightChannelData = rightChannelData.subarray(previousCount);
+            }
+            const playbackDelay = playAudioBuffer([leftChannelData, rightChannelData]);
+            if (reportLipSync) {
+              reportLipSync(extraHeader, playbackDelay);
+            }
+            dataFrames.forEach(handleDataFrame);
+          }
           prevPacketIndex = packetIndex;
         }
       }
@@ -345,14 +820,29 @@ void main(void) {
   }
 
   GM_registerMenuCommand("使用默认参数解码音频", () => {
-    run(0, 0, 32, 1072, 2, 2);
+    run(0, 0, 32, 1072, 2, 2, 0, '', '');
   });
   GM_registerMenuCommand("自定义参数解码音频", () => {
-    const config = window.prompt("x,y,width,height,cell_width,cell_height", GM_getValue("config", "0,0,32,1072,2,2"));
+    const config = window.prompt("x,y,width,height,cell_width,cell_height[,flags]", GM_getValue("config", "0,0,32,1072,2,2"));
     if (config) {
       GM_setValue("config", config);
-      const [x, y, width, height, cellWidth, cellHeight] = config.split(',').map((s) => s.trim()).map((s) => parseInt(s));
-      run(x, y, width, height, cellWidth, cellHeight);
+      const [x, y, width, height, cellWidth, cellHeight, flags] = config.split(',').map((s) => s.trim()).map((s) => parseInt(s));
+      // 密码和密钥不保存，每次都需要输入
+      let passphrase = '';
+      if (flags & FLAG_ENCRYPTED) {
+        passphrase = window.prompt("观看端密码", "");
+        if (!passphrase) {
+          return;
+        }
+      }
+      let secret = '';
+      if (flags & FLAG_AUTHENTICATED) {
+        secret = window.prompt("验证密钥", "");
+        if (!secret) {
+          return;
+        }
+      }
+      run(x, y, width, height, cellWidth, cellHeight, flags || 0, passphrase, secret);
     }
   });
 })();